@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
 /// Represents a text chunk with metadata
 #[derive(Debug, Clone)]
 pub struct TextChunk {
@@ -9,51 +12,446 @@ pub struct TextChunk {
     pub document_id: String,
     /// Starting position of this chunk in the original document
     pub start_position: usize,
+    /// Headings the chunk falls under, such as "Intro > Setup > Auth", when the document has them
+    pub breadcrumb: Option<String>,
+    /// Index of the first row in the chunk, counting data rows from 0, when split from a CSV or
+    /// TSV file
+    pub row: Option<usize>,
+    /// Page the chunk starts on, counting from 1, when the document has `PAGE_BREAK`s
+    pub page: Option<usize>,
+}
+
+/// Form feed marking the start of each page after the first in extracted text, on its own
+/// between paragraphs, so that chunks can be traced back to their page
+pub const PAGE_BREAK: char = '\u{c}';
+
+/// Thresholds for dropping low-quality chunks such as OCR noise or table fragments
+#[derive(Debug, Clone)]
+pub struct ChunkQualityFilter {
+    /// Minimum share of alphabetic characters among non-whitespace characters
+    pub min_alphabetic_ratio: f32,
+    /// Minimum number of distinct words in a chunk
+    pub min_distinct_words: usize,
+}
+
+impl Default for ChunkQualityFilter {
+    fn default() -> Self {
+        ChunkQualityFilter {
+            min_alphabetic_ratio: 0.5,
+            min_distinct_words: 5,
+        }
+    }
+}
+
+impl ChunkQualityFilter {
+    /// Check whether a chunk passes the quality thresholds
+    pub fn accepts(&self, chunk: &TextChunk) -> bool {
+        let visible_chars = chunk.text.chars().filter(|c| !c.is_whitespace()).count();
+        if visible_chars == 0 {
+            return false;
+        }
+
+        let alphabetic_chars = chunk.text.chars().filter(|c| c.is_alphabetic()).count();
+        let alphabetic_ratio = alphabetic_chars as f32 / visible_chars as f32;
+
+        // Only count tokens that contain letters as words
+        let distinct_words = chunk
+            .text
+            .split_whitespace()
+            .filter(|word| word.chars().any(|c| c.is_alphabetic()))
+            .map(|word| word.to_lowercase())
+            .collect::<HashSet<_>>()
+            .len();
+
+        alphabetic_ratio >= self.min_alphabetic_ratio && distinct_words >= self.min_distinct_words
+    }
+}
+
+/// Width of the token count buckets in `ChunkStats::histogram`
+pub const CHUNK_STATS_BUCKET_TOKENS: usize = 100;
+
+/// Distribution of chunk sizes in tokens
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkStats {
+    pub count: usize,
+    pub min_tokens: usize,
+    pub max_tokens: usize,
+    pub mean: f64,
+    /// Chunk counts per bucket of `CHUNK_STATS_BUCKET_TOKENS` tokens, the first starting at 0
+    pub histogram: Vec<usize>,
+}
+
+impl ChunkStats {
+    /// Compute the distribution of the given chunks' token counts
+    pub fn from_chunks(chunks: &[TextChunk]) -> Self {
+        let token_counts: Vec<usize> = chunks.iter().map(|chunk| chunk.token_count).collect();
+
+        let min_tokens = token_counts.iter().copied().min().unwrap_or(0);
+        let max_tokens = token_counts.iter().copied().max().unwrap_or(0);
+        let mean = if token_counts.is_empty() {
+            0.0
+        } else {
+            token_counts.iter().sum::<usize>() as f64 / token_counts.len() as f64
+        };
+
+        let mut histogram = Vec::new();
+        if !token_counts.is_empty() {
+            histogram.resize(max_tokens / CHUNK_STATS_BUCKET_TOKENS + 1, 0);
+            for token_count in &token_counts {
+                histogram[token_count / CHUNK_STATS_BUCKET_TOKENS] += 1;
+            }
+        }
+
+        ChunkStats {
+            count: token_counts.len(),
+            min_tokens,
+            max_tokens,
+            mean,
+            histogram,
+        }
+    }
+}
+
+/// Chunk sizes used by `split_into_chunks_with_config`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkingConfig {
+    /// Tokens a chunk is filled up to
+    pub target_tokens: usize,
+    /// Tokens repeated from the end of a chunk at the start of the next one
+    pub overlap_tokens: usize,
+    /// Chunks larger than this many times `target_tokens` are split again
+    pub max_chunk_multiplier: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        ChunkingConfig {
+            target_tokens: 500,
+            overlap_tokens: 50,
+            max_chunk_multiplier: 3,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    /// Check that the sizes can produce chunks
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.overlap_tokens >= self.target_tokens {
+            return Err(anyhow::anyhow!(
+                "Chunk overlap ({} tokens) must be smaller than the chunk target ({} tokens)",
+                self.overlap_tokens,
+                self.target_tokens
+            ));
+        }
+        if self.max_chunk_multiplier == 0 {
+            return Err(anyhow::anyhow!(
+                "Maximum chunk multiplier must be at least 1"
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// Split text into chunks of approximately 500 tokens
-pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
-    const TARGET_TOKENS: usize = 500;
-    const OVERLAP_TOKENS: usize = 50; // Overlap between chunks for context
+pub fn split_into_chunks(
+    text: &str,
+    file_name: &str,
+    counter: &dyn TokenCounter,
+) -> Vec<TextChunk> {
+    chunk_text(text, file_name, &ChunkingConfig::default(), counter, true)
+}
+
+/// Split text into chunks of the configured size
+pub fn split_into_chunks_with_config(
+    text: &str,
+    file_name: &str,
+    config: &ChunkingConfig,
+    counter: &dyn TokenCounter,
+) -> anyhow::Result<Vec<TextChunk>> {
+    config.validate()?;
+    Ok(chunk_text(text, file_name, config, counter, true))
+}
+
+/// Split a markdown document into chunks that follow its sections
+/// A new H1 or H2 heading always starts a new chunk unless the section before it is tiny, and
+/// each chunk under a heading starts with its heading breadcrumb, such as "Intro > Setup > Auth".
+/// Documents without headings are split like plain text.
+pub fn split_markdown(
+    text: &str,
+    file_name: &str,
+    config: &ChunkingConfig,
+    counter: &dyn TokenCounter,
+) -> anyhow::Result<Vec<TextChunk>> {
+    config.validate()?;
+
+    let sections = markdown_sections(text);
+    if sections.iter().all(|section| section.headings.is_empty()) {
+        return Ok(chunk_text(text, file_name, config, counter, true));
+    }
+
+    // Sections smaller than this join the next one even across an H1 or H2 boundary
+    let tiny_tokens = config.target_tokens / 10;
+
+    // Group consecutive sections into runs that are chunked together
+    let mut groups: Vec<MarkdownSection> = Vec::new();
+    for section in sections {
+        if let Some(group) = groups.last_mut() {
+            let group_tokens = counter.count_tokens(group.text);
+            let joins = if section.level <= 2 {
+                group_tokens < tiny_tokens
+            } else {
+                group_tokens + counter.count_tokens(section.text) <= config.target_tokens
+            };
+            if joins {
+                group.text = &text[group.start..section.start + section.text.len()];
+                // The group falls under the headings both parts share, or under the section's
+                // own when it only had text before the first heading
+                if group.headings.is_empty() {
+                    group.headings = section.headings;
+                } else {
+                    let shared = group
+                        .headings
+                        .iter()
+                        .zip(&section.headings)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    group.headings.truncate(shared);
+                }
+                continue;
+            }
+        }
+        groups.push(section);
+    }
+
+    let mut chunks = Vec::new();
+    for group in groups {
+        if group.text.trim().is_empty() {
+            continue;
+        }
+
+        let breadcrumb = group.headings.join(" > ");
+        for mut chunk in chunk_text(group.text, file_name, config, counter, true) {
+            chunk.start_position += group.start;
+            chunk.page = page_at(text, chunk.start_position);
+            if !breadcrumb.is_empty() {
+                chunk.text = format!("{}\n\n{}", breadcrumb, chunk.text);
+                chunk.token_count = counter.count_tokens(&chunk.text);
+                chunk.breadcrumb = Some(breadcrumb.clone());
+            }
+            chunks.push(chunk);
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Rows of a CSV or TSV file put in one chunk unless configured otherwise
+pub const DEFAULT_CSV_ROWS_PER_CHUNK: usize = 1;
+
+/// Split a CSV or TSV file into chunks of `rows_per_chunk` rows, each row written as one
+/// "Header: value" line per non-empty cell so that it reads as a self-contained record
+/// The first row is taken as the header and never becomes a chunk of its own.
+pub fn split_csv_rows(
+    text: &str,
+    file_name: &str,
+    delimiter: u8,
+    rows_per_chunk: usize,
+    counter: &dyn TokenCounter,
+) -> anyhow::Result<Vec<TextChunk>> {
+    if rows_per_chunk == 0 {
+        return Err(anyhow::anyhow!("Rows per chunk must be at least 1"));
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let headers: Vec<String> = reader
+        .headers()?
+        .iter()
+        .enumerate()
+        .map(|(column, header)| match header.trim() {
+            "" => format!("Column {}", column + 1),
+            header => header.to_string(),
+        })
+        .collect();
+
+    let mut chunks: Vec<TextChunk> = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record?;
+        let block = record
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(column, value)| match headers.get(column) {
+                Some(header) => format!("{}: {}", header, value.trim()),
+                None => format!("Column {}: {}", column + 1, value.trim()),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if block.is_empty() {
+            continue;
+        }
+
+        match chunks.last_mut() {
+            Some(chunk) if row - chunk.row.unwrap_or(row) < rows_per_chunk => {
+                chunk.text.push_str("\n\n");
+                chunk.text.push_str(&block);
+            }
+            _ => chunks.push(TextChunk {
+                text: block,
+                token_count: 0,
+                document_id: file_name.to_string(),
+                start_position: record
+                    .position()
+                    .map(|position| position.byte() as usize)
+                    .unwrap_or(0),
+                breadcrumb: None,
+                row: Some(row),
+                page: None,
+            }),
+        }
+    }
+
+    for chunk in &mut chunks {
+        chunk.token_count = counter.count_tokens(&chunk.text);
+    }
+    Ok(chunks)
+}
+
+/// Text under one markdown heading, up to the next heading
+struct MarkdownSection<'a> {
+    /// Heading level (1 for "#"), 0 for text before the first heading
+    level: usize,
+    /// Headings from the top level down to this section's own
+    headings: Vec<&'a str>,
+    /// The heading line and the text below it
+    text: &'a str,
+    /// Byte offset of `text` in the document
+    start: usize,
+}
+
+/// Cut a markdown document into sections at its headings, ignoring lines in fenced code blocks
+fn markdown_sections(text: &str) -> Vec<MarkdownSection<'_>> {
+    let mut sections = vec![MarkdownSection {
+        level: 0,
+        headings: Vec::new(),
+        text: "",
+        start: 0,
+    }];
+    let mut open_headings: Vec<(usize, &str)> = Vec::new();
+    let mut in_code_block = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        let heading = if in_code_block {
+            None
+        } else {
+            markdown_heading(line)
+        };
 
-    // First, split by paragraphs
-    let paragraphs: Vec<&str> = text
+        match heading {
+            Some((level, title)) => {
+                open_headings.retain(|&(parent_level, _)| parent_level < level);
+                open_headings.push((level, title));
+                sections.push(MarkdownSection {
+                    level,
+                    headings: open_headings.iter().map(|&(_, title)| title).collect(),
+                    text: &text[line_start..offset],
+                    start: line_start,
+                });
+            }
+            None => {
+                let section = sections.last_mut().expect("sections start non-empty");
+                section.text = &text[section.start..offset];
+            }
+        }
+    }
+
+    sections
+}
+
+/// Read the level and text of a markdown heading line such as "## Setup"
+pub(crate) fn markdown_heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim();
+    let text = line.trim_start_matches('#');
+    let level = line.len() - text.len();
+
+    if (1..=6).contains(&level) && text.starts_with(' ') && !text.trim().is_empty() {
+        Some((level, text.trim()))
+    } else {
+        None
+    }
+}
+
+/// Split text into chunks, splitting oversized chunks once more when `resplit` is set
+fn chunk_text(
+    text: &str,
+    file_name: &str,
+    config: &ChunkingConfig,
+    counter: &dyn TokenCounter,
+    resplit: bool,
+) -> Vec<TextChunk> {
+    let target_tokens = config.target_tokens;
+    let overlap_tokens = config.overlap_tokens; // Overlap between chunks for context
+
+    // Text without paragraphs of its own would otherwise rely on the sentence splitter alone
+    // Chunk positions are mapped back to the original text once the chunks are cut
+    let original = text;
+    let breaks = synthetic_break_positions(text);
+    let text = &*with_paragraph_breaks(text, &breaks);
+
+    // First, split by paragraphs, keeping markdown tables apart from the prose around them
+    // Page breaks are kept as empty paragraphs
+    let paragraphs: Vec<String> = text
         .split("\n\n")
-        .filter(|p| !p.trim().is_empty())
+        .flat_map(|p| {
+            if !p.trim().is_empty() {
+                paragraph_units(p.trim(), target_tokens, counter)
+            } else if p.contains(PAGE_BREAK) {
+                vec![String::new()]
+            } else {
+                Vec::new()
+            }
+        })
         .collect();
 
     let mut chunks = Vec::new();
     let mut current_chunk = String::new();
     let mut current_token_count = 0;
+    let mut after_page_break = false;
 
     // Process each paragraph
-    for paragraph in paragraphs {
+    for paragraph in &paragraphs {
+        if paragraph.is_empty() {
+            after_page_break = true;
+            continue;
+        }
         let paragraph = paragraph.trim();
 
         // Estimate token count for the paragraph
-        let paragraph_token_count = estimate_token_count(paragraph);
+        let paragraph_token_count = counter.count_tokens(paragraph);
 
         // If a single paragraph is too large, split it into sentences
-        if paragraph_token_count > TARGET_TOKENS {
+        if paragraph_token_count > target_tokens {
             // Split into sentences (naive split on punctuation)
-            let sentences: Vec<&str> = paragraph
-                .split(|c| ".!?\n".contains(c))
-                .filter(|s| !s.trim().is_empty())
-                .collect();
+            let sentences = split_sentences(paragraph);
 
             let mut sentence_buffer = String::new();
             let mut buffer_token_count = 0;
 
             for sentence in sentences {
-                let sentence = sentence.trim();
-                if sentence.is_empty() {
-                    continue;
-                }
-
-                let sentence_token_count = estimate_token_count(sentence);
+                let sentence_token_count = counter.count_tokens(sentence);
 
                 // If adding this sentence would exceed the token limit
-                if buffer_token_count + sentence_token_count > TARGET_TOKENS
+                if buffer_token_count + sentence_token_count > target_tokens
                     && !sentence_buffer.is_empty()
                 {
                     // Add the current buffer as a chunk
@@ -63,6 +461,9 @@ pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
                         token_count: buffer_token_count,
                         document_id: file_name.to_string(),
                         start_position,
+                        breadcrumb: None,
+                        row: None,
+                        page: None,
                     });
 
                     // Start a new buffer with overlap from the previous chunk
@@ -72,13 +473,13 @@ pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
                             sentence_buffer
                                 .chars()
                                 .count()
-                                .saturating_sub(OVERLAP_TOKENS * 4),
+                                .saturating_sub(overlap_tokens * 4),
                         ) // Approximate char count for overlap tokens
                         .map(|(i, _)| i)
                         .unwrap_or(0);
 
                     sentence_buffer = sentence_buffer[overlap_start..].trim().to_string();
-                    buffer_token_count = estimate_token_count(&sentence_buffer);
+                    buffer_token_count = counter.count_tokens(&sentence_buffer);
                 }
 
                 // Add the current sentence to the buffer
@@ -86,8 +487,7 @@ pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
                     sentence_buffer.push(' ');
                 }
                 sentence_buffer.push_str(sentence);
-                sentence_buffer.push('.'); // Add back the period
-                buffer_token_count += sentence_token_count + 1; // +1 for the period
+                buffer_token_count += sentence_token_count;
             }
 
             // Add any remaining content in the buffer
@@ -98,36 +498,49 @@ pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
                     token_count: buffer_token_count,
                     document_id: file_name.to_string(),
                     start_position,
+                    breadcrumb: None,
+                    row: None,
+                    page: None,
                 });
             }
         } else {
+            // A chunk already half full ends at a page break rather than run into the next page
+            let ends_page = after_page_break && current_token_count >= target_tokens / 2;
+
             // Check if adding this paragraph would exceed the token limit
-            if current_token_count + paragraph_token_count > TARGET_TOKENS
+            if (current_token_count + paragraph_token_count > target_tokens || ends_page)
                 && !current_chunk.is_empty()
             {
                 // Current chunk would exceed token limit, so finalize it
-                let start_position = text.find(&current_chunk).unwrap_or(0);
+                let start_position = chunk_start(text, &current_chunk);
                 chunks.push(TextChunk {
                     text: current_chunk.clone(),
                     token_count: current_token_count,
                     document_id: file_name.to_string(),
                     start_position,
+                    breadcrumb: None,
+                    row: None,
+                    page: None,
                 });
 
-                // Start a new chunk with overlap from the previous chunk
-                let overlap_start = current_chunk
-                    .char_indices()
-                    .nth(
-                        current_chunk
-                            .chars()
-                            .count()
-                            .saturating_sub(OVERLAP_TOKENS * 4),
-                    ) // Approximate char count for overlap tokens
-                    .map(|(i, _)| i)
-                    .unwrap_or(0);
+                // Start a new chunk with overlap from the previous chunk, unless on a new page
+                let overlap_start = if ends_page {
+                    current_chunk.len()
+                } else {
+                    current_chunk
+                        .char_indices()
+                        .nth(
+                            current_chunk
+                                .chars()
+                                .count()
+                                .saturating_sub(overlap_tokens * 4),
+                        ) // Approximate char count for overlap tokens
+                        .map(|(i, _)| i)
+                        .unwrap_or(0)
+                };
 
                 current_chunk = current_chunk[overlap_start..].trim().to_string();
-                current_token_count = estimate_token_count(&current_chunk);
+                current_token_count = counter.count_tokens(&current_chunk);
 
                 if !current_chunk.is_empty() {
                     current_chunk.push_str("\n\n");
@@ -141,35 +554,43 @@ pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
             current_chunk.push_str(paragraph);
             current_token_count += paragraph_token_count;
         }
+        after_page_break = false;
     }
 
     // Add the last chunk if it's not empty
     if !current_chunk.trim().is_empty() {
-        let start_position = text.find(&current_chunk).unwrap_or(0);
+        let start_position = chunk_start(text, &current_chunk);
         chunks.push(TextChunk {
             text: current_chunk,
             token_count: current_token_count,
             document_id: file_name.to_string(),
             start_position,
+            breadcrumb: None,
+            row: None,
+            page: None,
         });
     }
 
     // Ensure no chunk is too large
     let mut final_chunks = Vec::new();
     for chunk in chunks {
-        if chunk.token_count > TARGET_TOKENS * 3 {
+        if resplit && chunk.token_count > target_tokens * config.max_chunk_multiplier {
             // If a chunk is still too large, split it by sentences
             let TextChunk {
                 text,
                 token_count: _,
                 document_id,
-                start_position: _,
+                start_position,
+                breadcrumb: _,
+                row: _,
+                page: _,
             } = chunk;
             // Recursively split into chunks
-            let mut sub_chunks = split_into_chunks(&text, &document_id);
+            let mut sub_chunks = chunk_text(&text, &document_id, config, counter, false);
             // Ensure document_id is preserved in sub-chunks
             for sub_chunk in &mut sub_chunks {
                 sub_chunk.document_id = document_id.clone();
+                sub_chunk.start_position += start_position;
             }
             final_chunks.append(&mut sub_chunks);
         } else {
@@ -177,9 +598,242 @@ pub fn split_into_chunks(text: &str, file_name: &str) -> Vec<TextChunk> {
         }
     }
 
+    for chunk in &mut final_chunks {
+        chunk.start_position = original_position(&breaks, chunk.start_position);
+    }
+    if resplit {
+        for chunk in &mut final_chunks {
+            chunk.page = page_at(original, chunk.start_position);
+        }
+    }
     final_chunks
 }
 
+/// Position of a chunk in the text it was cut from
+/// A chunk running over a page break is located by its first paragraph, the break being left out
+/// of its text.
+fn chunk_start(text: &str, chunk: &str) -> usize {
+    text.find(chunk)
+        .or_else(|| {
+            let first_paragraph = chunk.split("\n\n").next()?;
+            text.find(first_paragraph)
+        })
+        .unwrap_or(0)
+}
+
+/// Page, counting from 1, of a position in text with `PAGE_BREAK`s, or `None` without any
+fn page_at(text: &str, position: usize) -> Option<usize> {
+    if !text.contains(PAGE_BREAK) {
+        return None;
+    }
+    // A form feed is a single byte in UTF-8 and never part of another character
+    let breaks = text.as_bytes()[..position.min(text.len())]
+        .iter()
+        .filter(|&&byte| byte == PAGE_BREAK as u8)
+        .count();
+    Some(breaks + 1)
+}
+
+/// Split text into trimmed sentences after each run of ".", "!" or "?" and at line breaks
+/// Sentences keep their own terminating punctuation
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let end = match c {
+            '\n' => i,
+            '.' | '!' | '?' => {
+                // Keep "..." and "?!" together
+                if matches!(chars.peek(), Some((_, '.' | '!' | '?'))) {
+                    continue;
+                }
+                i + 1
+            }
+            _ => continue,
+        };
+        sentences.push(&text[start..end]);
+        start = i + 1;
+    }
+    sentences.push(&text[start..]);
+
+    sentences
+        .into_iter()
+        .map(str::trim)
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+/// Split a paragraph into prose and markdown table blocks so that a table is never split
+/// mid-row or mixed with prose; tables above `max_tokens` become row groups that each
+/// repeat the header
+fn paragraph_units(paragraph: &str, max_tokens: usize, counter: &dyn TokenCounter) -> Vec<String> {
+    if !paragraph.lines().any(is_table_row) {
+        return vec![paragraph.to_string()];
+    }
+
+    let mut units = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    let mut block_is_table = false;
+
+    for line in paragraph.lines() {
+        let line_is_table = is_table_row(line);
+        if line_is_table != block_is_table && !block.is_empty() {
+            push_block(&mut units, &block, block_is_table, max_tokens, counter);
+            block.clear();
+        }
+        block_is_table = line_is_table;
+        block.push(line);
+    }
+    if !block.is_empty() {
+        push_block(&mut units, &block, block_is_table, max_tokens, counter);
+    }
+
+    units
+}
+
+/// Add a block of lines to the paragraph units, splitting large tables into row groups
+fn push_block(
+    units: &mut Vec<String>,
+    lines: &[&str],
+    is_table: bool,
+    max_tokens: usize,
+    counter: &dyn TokenCounter,
+) {
+    if is_table {
+        units.extend(table_row_groups(lines, max_tokens, counter));
+    } else {
+        units.push(lines.join("\n"));
+    }
+}
+
+/// Split table lines into groups of whole rows within `max_tokens`, each starting with the
+/// header row (and its separator row, if any)
+fn table_row_groups(lines: &[&str], max_tokens: usize, counter: &dyn TokenCounter) -> Vec<String> {
+    let table = lines.join("\n");
+    if lines.len() < 3 || counter.count_tokens(&table) <= max_tokens {
+        return vec![table];
+    }
+
+    let header_len = if is_table_separator(lines[1]) { 2 } else { 1 };
+    let header = lines[..header_len].join("\n");
+    let header_tokens = counter.count_tokens(&header);
+
+    let mut groups = Vec::new();
+    let mut group = header.clone();
+    let mut group_tokens = header_tokens;
+    let mut group_rows = 0;
+
+    for row in &lines[header_len..] {
+        let row_tokens = counter.count_tokens(row);
+        if group_rows > 0 && group_tokens + row_tokens > max_tokens {
+            groups.push(std::mem::replace(&mut group, header.clone()));
+            group_tokens = header_tokens;
+            group_rows = 0;
+        }
+
+        group.push('\n');
+        group.push_str(row);
+        group_tokens += row_tokens;
+        group_rows += 1;
+    }
+    if group_rows > 0 {
+        groups.push(group);
+    }
+
+    groups
+}
+
+/// Whether a line is a row of a pipe-delimited markdown table
+fn is_table_row(line: &str) -> bool {
+    line.trim_start().starts_with('|')
+}
+
+/// Whether a table row is the separator under the header, such as "|---|:---:|"
+fn is_table_separator(line: &str) -> bool {
+    let line = line.trim();
+    line.contains('-') && line.chars().all(|c| "|-: ".contains(c))
+}
+
+/// Break text with very few paragraph breaks for its length (such as a document on a single
+/// line) into paragraphs at sentence ends; other text is returned unchanged
+pub fn add_synthetic_paragraph_breaks(text: &str) -> Cow<'_, str> {
+    with_paragraph_breaks(text, &synthetic_break_positions(text))
+}
+
+/// Positions of the spaces after sentence ends that `add_synthetic_paragraph_breaks` turns into
+/// paragraph breaks, in ascending order
+fn synthetic_break_positions(text: &str) -> Vec<usize> {
+    const MAX_AVERAGE_PARAGRAPH_CHARS: usize = 4000; // Longer on average means breaks are missing
+    const SYNTHETIC_PARAGRAPH_CHARS: usize = 1500; // Length after which a sentence end breaks
+
+    let paragraph_count = text
+        .split("\n\n")
+        .filter(|p| !p.trim().is_empty())
+        .count()
+        .max(1);
+    if text.len() / paragraph_count <= MAX_AVERAGE_PARAGRAPH_CHARS {
+        return Vec::new();
+    }
+
+    let mut breaks = Vec::new();
+    let mut paragraph_len = 0;
+    let mut previous = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((_, c)) = chars.next() {
+        paragraph_len += c.len_utf8();
+
+        // Existing paragraph breaks are kept and restart the count
+        if c == '\n' && previous == Some('\n') {
+            paragraph_len = 0;
+        }
+        previous = Some(c);
+
+        // Replace the space after a sentence end once the paragraph is long enough
+        if paragraph_len >= SYNTHETIC_PARAGRAPH_CHARS && ".!?".contains(c) {
+            if let Some(&(position, ' ')) = chars.peek() {
+                chars.next();
+                breaks.push(position);
+                previous = Some('\n');
+                paragraph_len = 0;
+            }
+        }
+    }
+
+    breaks
+}
+
+/// Text with the spaces at `breaks` replaced by paragraph breaks
+fn with_paragraph_breaks<'a>(text: &'a str, breaks: &[usize]) -> Cow<'a, str> {
+    if breaks.is_empty() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len() + breaks.len());
+    let mut copied = 0;
+    for &position in breaks {
+        result.push_str(&text[copied..position]);
+        result.push_str("\n\n");
+        copied = position + 1;
+    }
+    result.push_str(&text[copied..]);
+
+    Cow::Owned(result)
+}
+
+/// Position in the original text of a position in the text with paragraph breaks at `breaks`
+/// Each break takes one more byte than the space it replaces.
+fn original_position(breaks: &[usize], position: usize) -> usize {
+    let shifted = breaks
+        .iter()
+        .enumerate()
+        .take_while(|&(i, &space)| space + i < position)
+        .count();
+    position - shifted
+}
+
 /// Calculate approximate token count for a text
 /// This is a very simple estimation - words plus punctuation
 pub fn estimate_token_count(text: &str) -> usize {
@@ -188,3 +842,430 @@ pub fn estimate_token_count(text: &str) -> usize {
     let punctuation = text.chars().filter(|c| c.is_ascii_punctuation()).count();
     words + punctuation
 }
+
+/// Counts the tokens of a text; implementations trade accuracy for speed
+pub trait TokenCounter: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Word and punctuation heuristic of `estimate_token_count`: fast, but it undercounts CJK
+/// text, code and long words
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FallbackCounter;
+
+impl TokenCounter for FallbackCounter {
+    fn count_tokens(&self, text: &str) -> usize {
+        estimate_token_count(text)
+    }
+}
+
+/// Byte-pair encoding counter (cl100k_base), much closer to the model's own token counts
+#[cfg(feature = "tiktoken")]
+pub struct BpeCounter {
+    bpe: tiktoken_rs::CoreBPE,
+}
+
+#[cfg(feature = "tiktoken")]
+impl BpeCounter {
+    /// Load the cl100k_base encoding
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(BpeCounter {
+            bpe: tiktoken_rs::cl100k_base()?,
+        })
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl TokenCounter for BpeCounter {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+}
+
+/// Rebuild a document's text from its chunks in order, dropping the overlap between neighbours
+/// This is an approximation of the source, good enough to contextualize or re-chunk it
+pub fn merge_chunk_texts(chunks: &[TextChunk]) -> String {
+    // Shorter matches are more likely to be coincidence than real overlap
+    const MIN_OVERLAP_BYTES: usize = 16;
+
+    let mut merged = String::new();
+
+    for chunk in chunks {
+        let text = chunk.text.as_str();
+
+        // Find the longest prefix of this chunk that repeats the end of the merged text
+        let overlap = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .rev()
+            .find(|&i| i >= MIN_OVERLAP_BYTES && merged.ends_with(&text[..i]))
+            .unwrap_or(0);
+
+        if overlap > 0 {
+            // The remainder keeps its original separator from the overlapped text
+            merged.push_str(&text[overlap..]);
+        } else {
+            if !merged.is_empty() {
+                merged.push_str("\n\n");
+            }
+            merged.push_str(text);
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(text: &str) -> TextChunk {
+        TextChunk {
+            text: text.to_string(),
+            token_count: estimate_token_count(text),
+            document_id: "test.txt".to_string(),
+            start_position: 0,
+            breadcrumb: None,
+            row: None,
+            page: None,
+        }
+    }
+
+    #[test]
+    fn test_quality_filter() {
+        let filter = ChunkQualityFilter::default();
+
+        assert!(filter.accepts(&chunk(
+            "The quarterly report shows steady growth across all regions."
+        )));
+        assert!(!filter.accepts(&chunk("12.5 | 13.7 | 14.2 | 15.9 | 16.1 | 17.3")));
+        assert!(!filter.accepts(&chunk("page page page page page page")));
+        assert!(!filter.accepts(&chunk("   ")));
+    }
+
+    #[test]
+    fn test_chunk_stats() {
+        let sized = |token_count| TextChunk {
+            token_count,
+            ..chunk("text")
+        };
+
+        let stats = ChunkStats::from_chunks(&[sized(40), sized(480), sized(510), sized(250)]);
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.min_tokens, 40);
+        assert_eq!(stats.max_tokens, 510);
+        assert_eq!(stats.mean, 320.0);
+        assert_eq!(stats.histogram, vec![1, 0, 1, 0, 1, 1]);
+
+        let empty = ChunkStats::from_chunks(&[]);
+        assert_eq!(empty.count, 0);
+        assert!(empty.histogram.is_empty());
+    }
+
+    #[test]
+    fn test_chunking_config() {
+        let text = "A sentence of moderate length about nothing in particular. ".repeat(100);
+
+        let config = ChunkingConfig {
+            target_tokens: 100,
+            overlap_tokens: 10,
+            max_chunk_multiplier: 3,
+        };
+        let chunks =
+            split_into_chunks_with_config(&text, "doc.txt", &config, &FallbackCounter).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.token_count <= 300));
+
+        // Overlap must be smaller than the target
+        let config = ChunkingConfig {
+            target_tokens: 50,
+            overlap_tokens: 50,
+            ..ChunkingConfig::default()
+        };
+        assert!(
+            split_into_chunks_with_config(&text, "doc.txt", &config, &FallbackCounter).is_err()
+        );
+        assert!(ChunkingConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_sentence_punctuation_preserved() {
+        let paragraph = "Why? How? When? Because it rained! Wait... really. ".repeat(200);
+
+        let chunks = split_into_chunks(&paragraph, "questions.txt", &FallbackCounter);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(
+                !chunk.text.contains("?."),
+                "doubled punctuation: {}",
+                chunk.text
+            );
+            assert!(
+                !chunk.text.contains("!."),
+                "doubled punctuation: {}",
+                chunk.text
+            );
+            assert!(!chunk.text.contains("...."), "extra period: {}", chunk.text);
+        }
+
+        assert_eq!(
+            split_sentences("One. Two?! Three...\nno stop"),
+            vec!["One.", "Two?!", "Three...", "no stop"]
+        );
+    }
+
+    #[test]
+    fn test_split_markdown() {
+        let body = "Some explanation of this part of the guide, with enough words to matter. ";
+        let text = format!(
+            "Preface text.\n\n# Intro\n\n{}\n\n## Setup\n\n{}\n\n### Auth\n\n{}\n\n```\n# not a heading\n```\n\n## Usage\n\n{}",
+            body.repeat(10),
+            body.repeat(10),
+            body.repeat(3),
+            body.repeat(10)
+        );
+
+        let chunks = split_markdown(
+            &text,
+            "guide.md",
+            &ChunkingConfig::default(),
+            &FallbackCounter,
+        )
+        .unwrap();
+        let breadcrumbs: Vec<Option<&str>> = chunks
+            .iter()
+            .map(|chunk| chunk.breadcrumb.as_deref())
+            .collect();
+        assert_eq!(
+            breadcrumbs,
+            vec![Some("Intro"), Some("Intro > Setup"), Some("Intro > Usage")]
+        );
+
+        // The short preface joins the first section
+        assert!(chunks[0].text.starts_with("Intro\n\nPreface text."));
+
+        // The H3 section fits in its parent's chunk; the fenced line is not a heading
+        assert!(chunks[1].text.starts_with("Intro > Setup\n\n## Setup"));
+        assert!(chunks[1].text.contains("### Auth"));
+        assert!(chunks[1].text.contains("# not a heading"));
+        assert!(!chunks[0].text.contains("## Setup"));
+        assert_eq!(&text[chunks[2].start_position..][..8], "## Usage");
+
+        // A tiny section joins the next one under the headings they share
+        let text = format!(
+            "# Intro\n\n## Setup\n\nShort.\n\n## Usage\n\n{}",
+            body.repeat(10)
+        );
+        let chunks = split_markdown(
+            &text,
+            "guide.md",
+            &ChunkingConfig::default(),
+            &FallbackCounter,
+        )
+        .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].breadcrumb.as_deref(), Some("Intro"));
+
+        // Without headings it splits like plain text
+        let plain = "Just a paragraph.\n\nAnd another one.";
+        let chunks = split_markdown(
+            plain,
+            "notes.md",
+            &ChunkingConfig::default(),
+            &FallbackCounter,
+        )
+        .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].breadcrumb.is_none());
+    }
+
+    #[test]
+    fn test_page_breaks() {
+        let page = |label: &str, sentences: usize| {
+            format!("This sentence is on the {} page. ", label)
+                .repeat(sentences)
+                .trim()
+                .to_string()
+        };
+        let text = format!(
+            "{}\n\n{c}\n\n{}\n\n{}\n\n{c}\n\n{c}\n\n{}",
+            page("first", 5),
+            page("second", 2),
+            page("second", 2),
+            page("fourth", 1),
+            c = PAGE_BREAK
+        );
+        let config = ChunkingConfig {
+            target_tokens: 80,
+            overlap_tokens: 5,
+            ..ChunkingConfig::default()
+        };
+
+        let chunks =
+            split_into_chunks_with_config(&text, "report.pdf", &config, &FallbackCounter).unwrap();
+        // The first page fills half a chunk and ends it, while the second page, too short to,
+        // runs on into the fourth after the empty third
+        assert_eq!(chunks.len(), 2);
+        assert!(!chunks[0].text.contains("second"));
+        assert!(chunks[1]
+            .text
+            .starts_with("This sentence is on the second page."));
+        assert!(chunks[1]
+            .text
+            .ends_with("This sentence is on the fourth page."));
+        assert!(chunks.iter().all(|chunk| !chunk.text.contains(PAGE_BREAK)));
+        let pages: Vec<Option<usize>> = chunks.iter().map(|chunk| chunk.page).collect();
+        assert_eq!(pages, vec![Some(1), Some(2)]);
+
+        assert_eq!(page_at(&text, text.len()), Some(4));
+        assert_eq!(page_at("No pages here.", 5), None);
+    }
+
+    #[test]
+    fn test_split_csv_rows() {
+        let csv = "Name,Price,Notes\n\
+                   Kettle,25,Stainless steel\n\
+                   Toaster,40,\n\
+                   \"Mug, large\",8,Dishwasher safe\n\
+                   Teapot,30,Holds 1.5 litres\n\
+                   Blender,,Out of stock\n";
+
+        let chunks = split_csv_rows(csv, "catalog.csv", b',', 1, &FallbackCounter).unwrap();
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(
+            chunks[0].text,
+            "Name: Kettle\nPrice: 25\nNotes: Stainless steel"
+        );
+        // Empty cells are left out
+        assert_eq!(chunks[1].text, "Name: Toaster\nPrice: 40");
+        assert_eq!(
+            chunks[2].text,
+            "Name: Mug, large\nPrice: 8\nNotes: Dishwasher safe"
+        );
+        assert_eq!(chunks[4].text, "Name: Blender\nNotes: Out of stock");
+        // The header row is not data
+        assert!(chunks
+            .iter()
+            .all(|chunk| !chunk.text.contains("Name: Name")));
+        let rows: Vec<Option<usize>> = chunks.iter().map(|chunk| chunk.row).collect();
+        assert_eq!(rows, vec![Some(0), Some(1), Some(2), Some(3), Some(4)]);
+        assert!(csv[chunks[1].start_position..].starts_with("Toaster"));
+
+        // Rows are grouped, the last group taking what is left
+        let chunks = split_csv_rows(csv, "catalog.csv", b',', 2, &FallbackCounter).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[0].text,
+            "Name: Kettle\nPrice: 25\nNotes: Stainless steel\n\nName: Toaster\nPrice: 40"
+        );
+        assert_eq!(chunks[2].row, Some(4));
+
+        let tsv = csv.replace(',', "\t").replace("\"Mug\t large\"", "Mug");
+        let chunks = split_csv_rows(&tsv, "catalog.tsv", b'\t', 1, &FallbackCounter).unwrap();
+        assert_eq!(
+            chunks[2].text,
+            "Name: Mug\nPrice: 8\nNotes: Dishwasher safe"
+        );
+    }
+
+    #[test]
+    fn test_table_chunking() {
+        let mut text = String::from("Quarterly results by region are listed below.\n");
+        text.push_str("| Region | Quarter | Revenue | Growth |\n|---|---|---|---|\n");
+        for i in 0..30 {
+            text.push_str(&format!(
+                "| Region {} with a fairly long descriptive name | Q{} | {} million dollars | {}% |\n",
+                i,
+                i % 4 + 1,
+                100 + i,
+                i
+            ));
+        }
+        text.push_str("\nThe totals are discussed in the next section of this report.");
+
+        let chunks = split_into_chunks(&text, "report.md", &FallbackCounter);
+        let table_chunks: Vec<&TextChunk> = chunks
+            .iter()
+            .filter(|chunk| chunk.text.contains("| Region "))
+            .collect();
+
+        // The table does not fit in one chunk, so it is split into row groups
+        assert!(table_chunks.len() > 1);
+        for chunk in &table_chunks {
+            assert!(chunk
+                .text
+                .contains("| Region | Quarter | Revenue | Growth |\n|---|---|---|---|"));
+            // Every row is whole
+            for line in chunk.text.lines().filter(|line| line.starts_with('|')) {
+                assert!(line.ends_with('|'), "split row: {}", line);
+            }
+        }
+
+        // Every row is kept
+        for i in 0..30 {
+            let row = format!("| Region {} with", i);
+            assert!(table_chunks.iter().any(|chunk| chunk.text.contains(&row)));
+        }
+
+        // A small table stays whole and apart from the prose
+        let small = "Intro line.\n| a | b |\n|---|---|\n| 1 | 2 |\nOutro line.";
+        assert_eq!(
+            paragraph_units(small, 500, &FallbackCounter),
+            vec![
+                "Intro line.",
+                "| a | b |\n|---|---|\n| 1 | 2 |",
+                "Outro line."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_synthetic_paragraph_breaks() {
+        let short = "First paragraph. Still first.\n\nSecond paragraph.";
+        assert!(matches!(
+            add_synthetic_paragraph_breaks(short),
+            Cow::Borrowed(_)
+        ));
+
+        // A single 50k-character line
+        let sentence = "The committee reviewed the proposal and approved the budget. ";
+        let single_line = sentence.repeat(50_000 / sentence.len());
+        let broken = add_synthetic_paragraph_breaks(&single_line);
+        let paragraphs: Vec<&str> = broken.split("\n\n").collect();
+        assert!(paragraphs.len() > 20);
+        assert!(paragraphs.iter().all(|p| p.len() < 1600));
+
+        let chunks = split_into_chunks(&single_line, "single_line.txt", &FallbackCounter);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.token_count <= 500));
+
+        // Chunk positions point into the text as given, not the one with the breaks added
+        let numbered: String = (0..800)
+            .map(|i| format!("Item {} was reviewed and approved. ", i))
+            .collect();
+        let chunks = split_into_chunks(&numbered, "numbered.txt", &FallbackCounter);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            let first_line = chunk.text.lines().next().unwrap();
+            assert!(numbered[chunk.start_position..].starts_with(first_line));
+        }
+        assert_eq!(original_position(&[10, 20], 25), 23);
+        assert_eq!(original_position(&[10, 20], 5), 5);
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn test_bpe_counter() {
+        let bpe = BpeCounter::new().unwrap();
+
+        // Plain English words are single tokens for both
+        assert_eq!(bpe.count_tokens("hello world"), 2);
+        assert_eq!(FallbackCounter.count_tokens("hello world"), 2);
+
+        // Text without spaces is one word to the heuristic but many tokens
+        let japanese = "日本語のテキストは空白で区切られません";
+        assert_eq!(FallbackCounter.count_tokens(japanese), 1);
+        assert!(bpe.count_tokens(japanese) > 5);
+    }
+}
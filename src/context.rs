@@ -1,7 +1,12 @@
-use crate::chunking::{estimate_token_count, TextChunk};
+use crate::chunking::{
+    estimate_token_count, markdown_heading, FallbackCounter, TextChunk, TokenCounter,
+};
 use crate::gemini::GeminiClient;
 use anyhow::Result;
-use log::{info, warn};
+use futures::stream::{self, StreamExt};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::time::sleep;
@@ -17,10 +22,110 @@ pub struct ContextualizedChunk {
     pub token_count: usize,
 }
 
+impl ContextualizedChunk {
+    /// Wrap a chunk without adding any context
+    pub fn without_context(chunk: TextChunk) -> Self {
+        ContextualizedChunk {
+            contextualized_text: chunk.text.clone(),
+            token_count: chunk.token_count,
+            original_chunk: chunk,
+        }
+    }
+}
+
+/// How chunks are given context before embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextMode {
+    /// Ask the model to situate each chunk within the whole document
+    #[default]
+    LlmGenerated,
+    /// Prefix each chunk with the document title and nearest heading, without API calls
+    TitleAndHeading,
+    /// Embed chunks as they are
+    None,
+}
+
+impl FromStr for ContextMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "llm" => Ok(ContextMode::LlmGenerated),
+            "title" | "title-and-heading" => Ok(ContextMode::TitleAndHeading),
+            "none" => Ok(ContextMode::None),
+            _ => Err(anyhow::anyhow!(
+                "Unknown context mode: {} (expected llm, title or none)",
+                s
+            )),
+        }
+    }
+}
+
+/// Prefix a chunk with its document title and the nearest heading above it
+pub fn title_and_heading_context(chunk: TextChunk, source_document: &str) -> ContextualizedChunk {
+    let heading = nearest_heading(&chunk, source_document);
+
+    let context = match heading {
+        Some(heading) => format!("{} > {}", chunk.document_id, heading),
+        None => chunk.document_id.clone(),
+    };
+
+    let contextualized_text = format!("Context: {}\n\n{}", context, chunk.text);
+    let token_count = estimate_token_count(&contextualized_text);
+
+    ContextualizedChunk {
+        original_chunk: chunk,
+        contextualized_text,
+        token_count,
+    }
+}
+
+/// Build the "{title} | {heading}: " prefix of a chunk from its document title and heading
+pub fn metadata_prefix(chunk: &TextChunk, source_document: &str) -> String {
+    match nearest_heading(chunk, source_document) {
+        Some(heading) => format!("{} | {}: ", chunk.document_id, heading),
+        None => format!("{}: ", chunk.document_id),
+    }
+}
+
+/// Find the markdown heading that a chunk falls under
+fn nearest_heading<'a>(chunk: &'a TextChunk, source_document: &'a str) -> Option<&'a str> {
+    // A chunk starting with a heading belongs to that heading
+    if let Some(heading) = chunk.text.lines().next().and_then(parse_heading) {
+        return Some(heading);
+    }
+
+    source_document
+        .get(..chunk.start_position)?
+        .lines()
+        .rev()
+        .find_map(parse_heading)
+}
+
+/// Extract the text of a markdown heading line such as "## Setup"
+fn parse_heading(line: &str) -> Option<&str> {
+    markdown_heading(line).map(|(_, text)| text)
+}
+
+/// Build the prompt asking the model to situate a chunk within its document
+pub fn context_prompt(source_document: &str, chunk_text: &str) -> String {
+    format!(
+        "<document>\n{}\n</document>\nHere is the chunk we want to situate within the whole document\n<chunk>\n{}\n</chunk>\nPlease give a short succinct context to situate this chunk within the overall document for the purposes of improving search retrieval of the chunk. Answer only with the succinct context and nothing else.",
+        source_document, chunk_text
+    )
+}
+
+/// Context requests sent at the same time unless configured otherwise
+pub const DEFAULT_CONTEXT_CONCURRENCY: usize = 4;
+
 /// Context Generator for enhancing chunks with document context
 pub struct ContextGenerator {
     gemini_client: GeminiClient,
-    rate_limiter: Arc<Mutex<RateLimiter>>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Counts prompt tokens against the rate limit
+    token_counter: Arc<dyn TokenCounter>,
+    /// Chunks whose context is generated at the same time
+    concurrency: usize,
 }
 
 impl ContextGenerator {
@@ -28,10 +133,25 @@ impl ContextGenerator {
     pub fn new(gemini_client: GeminiClient) -> Self {
         ContextGenerator {
             gemini_client,
-            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(30, 1_000_000))),
+            rate_limiter: Arc::new(RateLimiter::new(30, 1_000_000)),
+            token_counter: Arc::new(FallbackCounter),
+            concurrency: DEFAULT_CONTEXT_CONCURRENCY,
         }
     }
 
+    /// Generate the context of up to `concurrency` chunks at the same time
+    /// The rate limit still applies to all of them together
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Count prompt tokens for rate limiting with the given counter
+    pub fn with_token_counter(mut self, token_counter: Arc<dyn TokenCounter>) -> Self {
+        self.token_counter = token_counter;
+        self
+    }
+
     /// Generate contextual information for a chunk
     pub async fn generate_context_for_chunk(
         &self,
@@ -54,15 +174,20 @@ impl ContextGenerator {
         }
 
         // Create the prompt for context generation
-        let prompt = format!(
-            "<document>\n{}\n</document>\nHere is the chunk we want to situate within the whole document\n<chunk>\n{}\n</chunk>\nPlease give a short succinct context to situate this chunk within the overall document for the purposes of improving search retrieval of the chunk. Answer only with the succinct context and nothing else.",
-            source_document,
-            chunk.text
-        );
+        let prompt = context_prompt(source_document, &chunk.text);
 
         // Create a custom request for context generation using Gemini 2.0 Flash-Lite
         let context = self.generate_context_with_flash_lite(&prompt).await?;
 
+        // An empty answer would leave a hollow "Context:" prefix, so embed the chunk as is
+        if context.trim().is_empty() {
+            debug!(
+                "Empty context for chunk at position {} of {}, using the chunk text alone",
+                chunk.start_position, chunk.document_id
+            );
+            return Ok(ContextualizedChunk::without_context(chunk));
+        }
+
         // Combine the generated context with the original chunk
         let contextualized_text = format!("Context: {}\n\n{}", context.trim(), chunk.text);
         let token_count = crate::chunking::estimate_token_count(&contextualized_text);
@@ -80,25 +205,48 @@ impl ContextGenerator {
         chunks: Vec<TextChunk>,
         source_document: &str,
     ) -> Result<Vec<ContextualizedChunk>> {
-        let mut contextualized_chunks = Vec::new();
+        self.contextualize_chunks_with_progress(chunks, source_document, |_, _| {})
+            .await
+    }
 
+    /// Generate contexts for multiple chunks, several at a time, calling
+    /// `on_progress(done, total)` after each
+    /// Contexts are returned in the order of the chunks
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "contextualize_chunks", skip_all, fields(chunks = chunks.len()))
+    )]
+    pub async fn contextualize_chunks_with_progress(
+        &self,
+        chunks: Vec<TextChunk>,
+        source_document: &str,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<ContextualizedChunk>> {
         // Get total number of chunks for progress reporting
         let total_chunks = chunks.len();
+        let mut contextualized_chunks = Vec::with_capacity(total_chunks);
         info!("Contextualizing {} chunks...", total_chunks);
 
-        for (i, chunk) in chunks.into_iter().enumerate() {
-            let contextualized_chunk = self
-                .generate_context_for_chunk(chunk, source_document)
-                .await?;
-            contextualized_chunks.push(contextualized_chunk);
+        // Results are yielded in chunk order, so progress only counts chunks ready to use
+        let mut contexts = stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| self.generate_context_for_chunk(chunk, source_document)),
+        )
+        .buffered(self.concurrency);
+
+        while let Some(contextualized_chunk) = contexts.next().await {
+            contextualized_chunks.push(contextualized_chunk?);
+            let done = contextualized_chunks.len();
+            on_progress(done, total_chunks);
 
             // Log progress after every 5th chunk
-            if (i + 1) % 5 == 0 {
+            if done % 5 == 0 {
                 info!(
                     "Context progress: processed {}/{} chunks ({}%)",
-                    i + 1,
+                    done,
                     total_chunks,
-                    ((i + 1) * 100) / total_chunks
+                    (done * 100) / total_chunks
                 );
             }
         }
@@ -117,117 +265,134 @@ impl ContextGenerator {
     /// Generate context using Gemini 2.0 Flash-Lite model specifically for summarization
     /// Rate limited to 30 RPM and 1,000,000 TPM for prompts
     async fn generate_context_with_flash_lite(&self, prompt: &str) -> Result<String> {
-        // Estimate token count for the prompt
-        let prompt_token_count = estimate_token_count(prompt);
-
         // Apply rate limiting
-        let wait_duration = {
-            let mut limiter = self.rate_limiter.lock().unwrap();
-            limiter.check_and_update(prompt_token_count)
-        };
-
-        if !wait_duration.is_zero() {
-            warn!(
-                "Rate limit reached, waiting for {:?} before sending request",
-                wait_duration
-            );
-            sleep(wait_duration).await;
-        }
+        self.rate_limiter
+            .acquire(self.token_counter.count_tokens(prompt))
+            .await;
 
         // Use the gemini module's generate_context method
         self.gemini_client.generate_context(prompt).await
     }
 }
 
-/// Rate limiter for API requests
+/// Rate limiter for API requests, shared by requests sent concurrently
 struct RateLimiter {
-    /// Maximum requests per minute
-    max_rpm: usize,
-    /// Maximum tokens per minute for prompts
-    max_tpm: usize,
-    /// Timestamps of recent requests
-    request_timestamps: Vec<Instant>,
-    /// Token counts of recent requests
-    token_counts: Vec<usize>,
+    /// Maximum requests per window
+    max_requests: usize,
+    /// Maximum prompt tokens per window
+    max_tokens: usize,
+    /// Length of the window the limits apply to
+    window: Duration,
+    /// Time and token count of the requests granted within the window, oldest first
+    granted: Mutex<VecDeque<(Instant, usize)>>,
 }
 
 impl RateLimiter {
-    /// Create a new rate limiter
+    /// Create a rate limiter allowing `max_rpm` requests and `max_tpm` prompt tokens per minute
     fn new(max_rpm: usize, max_tpm: usize) -> Self {
+        Self::with_window(max_rpm, max_tpm, Duration::from_secs(60))
+    }
+
+    /// Create a rate limiter over a window of the given length
+    fn with_window(max_requests: usize, max_tokens: usize, window: Duration) -> Self {
         RateLimiter {
-            max_rpm,
-            max_tpm,
-            request_timestamps: Vec::new(),
-            token_counts: Vec::new(),
+            max_requests,
+            max_tokens,
+            window,
+            granted: Mutex::new(VecDeque::new()),
         }
     }
 
-    /// Check if the rate limit has been reached and update the internal state
-    /// Returns the duration to wait if the rate limit has been reached
-    fn check_and_update(&mut self, token_count: usize) -> Duration {
-        let now = Instant::now();
-        let one_minute_ago = now - Duration::from_secs(60);
-
-        // Remove entries older than 1 minute
-        let mut i = 0;
-        while i < self.request_timestamps.len() {
-            if self.request_timestamps[i] < one_minute_ago {
-                self.request_timestamps.remove(i);
-                self.token_counts.remove(i);
-            } else {
-                i += 1;
-            }
+    /// Wait until a request with `tokens` prompt tokens fits within the limits, and count it
+    /// After every wait the limits are checked again, so concurrent requests woken together
+    /// only go ahead as far as the limits allow
+    async fn acquire(&self, tokens: usize) {
+        while let Some(wait_duration) = self.try_acquire(tokens, Instant::now()) {
+            warn!(
+                "Rate limit reached, waiting for {:?} before sending request",
+                wait_duration
+            );
+            sleep(wait_duration).await;
         }
+    }
 
-        // Calculate current rates
-        let current_rpm = self.request_timestamps.len();
-        let current_tpm: usize = self.token_counts.iter().sum();
-
-        // Check if adding this request would exceed limits
-        if current_rpm >= self.max_rpm || current_tpm + token_count > self.max_tpm {
-            // Log which limit was exceeded
-            if current_rpm >= self.max_rpm {
-                warn!(
-                    "Rate limit exceeded: {}/{} requests per minute",
-                    current_rpm, self.max_rpm
-                );
-            }
-            if current_tpm + token_count > self.max_tpm {
-                warn!(
-                    "Token limit exceeded: {}/{} tokens per minute (trying to add {} tokens)",
-                    current_tpm, self.max_tpm, token_count
-                );
-            }
+    /// Count a request made at `now` if it fits within the limits, or return how long to wait
+    /// before trying again
+    /// A request alone in the window always fits, even with more tokens than the limit
+    fn try_acquire(&self, tokens: usize, now: Instant) -> Option<Duration> {
+        let mut granted = self.granted.lock().unwrap();
 
-            // Calculate how long to wait
-            if !self.request_timestamps.is_empty() {
-                let oldest_timestamp = self.request_timestamps[0];
-                // Calculate when the oldest request will expire from the window
-                let expiry_time = oldest_timestamp + Duration::from_secs(60);
-                let wait_duration = if expiry_time > now {
-                    expiry_time - now
-                } else {
-                    Duration::ZERO
-                };
-                let final_wait = wait_duration + Duration::from_millis(100); // Add a small buffer
-                warn!(
-                    "Rate limiter enforcing wait of {:?} before next request",
-                    final_wait
-                );
-                return final_wait;
+        // Forget requests that have left the window
+        while let Some(&(granted_at, _)) = granted.front() {
+            if now.duration_since(granted_at) < self.window {
+                break;
             }
-            let fallback_wait = Duration::from_secs(1); // Fallback wait time
-            warn!(
-                "Rate limiter enforcing fallback wait of {:?}",
-                fallback_wait
+            granted.pop_front();
+        }
+
+        let requests = granted.len();
+        let window_tokens: usize = granted.iter().map(|&(_, tokens)| tokens).sum();
+        let Some(&(oldest, _)) = granted.front() else {
+            granted.push_back((now, tokens));
+            return None;
+        };
+
+        if requests >= self.max_requests {
+            debug!(
+                "Rate limit exceeded: {}/{} requests per window",
+                requests, self.max_requests
             );
-            return fallback_wait;
+        } else if window_tokens + tokens > self.max_tokens {
+            debug!(
+                "Token limit exceeded: {}/{} tokens per window (trying to add {} tokens)",
+                window_tokens, self.max_tokens, tokens
+            );
+        } else {
+            granted.push_back((now, tokens));
+            return None;
         }
 
-        // Update state with the new request
-        self.request_timestamps.push(now);
-        self.token_counts.push(token_count);
+        // Room opens up once the oldest request leaves the window; a small buffer keeps the
+        // retry from landing just before that
+        let expires_in = (oldest + self.window).saturating_duration_since(now);
+        Some(expires_in + Duration::from_millis(100))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter_window() {
+        let window = Duration::from_millis(200);
+        let limiter = Arc::new(RateLimiter::with_window(30, 1_000_000, window));
+
+        // Rapid concurrent acquisitions, more than one window allows
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..40 {
+            let limiter = limiter.clone();
+            tasks.spawn(async move {
+                limiter.acquire(10).await;
+                Instant::now()
+            });
+        }
+        let mut granted_at = Vec::new();
+        while let Some(result) = tasks.join_next().await {
+            granted_at.push(result.unwrap());
+        }
+        granted_at.sort();
 
-        Duration::ZERO // No need to wait
+        // The request 30 after any other falls outside that one's window
+        for (earlier, later) in granted_at.iter().zip(&granted_at[30..]) {
+            assert!(later.duration_since(*earlier) >= window);
+        }
+
+        // Tokens are limited the same way, but a lone oversized request still goes through
+        let tokens = RateLimiter::with_window(100, 50, window);
+        let now = Instant::now();
+        assert!(tokens.try_acquire(80, now).is_none());
+        assert!(tokens.try_acquire(1, now).is_some());
+        assert!(tokens.try_acquire(1, now + window).is_none());
     }
 }
@@ -1,34 +1,227 @@
 use crate::chunking::TextChunk;
-use crate::gemini::Embedding;
+use crate::context::ContextualizedChunk;
+use crate::gemini::{Embedding, EmbeddingProfile};
 use anyhow::{Context, Result};
-use qdrant_client::qdrant::UpsertPointsBuilder;
-use qdrant_client::qdrant::{CreateCollectionBuilder, Distance, PointStruct, Value, VectorParams};
-use qdrant_client::Qdrant;
+use log::warn;
+use qdrant_client::qdrant::vectors_config::Config;
+use qdrant_client::qdrant::vectors_output::VectorsOptions;
+use qdrant_client::qdrant::{vector_output, VectorOutput, Vectors, VectorsOutput};
+use qdrant_client::qdrant::{Condition, DeletePointsBuilder, Filter, SetPayloadPointsBuilder};
+use qdrant_client::qdrant::{CountPointsBuilder, CreateCollectionBuilder, Distance, PointStruct};
+use qdrant_client::qdrant::{CreateFieldIndexCollectionBuilder, FieldType, Range};
+use qdrant_client::qdrant::{PointId, RetrievedPoint, ScrollPointsBuilder, UpsertPointsBuilder};
+use qdrant_client::qdrant::{Value, VectorParams, VectorsConfigBuilder};
+use qdrant_client::{Payload, Qdrant};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::OnceCell;
 
-const COLLECTION_VECTOR_SIZE: u64 = 768; // Default dimension for most embedding models
+const COLLECTION_PREFIX: &str = "rag_"; // Prefix of every collection created by this tool
+const TENANT_SEPARATOR: char = '-'; // Ends the tenant in a collection name; never in sanitized names
+const SCROLL_PAGE_SIZE: u32 = 100; // Points fetched per scroll request
+const KEYWORD_SCAN_FACTOR: u64 = 10; // Keyword candidates read per requested match
+const UPSERT_BATCH_SIZE: usize = 100; // Points sent per upsert request
+const MAX_PAYLOAD_BYTES: usize = 1024 * 1024; // Largest payload accepted for a single point
+const SCHEMA_SAMPLE_SIZE: u32 = 100; // Points examined when validating a collection's payloads
+const PROFILE_SAMPLE_SIZE: u32 = 100; // Points whose embedding profiles are compared before a search
+
+/// Payload keys written with every chunk by `store_chunks`
+const EXPECTED_PAYLOAD_KEYS: [&str; 5] = [
+    "text",
+    "original_text",
+    "document_id",
+    "start_position",
+    "chunk_index",
+];
+
+/// Payload key holding the Unix time (seconds) a chunk was stored at
+const INGESTED_AT_KEY: &str = "ingested_at";
+
+/// Payload key holding the (contextualized) chunk text, full-text indexed for keyword search
+const TEXT_KEY: &str = "text";
+
+/// Words too common to be worth matching by keyword
+const KEYWORD_STOPWORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "by", "can", "do", "does", "for", "from", "how",
+    "i", "in", "is", "it", "of", "on", "or", "that", "the", "this", "to", "was", "what", "when",
+    "where", "which", "who", "why", "with",
+];
+
+/// Name under which every document is stored when collections are shared
+pub const SHARED_COLLECTION: &str = "shared";
+
+/// Start of the key `named_collection` gives a named collection in place of a file name;
+/// document ids are file names or relative paths, which never start with it
+const NAMED_COLLECTION_KEY: char = '/';
+/// Start of the names of named collections after the tenant's collection prefix
+const NAMED_COLLECTION_PREFIX: &str = "c_";
+/// Payload key holding the name of the named collection a chunk is stored in
+const COLLECTION_KEY: &str = "collection";
+
+/// Payload key of the MIME type a document was split by, when it was given at ingest
+const MIME_TYPE_KEY: &str = "mime_type";
+
+/// Named vector holding the embedding of the contextualized chunk text
+pub const CONTEXTUAL_VECTOR: &str = "contextual";
+/// Named vector holding the embedding of the raw chunk text
+pub const RAW_VECTOR: &str = "raw";
 
 /// Configuration for Qdrant
 pub struct QdrantConfig {
     pub url: String,
     pub api_key: Option<String>,
+    /// Distance metric for new collections
+    pub distance: Distance,
+    /// Tenant whose collections this client works with; tenants never see each other's
+    /// collections, even for documents with the same name
+    pub tenant_id: Option<String>,
+    /// Scale stored chunk vectors and query vectors to unit length before they reach Qdrant
+    pub normalize_embeddings: bool,
 }
 
 impl QdrantConfig {
     /// Create a new configuration from environment variables
     pub fn from_env() -> Result<Self> {
-        let url = env::var("QDRANT_URL")?;
+        let url = env::var("QDRANT_URL").context("QDRANT_URL not set")?;
         let api_key = env::var("QDRANT_API_KEY").ok();
+        let tenant_id = env::var("QDRANT_TENANT").ok().filter(|id| !id.is_empty());
+        let distance = match env::var("QDRANT_DISTANCE") {
+            Ok(name) => parse_distance(&name)?,
+            Err(_) => Distance::Cosine,
+        };
+        let normalize_embeddings = match env::var("QDRANT_NORMALIZE_EMBEDDINGS") {
+            Ok(value) => value.trim().parse().with_context(|| {
+                format!(
+                    "Invalid QDRANT_NORMALIZE_EMBEDDINGS: {} (expected true or false)",
+                    value
+                )
+            })?,
+            Err(_) => false,
+        };
+
+        Ok(QdrantConfig {
+            url,
+            api_key,
+            distance,
+            tenant_id,
+            normalize_embeddings,
+        })
+    }
+}
+
+/// Parse a distance metric name as used in QDRANT_DISTANCE
+fn parse_distance(name: &str) -> Result<Distance> {
+    match name.trim().to_lowercase().as_str() {
+        "cosine" => Ok(Distance::Cosine),
+        "dot" => Ok(Distance::Dot),
+        "euclid" | "euclidean" => Ok(Distance::Euclid),
+        "manhattan" => Ok(Distance::Manhattan),
+        _ => Err(anyhow::anyhow!(
+            "Unsupported QDRANT_DISTANCE: {} (expected cosine, dot, euclid or manhattan)",
+            name
+        )),
+    }
+}
 
-        Ok(QdrantConfig { url, api_key })
+/// A retrieved chunk with its similarity score to the query
+#[derive(Debug, Clone)]
+pub struct ScoredChunk {
+    pub chunk: TextChunk,
+    /// Similarity of the chunk's embedding to the query, 0.0 for a chunk found by keyword alone
+    pub score: f32,
+    /// Share of the query's keywords found in the chunk, for a chunk found by keyword search
+    pub keyword_score: Option<f32>,
+    /// Position of the chunk within its document's chunks
+    pub chunk_index: usize,
+}
+
+/// A stored chunk with its embeddings, one JSON object per line of an export
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportRecord {
+    /// The (contextualized) chunk text
+    pub text: String,
+    pub document_id: String,
+    pub start_position: usize,
+    pub chunk_index: usize,
+    /// Embedding of the contextualized chunk text
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+    /// Embedding of the raw chunk text, in collections storing both
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_embedding: Option<Vec<f32>>,
+    /// Every other payload field, such as the original text and ingest metadata
+    #[serde(flatten)]
+    pub payload: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Retrieval settings stored with a document at ingest, used when a query does not set its own
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetrievalDefaults {
+    /// Number of chunks to retrieve
+    pub top_k: Option<u64>,
+    /// Lowest similarity score a chunk may have to be used
+    pub min_score: Option<f32>,
+}
+
+/// How many of a collection's sampled points carry each expected payload key
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaReport {
+    /// Number of points examined
+    pub sampled_points: usize,
+    /// Every expected payload key with the number of sampled points that have it
+    pub key_counts: Vec<(&'static str, usize)>,
+}
+
+impl SchemaReport {
+    /// Count the expected keys in a sample of point payloads
+    fn from_payloads<'a>(payloads: impl IntoIterator<Item = &'a HashMap<String, Value>>) -> Self {
+        let mut sampled_points = 0;
+        let mut key_counts: Vec<(&'static str, usize)> =
+            EXPECTED_PAYLOAD_KEYS.iter().map(|key| (*key, 0)).collect();
+
+        for payload in payloads {
+            sampled_points += 1;
+            for (key, count) in key_counts.iter_mut() {
+                if payload.contains_key(*key) {
+                    *count += 1;
+                }
+            }
+        }
+
+        Self {
+            sampled_points,
+            key_counts,
+        }
+    }
+
+    /// Expected keys missing from at least one sampled point
+    pub fn missing_keys(&self) -> Vec<&'static str> {
+        self.key_counts
+            .iter()
+            .filter(|(_, count)| *count < self.sampled_points)
+            .map(|(key, _)| *key)
+            .collect()
     }
 }
 
 /// Client for interacting with Qdrant
 pub struct QdrantClient {
     client: Qdrant,
+    distance: Distance,
+    /// Prefix of the collections of this client's tenant
+    collection_prefix: String,
+    /// Whether embeddings are scaled to unit length before they are stored or searched with
+    normalize_embeddings: bool,
+    /// Names of every collection, listed when a file's collection is first looked up
+    collection_names: OnceCell<HashSet<String>>,
+    /// Collection names already looked up, by the file name (or `SHARED_COLLECTION`) they are for
+    resolved_collections: Mutex<HashMap<String, String>>,
 }
 
 impl QdrantClient {
@@ -43,12 +236,119 @@ impl QdrantClient {
 
         let client = config_builder.build()?;
 
-        Ok(QdrantClient { client })
+        Ok(QdrantClient {
+            client,
+            distance: config.distance,
+            collection_prefix: collection_prefix(config.tenant_id.as_deref()),
+            normalize_embeddings: config.normalize_embeddings,
+            collection_names: OnceCell::new(),
+            resolved_collections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Distance metric used for new collections
+    pub fn distance(&self) -> Distance {
+        self.distance
+    }
+
+    /// Whether embeddings are scaled to unit length before they are stored or searched with
+    pub fn normalizes_embeddings(&self) -> bool {
+        self.normalize_embeddings
+    }
+
+    /// Vector values of an embedding as stored or searched with, normalized when configured
+    fn vector_values(&self, embedding: Embedding) -> Vec<f32> {
+        if self.normalize_embeddings {
+            embedding.normalized().values
+        } else {
+            embedding.values
+        }
+    }
+
+    /// Name of the collection holding a file in this client's tenant
+    /// Named collections have names of their own, so they never take a document's collection
+    /// A file indexed before names were hashed keeps its collection of that time
+    async fn collection_name(&self, file_name: &str) -> String {
+        if let Some(name) = named_collection_name(file_name) {
+            return self.named_collection_full_name(name);
+        }
+        let resolved = self
+            .resolved_collections
+            .lock()
+            .unwrap()
+            .get(file_name)
+            .cloned();
+        if let Some(resolved) = resolved {
+            return resolved;
+        }
+
+        let resolved = self.resolve_collection_name(file_name).await;
+        self.resolved_collections
+            .lock()
+            .unwrap()
+            .insert(file_name.to_string(), resolved.clone());
+        resolved
+    }
+
+    /// Name of the named collection `name` in this client's tenant
+    fn named_collection_full_name(&self, name: &str) -> String {
+        get_collection_name(
+            &format!("{}{}", self.collection_prefix, NAMED_COLLECTION_PREFIX),
+            name,
+        )
+    }
+
+    /// Hashed collection name of a file, or the name it was given before names were hashed
+    /// when only that collection exists and holds the file's chunks
+    /// Failures to look are logged, leaving the file with its hashed name
+    async fn resolve_collection_name(&self, file_name: &str) -> String {
+        let hashed_name = get_collection_name(&self.collection_prefix, file_name);
+        let legacy_name = legacy_collection_name(&self.collection_prefix, file_name);
+
+        let names = self
+            .collection_names
+            .get_or_init(|| async {
+                match self.client.list_collections().await {
+                    Ok(response) => response
+                        .collections
+                        .into_iter()
+                        .map(|collection| collection.name)
+                        .collect(),
+                    Err(e) => {
+                        warn!(
+                            "Failed to list collections, using hashed collection names only: {:#}",
+                            e
+                        );
+                        HashSet::new()
+                    }
+                }
+            })
+            .await;
+        if names.contains(&hashed_name) || !names.contains(&legacy_name) {
+            return hashed_name;
+        }
+        if file_name == SHARED_COLLECTION {
+            return legacy_name;
+        }
+
+        // The legacy name may be another document's hashed name, so its chunks must be this
+        // file's
+        match self.sample_payload(&legacy_name, "document_id").await {
+            Ok(Some(document_id)) if document_id == file_name => legacy_name,
+            Ok(_) => hashed_name,
+            Err(e) => {
+                warn!(
+                    "Failed to read collection {}, using {} for {}: {:#}",
+                    legacy_name, hashed_name, file_name, e
+                );
+                hashed_name
+            }
+        }
     }
 
     /// Check if a collection exists
     pub async fn collection_exists(&self, file_name: &str) -> Result<bool> {
-        let collection_name = get_collection_name(file_name);
+        let collection_name = self.collection_name(file_name).await;
 
         match self.client.collection_info(&collection_name).await {
             Ok(_) => Ok(true),
@@ -64,28 +364,238 @@ impl QdrantClient {
         }
     }
 
-    /// Create a new collection for a file
-    pub async fn create_collection(&self, file_name: &str) -> Result<()> {
-        let collection_name = get_collection_name(file_name);
+    /// List the documents that have a collection, by their original document id where possible
+    pub async fn list_collections(&self) -> Result<Vec<String>> {
+        let response = self
+            .client
+            .list_collections()
+            .await
+            .context("Failed to list collections")?;
+
+        let mut documents = Vec::new();
+        for collection in response.collections {
+            let Some(sanitized_name) = collection.name.strip_prefix(&self.collection_prefix) else {
+                continue;
+            };
+            // Without a tenant, the prefix also matches the collections of every tenant
+            if sanitized_name.contains(TENANT_SEPARATOR) {
+                continue;
+            }
+            // The shared collection holds many documents rather than being one
+            if collection.name == self.collection_name(SHARED_COLLECTION).await {
+                continue;
+            }
+
+            // Collection names are sanitized and hashed, so recover the document id from a
+            // stored chunk
+            let document_id = match self.sample_payload(&collection.name, "document_id").await? {
+                Some(document_id) => (self.collection_name(&document_id).await == collection.name)
+                    .then_some(document_id),
+                None => None,
+            };
 
-        let create_collection = CreateCollectionBuilder::new(collection_name.clone())
-            .vectors_config(VectorParams {
-                size: COLLECTION_VECTOR_SIZE,
-                distance: Distance::Cosine.into(),
-                ..Default::default()
-            });
+            match document_id {
+                Some(document_id) => documents.push(document_id),
+                // Named collections hold many documents too, and are listed by their name
+                None if sanitized_name.starts_with(NAMED_COLLECTION_PREFIX) => {}
+                None => warn!(
+                    "Skipping collection {}: it is empty or was named without a hash while its document has a hashed collection too; re-index its document",
+                    collection.name
+                ),
+            }
+        }
+
+        documents.sort();
+        Ok(documents)
+    }
+
+    /// List the named collections, by the name they were given, of this client's tenant
+    pub async fn list_named_collections(&self) -> Result<Vec<String>> {
+        let response = self
+            .client
+            .list_collections()
+            .await
+            .context("Failed to list collections")?;
+
+        let mut names = Vec::new();
+        for collection in response.collections {
+            if !matches_collection_prefix(
+                &collection.name,
+                &self.collection_prefix,
+                Some(NAMED_COLLECTION_PREFIX),
+                false,
+            ) {
+                continue;
+            }
+
+            // Like document ids, the name is recovered from a stored chunk; collections of
+            // documents whose name starts the same way hold none
+            let name = self
+                .sample_payload(&collection.name, COLLECTION_KEY)
+                .await?
+                .filter(|name| self.named_collection_full_name(name) == collection.name);
+            names.extend(name);
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// List the distinct document ids stored in a collection
+    pub async fn list_documents(&self, file_name: &str) -> Result<Vec<String>> {
+        let collection_name = self.collection_name(file_name).await;
+        let points = self.scroll_points(&collection_name, None, false).await?;
+
+        let mut documents: Vec<String> = points
+            .into_iter()
+            .filter_map(|point| {
+                point
+                    .payload
+                    .get("document_id")
+                    .and_then(|v| v.as_str())
+                    .cloned()
+            })
+            .collect();
+
+        documents.sort();
+        documents.dedup();
+        Ok(documents)
+    }
+
+    /// Read the string stored under a payload key in any point of a collection
+    async fn sample_payload(&self, collection_name: &str, key: &str) -> Result<Option<String>> {
+        let response = self
+            .client
+            .scroll(
+                ScrollPointsBuilder::new(collection_name)
+                    .limit(1)
+                    .with_payload(true)
+                    .with_vectors(false),
+            )
+            .await
+            .with_context(|| format!("Failed to scroll collection {}", collection_name))?;
+
+        Ok(response
+            .result
+            .into_iter()
+            .next()
+            .and_then(|point| point.payload.get(key).and_then(|v| v.as_str()).cloned()))
+    }
+
+    /// Create a new collection for a file holding vectors of `vector_size` dimensions
+    /// With `raw_vectors`, every point holds two named vectors: one of the contextualized text
+    /// and one of the raw chunk text
+    pub async fn create_collection(
+        &self,
+        file_name: &str,
+        vector_size: u64,
+        raw_vectors: bool,
+    ) -> Result<()> {
+        let collection_name = self.collection_name(file_name).await;
+
+        let vector_params = VectorParams {
+            size: vector_size,
+            distance: self.distance.into(),
+            ..Default::default()
+        };
+        let mut vectors_config = VectorsConfigBuilder::default();
+        if raw_vectors {
+            vectors_config
+                .add_named_vector_params(CONTEXTUAL_VECTOR, vector_params)
+                .add_named_vector_params(RAW_VECTOR, vector_params);
+        } else {
+            vectors_config.add_vector_params(vector_params);
+        }
+
+        let create_collection =
+            CreateCollectionBuilder::new(collection_name.clone()).vectors_config(vectors_config);
 
         self.client
             .create_collection(create_collection)
             .await
             .with_context(|| format!("Failed to create collection {}", collection_name))?;
 
+        // Range conditions on the ingest time need an integer index to be filtered efficiently
+        self.client
+            .create_field_index(
+                CreateFieldIndexCollectionBuilder::new(
+                    collection_name.clone(),
+                    INGESTED_AT_KEY,
+                    FieldType::Integer,
+                )
+                .wait(true),
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to index {} in collection {}",
+                    INGESTED_AT_KEY, collection_name
+                )
+            })?;
+
+        // Keyword search matches words of the chunk text through a full-text index
+        self.client
+            .create_field_index(
+                CreateFieldIndexCollectionBuilder::new(
+                    collection_name.clone(),
+                    TEXT_KEY,
+                    FieldType::Text,
+                )
+                .wait(true),
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to index {} in collection {}",
+                    TEXT_KEY, collection_name
+                )
+            })?;
+
         Ok(())
     }
 
+    /// Check if a collection stores raw chunk vectors next to the contextual ones
+    pub async fn has_raw_vectors(&self, file_name: &str) -> Result<bool> {
+        let vectors_config = self.vectors_config(file_name).await?;
+
+        Ok(
+            matches!(vectors_config, Some(Config::ParamsMap(params)) if params.map.contains_key(RAW_VECTOR)),
+        )
+    }
+
+    /// Number of dimensions of the (contextual) vectors a collection holds
+    pub async fn vector_size(&self, file_name: &str) -> Result<Option<u64>> {
+        let vectors_config = self.vectors_config(file_name).await?;
+
+        Ok(match vectors_config {
+            Some(Config::Params(params)) => Some(params.size),
+            Some(Config::ParamsMap(params)) => {
+                params.map.get(CONTEXTUAL_VECTOR).map(|params| params.size)
+            }
+            None => None,
+        })
+    }
+
+    /// Read the vector settings of a collection
+    async fn vectors_config(&self, file_name: &str) -> Result<Option<Config>> {
+        let collection_name = self.collection_name(file_name).await;
+        let info = self
+            .client
+            .collection_info(&collection_name)
+            .await
+            .with_context(|| format!("Failed to read collection {}", collection_name))?;
+
+        Ok(info
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.vectors_config)
+            .and_then(|vectors_config| vectors_config.config))
+    }
+
     /// Delete a collection
     pub async fn delete_collection(&self, file_name: &str) -> Result<()> {
-        let collection_name = get_collection_name(file_name);
+        let collection_name = self.collection_name(file_name).await;
 
         self.client
             .delete_collection(collection_name.clone())
@@ -95,118 +605,1871 @@ impl QdrantClient {
         Ok(())
     }
 
-    /// Store chunks in the collection
-    pub async fn store_chunks(
+    /// Copy every point of the `source` collection into `dest` with its vectors and payload,
+    /// so documents become searchable together without embedding them again
+    /// `dest` is the shared collection or a named one, which tell documents apart by their
+    /// document id. It is created with the vector settings of `source` when missing, and must
+    /// have the same ones otherwise. Copied points get ids derived from their document id and
+    /// chunk index, as in the shared collection, so chunks of different documents never collide
+    /// Points are copied a scroll page at a time; returns the number of points copied
+    pub async fn merge_collections(&self, source: &str, dest: &str) -> Result<usize> {
+        let source_name = self.collection_name(source).await;
+        let dest_name = self.collection_name(dest).await;
+        if source_name == dest_name {
+            return Err(anyhow::anyhow!("Cannot merge {} into itself", source_name));
+        }
+        if dest != SHARED_COLLECTION && named_collection_name(dest).is_none() {
+            return Err(anyhow::anyhow!(
+                "Cannot merge into the collection of document {}; merge into the shared collection or a named one",
+                dest
+            ));
+        }
+
+        let Some(vector_size) = self.vector_size(source).await? else {
+            return Err(anyhow::anyhow!(
+                "Collection {} has no vectors to merge",
+                source_name
+            ));
+        };
+        let raw_vectors = self.has_raw_vectors(source).await?;
+
+        if !self.collection_exists(dest).await? {
+            self.create_collection(dest, vector_size, raw_vectors)
+                .await?;
+        } else if self.vector_size(dest).await? != Some(vector_size)
+            || self.has_raw_vectors(dest).await? != raw_vectors
+        {
+            return Err(anyhow::anyhow!(
+                "Collection {} holds vectors of other settings than {} ({}-dimensional{})",
+                dest_name,
+                source_name,
+                vector_size,
+                if raw_vectors {
+                    ", with raw vectors"
+                } else {
+                    ""
+                }
+            ));
+        }
+
+        let mut copied = 0;
+        let mut offset = None;
+        loop {
+            let (page, next_offset) = self.scroll_page(&source_name, None, true, offset).await?;
+            let points = page
+                .into_iter()
+                .map(|point| merged_point(point, named_collection_name(dest)))
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Failed to merge {}", source_name))?;
+            copied += points.len();
+            self.upsert_in_batches(&dest_name, points).await?;
+
+            match next_offset {
+                Some(next_offset) => offset = Some(next_offset),
+                None => break,
+            }
+        }
+
+        Ok(copied)
+    }
+
+    /// Upsert points in batches so a single request stays within Qdrant's request size limit
+    async fn upsert_in_batches(
         &self,
-        chunks: Vec<TextChunk>,
-        embeddings: Vec<Embedding>,
-        file_name: &str,
+        collection_name: &str,
+        mut points: Vec<PointStruct>,
     ) -> Result<()> {
-        let collection_name = get_collection_name(file_name);
+        while !points.is_empty() {
+            let batch: Vec<PointStruct> = points
+                .drain(..points.len().min(UPSERT_BATCH_SIZE))
+                .collect();
 
-        // Convert chunks and embeddings to points
-        let points: Vec<PointStruct> = chunks
+            self.client
+                .upsert_points(UpsertPointsBuilder::new(collection_name, batch).wait(true))
+                .await
+                .with_context(|| {
+                    format!("Failed to upsert points in collection {}", collection_name)
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Names of this client's tenant's (or of no tenant's) collections whose name continues
+    /// with `prefix` after the tenant's collection prefix, or all of them without a prefix
+    /// With `absolute`, `prefix` is matched against whole collection names of any tenant
+    pub async fn collections_with_prefix(
+        &self,
+        prefix: Option<&str>,
+        absolute: bool,
+    ) -> Result<Vec<String>> {
+        if prefix.is_some_and(str::is_empty) {
+            return Err(anyhow::anyhow!(
+                "Collection prefix must not be empty, it would match every collection"
+            ));
+        }
+
+        let response = self
+            .client
+            .list_collections()
+            .await
+            .context("Failed to list collections")?;
+
+        let mut names: Vec<String> = response
+            .collections
             .into_iter()
-            .zip(embeddings.into_iter())
-            .enumerate()
-            .map(|(idx, (chunk, embedding))| {
-                let payload: HashMap<String, Value> = serde_json::from_value(json!({
-                    "text": chunk.text,
-                    "document_id": chunk.document_id,
-                    "start_position": chunk.start_position,
-                    "chunk_index": idx,
-                }))
-                .unwrap();
-
-                PointStruct::new(idx as u64, embedding.values, payload)
+            .map(|collection| collection.name)
+            .filter(|name| {
+                matches_collection_prefix(name, &self.collection_prefix, prefix, absolute)
             })
             .collect();
+        names.sort();
 
-        // Instead of directly passing the collection name, use the builder
-        let upsert_request = UpsertPointsBuilder::new(collection_name.clone(), points).build();
+        Ok(names)
+    }
+
+    /// Delete exactly the named collections, such as those `collections_with_prefix` listed,
+    /// returning how many were deleted
+    pub async fn delete_collections(&self, names: &[String]) -> Result<usize> {
+        for name in names {
+            self.client
+                .delete_collection(name.clone())
+                .await
+                .with_context(|| format!("Failed to delete collection {}", name))?;
+        }
+
+        Ok(names.len())
+    }
+
+    /// Delete the points of one document from a collection
+    pub async fn delete_document(&self, file_name: &str, document_id: &str) -> Result<()> {
+        let collection_name = self.collection_name(file_name).await;
 
-        // Upsert points in batch
         self.client
-            .upsert_points(upsert_request)
+            .delete_points(
+                DeletePointsBuilder::new(collection_name.clone())
+                    .points(document_filter(document_id))
+                    .wait(true),
+            )
             .await
             .with_context(|| {
-                format!("Failed to upsert points in collection {}", collection_name)
+                format!(
+                    "Failed to delete {} from collection {}",
+                    document_id, collection_name
+                )
             })?;
 
         Ok(())
     }
 
-    /// Search for relevant chunks
-    pub async fn search(
+    /// Flag every chunk of a document as fully ingested, storing its retrieval defaults, the
+    /// model and task type it was embedded with, and the MIME type it was split by when known
+    pub async fn mark_ingest_complete(
         &self,
-        query_embedding: Embedding,
         file_name: &str,
-        limit: u64,
-    ) -> Result<Vec<TextChunk>> {
-        use qdrant_client::qdrant::{with_payload_selector, SearchPoints, WithPayloadSelector};
+        document_id: &str,
+        defaults: RetrievalDefaults,
+        profile: &EmbeddingProfile,
+        mime_type: Option<&str>,
+    ) -> Result<()> {
+        let collection_name = self.collection_name(file_name).await;
+        let mut payload_json = json!({
+            "ingest_complete": true,
+            "default_top_k": defaults.top_k,
+            "default_min_score": defaults.min_score,
+            "embedding_model": profile.model,
+            "embedding_task_type": profile.task_type.map(|task_type| task_type.as_str()),
+        });
+        if let Some(mime_type) = mime_type {
+            payload_json[MIME_TYPE_KEY] = json!(mime_type);
+        }
+        let payload = Payload::try_from(payload_json)?;
 
-        let collection_name = get_collection_name(file_name);
+        self.client
+            .set_payload(
+                SetPayloadPointsBuilder::new(collection_name.clone(), payload)
+                    .points_selector(document_filter(document_id))
+                    .wait(true),
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to mark {} as ingested in collection {}",
+                    document_id, collection_name
+                )
+            })?;
 
-        // Create search request
-        let search_request = SearchPoints {
-            collection_name: collection_name.clone(),
-            vector: query_embedding.values,
-            limit,
-            with_payload: Some(WithPayloadSelector {
-                selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-            }),
-            ..Default::default()
-        };
+        Ok(())
+    }
 
-        // Execute search
-        let search_response = self
+    /// Check which expected payload keys the points of a collection carry, over a sample of
+    /// its points; collections created by older versions may lack some of them
+    pub async fn validate_collection(&self, file_name: &str) -> Result<SchemaReport> {
+        let collection_name = self.collection_name(file_name).await;
+        let response = self
             .client
-            .search_points(search_request)
+            .scroll(
+                ScrollPointsBuilder::new(collection_name.clone())
+                    .limit(SCHEMA_SAMPLE_SIZE)
+                    .with_payload(true)
+                    .with_vectors(false),
+            )
             .await
-            .with_context(|| format!("Failed to search collection {}", collection_name))?;
+            .with_context(|| format!("Failed to scroll collection {}", collection_name))?;
 
-        // Convert search results back to TextChunks
-        let chunks = search_response
+        Ok(SchemaReport::from_payloads(
+            response.result.iter().map(|point| &point.payload),
+        ))
+    }
+
+    /// Backfill the payload fields that can be derived for points stored without them:
+    /// `document_id` from the collection's document and `chunk_index` from the point id
+    /// Returns the number of points updated
+    pub async fn repair_collection(&self, file_name: &str) -> Result<usize> {
+        use qdrant_client::qdrant::point_id::PointIdOptions;
+
+        let collection_name = self.collection_name(file_name).await;
+        // Shared and named collection points have hashed ids and belong to many documents
+        if collection_name == self.collection_name(SHARED_COLLECTION).await {
+            return Err(anyhow::anyhow!(
+                "Missing fields of the shared collection cannot be derived, re-ingest its documents"
+            ));
+        }
+        if let Some(name) = named_collection_name(file_name) {
+            return Err(anyhow::anyhow!(
+                "Missing fields of named collection {} cannot be derived, re-ingest its documents",
+                name
+            ));
+        }
+
+        let points = self.scroll_points(&collection_name, None, false).await?;
+        let mut repaired = 0;
+        for point in points {
+            let Some(point_id) = point.id else {
+                continue;
+            };
+
+            let mut backfill = serde_json::Map::new();
+            if !point.payload.contains_key("document_id") {
+                backfill.insert("document_id".to_string(), json!(file_name));
+            }
+            if !point.payload.contains_key("chunk_index") {
+                // Points of a document's own collection are numbered by chunk index
+                if let Some(PointIdOptions::Num(chunk_index)) = &point_id.point_id_options {
+                    backfill.insert("chunk_index".to_string(), json!(chunk_index));
+                }
+            }
+            if backfill.is_empty() {
+                continue;
+            }
+
+            let payload = Payload::from(backfill);
+            self.client
+                .set_payload(
+                    SetPayloadPointsBuilder::new(collection_name.clone(), payload)
+                        .points_selector(vec![point_id])
+                        .wait(true),
+                )
+                .await
+                .with_context(|| {
+                    format!("Failed to repair points of collection {}", collection_name)
+                })?;
+            repaired += 1;
+        }
+
+        Ok(repaired)
+    }
+
+    /// Read the MIME type a document was split by, stored when it was given rather than
+    /// guessed from the file extension
+    pub async fn document_mime_type(
+        &self,
+        file_name: &str,
+        document_id: &str,
+    ) -> Result<Option<String>> {
+        let collection_name = self.collection_name(file_name).await;
+
+        let response = self
+            .client
+            .scroll(
+                ScrollPointsBuilder::new(collection_name.clone())
+                    .filter(document_filter(document_id))
+                    .limit(1)
+                    .with_payload(true)
+                    .with_vectors(false),
+            )
+            .await
+            .with_context(|| format!("Failed to scroll collection {}", collection_name))?;
+
+        Ok(response
             .result
             .into_iter()
-            .filter_map(|scored_point| {
-                let payload = scored_point.payload;
-                let text = payload.get("text")?.as_str()?;
-                // Get document_id from payload or fallback to file_name
-                let document_id = payload
-                    .get("document_id")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or(&file_name.to_string())
-                    .to_string();
+            .next()
+            .and_then(|point| point.payload.get(MIME_TYPE_KEY)?.as_str().cloned()))
+    }
 
-                // Get start position or default to 0
-                let start_position = payload
-                    .get("start_position")
-                    .and_then(|v| v.as_integer())
-                    .map(|v| v as usize)
-                    .unwrap_or(0);
+    /// Read the retrieval defaults stored with a document
+    pub async fn retrieval_defaults(
+        &self,
+        file_name: &str,
+        document_id: &str,
+    ) -> Result<RetrievalDefaults> {
+        let collection_name = self.collection_name(file_name).await;
 
-                Some(TextChunk {
-                    text: text.to_string(),
-                    token_count: text.split_whitespace().count(), // Estimate token count
-                    document_id,
-                    start_position,
-                })
-            })
-            .collect();
+        let response = self
+            .client
+            .scroll(
+                ScrollPointsBuilder::new(collection_name.clone())
+                    .filter(document_filter(document_id))
+                    .limit(1)
+                    .with_payload(true)
+                    .with_vectors(false),
+            )
+            .await
+            .with_context(|| format!("Failed to scroll collection {}", collection_name))?;
 
-        Ok(chunks)
+        let Some(point) = response.result.into_iter().next() else {
+            return Ok(RetrievalDefaults::default());
+        };
+
+        Ok(RetrievalDefaults {
+            top_k: point
+                .payload
+                .get("default_top_k")
+                .and_then(|v| v.as_integer())
+                .map(|v| v as u64),
+            min_score: point
+                .payload
+                .get("default_min_score")
+                .and_then(|v| v.as_double())
+                .map(|v| v as f32),
+        })
     }
-}
 
-/// Generate a collection name from a file name
-fn get_collection_name(file_name: &str) -> String {
-    // Replace non-alphanumeric characters with underscores and convert to lowercase
-    let name = file_name
-        .chars()
-        .map(|c| if c.is_alphanumeric() { c } else { '_' })
-        .collect::<String>()
-        .to_lowercase();
+    /// Model and task type the chunks of a collection (or of one document in it) were embedded
+    /// with, or None for chunks ingested before they were stored
+    /// A shared or named collection may hold documents embedded with different profiles; a
+    /// sample of its points is compared and a warning logged when they disagree.
+    pub async fn embedding_profile(
+        &self,
+        file_name: &str,
+        document_id: Option<&str>,
+    ) -> Result<Option<EmbeddingProfile>> {
+        let collection_name = self.collection_name(file_name).await;
+
+        // The chunks of one document are embedded alike, so one of them is enough
+        let request = ScrollPointsBuilder::new(collection_name.clone())
+            .with_payload(true)
+            .with_vectors(false);
+        let request = match document_id {
+            Some(document_id) => request.limit(1).filter(document_filter(document_id)),
+            None => request.limit(PROFILE_SAMPLE_SIZE),
+        };
+
+        let response = self
+            .client
+            .scroll(request)
+            .await
+            .with_context(|| format!("Failed to scroll collection {}", collection_name))?;
+
+        let profiles =
+            distinct_embedding_profiles(response.result.iter().map(|point| &point.payload));
+        if profiles.len() > 1 {
+            let models: Vec<&str> = profiles
+                .iter()
+                .map(|profile| profile.model.as_str())
+                .collect();
+            warn!(
+                "Chunks in collection {} were embedded with {} different models or task types \
+                 ({}); questions are embedded to match the first, so chunks of the others may \
+                 be missed. Re-ingest them to embed every document alike.",
+                collection_name,
+                profiles.len(),
+                models.join(", ")
+            );
+        }
+        Ok(profiles.into_iter().next())
+    }
+
+    /// Check whether a document's ingest ran to completion
+    pub async fn is_ingest_complete(&self, file_name: &str, document_id: &str) -> Result<bool> {
+        let collection_name = self.collection_name(file_name).await;
+        let filter = Filter::must([
+            Condition::matches("document_id", document_id.to_string()),
+            Condition::matches("ingest_complete", true),
+        ]);
+
+        let response = self
+            .client
+            .count(
+                CountPointsBuilder::new(collection_name.clone())
+                    .filter(filter)
+                    .exact(true),
+            )
+            .await
+            .with_context(|| format!("Failed to count points in collection {}", collection_name))?;
+
+        Ok(response.result.is_some_and(|result| result.count > 0))
+    }
+
+    /// Count the points stored in a collection, optionally only those of one document
+    pub async fn count_points(&self, file_name: &str, document_id: Option<&str>) -> Result<u64> {
+        let collection_name = self.collection_name(file_name).await;
+
+        let mut request = CountPointsBuilder::new(collection_name.clone()).exact(true);
+        if let Some(document_id) = document_id {
+            request = request.filter(document_filter(document_id));
+        }
+
+        let response =
+            self.client.count(request).await.with_context(|| {
+                format!("Failed to count points in collection {}", collection_name)
+            })?;
+
+        Ok(response.result.map(|result| result.count).unwrap_or(0))
+    }
 
-    format!("rag_{}", name)
+    /// Store contextualized chunks in the collection, numbering them from `first_index`
+    /// The original chunk text is kept alongside so the context can be regenerated later
+    /// In a collection shared by several documents, point ids are derived from the document id
+    /// `raw_embeddings` (of the original chunk texts) are required by collections created with
+    /// raw vectors and must be left out otherwise
+    pub async fn store_chunks(
+        &self,
+        chunks: Vec<ContextualizedChunk>,
+        embeddings: Vec<Embedding>,
+        raw_embeddings: Option<Vec<Embedding>>,
+        file_name: &str,
+        first_index: u64,
+    ) -> Result<()> {
+        let collection_name = self.collection_name(file_name).await;
+        let mut raw_embeddings = raw_embeddings.map(Vec::into_iter);
+        let ingested_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        // Convert chunks and embeddings to points
+        let mut points = Vec::with_capacity(chunks.len());
+        for (offset, (chunk, embedding)) in chunks.into_iter().zip(embeddings).enumerate() {
+            let idx = first_index + offset as u64;
+            let mut payload_json = chunk_payload(&chunk, idx, ingested_at);
+            if let Some(name) = named_collection_name(file_name) {
+                payload_json[COLLECTION_KEY] = json!(name);
+            }
+
+            // Oversized payloads otherwise fail deep inside gRPC with a generic error
+            let payload_size = payload_json.to_string().len();
+            if payload_size > MAX_PAYLOAD_BYTES {
+                return Err(anyhow::anyhow!(
+                    "Chunk {} of {} (position {}) has a {} byte payload, above the {} byte limit; use smaller chunks",
+                    idx,
+                    chunk.original_chunk.document_id,
+                    chunk.original_chunk.start_position,
+                    payload_size,
+                    MAX_PAYLOAD_BYTES
+                ));
+            }
+
+            let payload: HashMap<String, Value> = serde_json::from_value(payload_json)?;
+            let point_id = if self
+                .collection_name(&chunk.original_chunk.document_id)
+                .await
+                == collection_name
+            {
+                idx
+            } else {
+                shared_point_id(&chunk.original_chunk.document_id, idx)
+            };
+            match raw_embeddings.as_mut() {
+                Some(raw_embeddings) => {
+                    let raw_embedding = raw_embeddings.next().with_context(|| {
+                        format!(
+                            "Missing raw embedding for chunk {} of {}",
+                            idx, collection_name
+                        )
+                    })?;
+                    let vectors = HashMap::from([
+                        (CONTEXTUAL_VECTOR.to_string(), self.vector_values(embedding)),
+                        (RAW_VECTOR.to_string(), self.vector_values(raw_embedding)),
+                    ]);
+                    points.push(PointStruct::new(point_id, vectors, payload));
+                }
+                None => points.push(PointStruct::new(
+                    point_id,
+                    self.vector_values(embedding),
+                    payload,
+                )),
+            }
+        }
+
+        self.upsert_in_batches(&collection_name, points).await
+    }
+
+    /// Search for relevant chunks
+    pub async fn search(
+        &self,
+        query_embedding: Embedding,
+        file_name: &str,
+        limit: u64,
+    ) -> Result<Vec<TextChunk>> {
+        let scored_chunks = self
+            .search_scored(query_embedding, file_name, limit, None, None, None)
+            .await?;
+
+        Ok(scored_chunks
+            .into_iter()
+            .map(|scored_chunk| scored_chunk.chunk)
+            .collect())
+    }
+
+    /// Search for relevant chunks along with their similarity scores
+    /// With `document_id` set, only chunks of that document are considered, and with
+    /// `min_score` set, only chunks scoring at least that much
+    /// `vector_name` selects the named vector searched in collections holding several
+    pub async fn search_scored(
+        &self,
+        query_embedding: Embedding,
+        file_name: &str,
+        limit: u64,
+        document_id: Option<&str>,
+        min_score: Option<f32>,
+        vector_name: Option<&str>,
+    ) -> Result<Vec<ScoredChunk>> {
+        let filter = SearchFilter {
+            document_id,
+            ..SearchFilter::default()
+        };
+        self.search_filtered(
+            query_embedding,
+            file_name,
+            limit,
+            filter,
+            min_score,
+            vector_name,
+        )
+        .await
+    }
+
+    /// Search for relevant chunks matching all conditions of `filter`, along with their
+    /// similarity scores
+    pub async fn search_filtered(
+        &self,
+        query_embedding: Embedding,
+        file_name: &str,
+        limit: u64,
+        filter: SearchFilter<'_>,
+        min_score: Option<f32>,
+        vector_name: Option<&str>,
+    ) -> Result<Vec<ScoredChunk>> {
+        use qdrant_client::qdrant::{with_payload_selector, SearchPoints, WithPayloadSelector};
+
+        let collection_name = self.collection_name(file_name).await;
+
+        // Create search request
+        let search_request = SearchPoints {
+            collection_name: collection_name.clone(),
+            vector: self.vector_values(query_embedding),
+            limit,
+            with_payload: Some(WithPayloadSelector {
+                selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+            }),
+            filter: filter.to_filter(),
+            score_threshold: min_score,
+            vector_name: vector_name.map(str::to_string),
+            ..Default::default()
+        };
+
+        // Execute search
+        let search_response = self
+            .client
+            .search_points(search_request)
+            .await
+            .with_context(|| format!("Failed to search collection {}", collection_name))?;
+
+        // Convert search results back to TextChunks
+        let chunks = search_response
+            .result
+            .into_iter()
+            .filter_map(|scored_point| {
+                let (chunk_index, chunk) = payload_chunk(&scored_point.payload, file_name)?;
+                Some(ScoredChunk {
+                    chunk,
+                    score: scored_point.score,
+                    keyword_score: None,
+                    chunk_index,
+                })
+            })
+            .collect();
+
+        Ok(chunks)
+    }
+
+    /// Search for chunks containing the words of a query, such as error codes or API names
+    /// that embed poorly, matching all conditions of `filter`
+    /// Each chunk scores the fraction of the query's terms it contains as whole words; chunks
+    /// containing every term are looked for first, and at most `KEYWORD_SCAN_FACTOR` times
+    /// `limit` candidates are read per search. Collections created without a text index
+    /// still match, only more slowly
+    pub async fn search_keyword(
+        &self,
+        query: &str,
+        file_name: &str,
+        limit: u64,
+        filter: SearchFilter<'_>,
+    ) -> Result<Vec<ScoredChunk>> {
+        let terms = keyword_terms(query);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let collection_name = self.collection_name(file_name).await;
+        let max_candidates = limit.saturating_mul(KEYWORD_SCAN_FACTOR);
+        let term_conditions: Vec<Condition> = terms
+            .iter()
+            .map(|term| Condition::matches_text(TEXT_KEY, term.clone()))
+            .collect();
+        let filter = filter.to_filter().unwrap_or_default();
+
+        let mut all_terms = filter.clone();
+        all_terms.must.extend(term_conditions.clone());
+        let mut chunks = self
+            .scroll_candidates(&collection_name, all_terms, file_name, max_candidates)
+            .await?;
+
+        // Chunks containing only some of the terms make up for too few containing all of them
+        if terms.len() > 1
+            && rank_keyword_matches(chunks.clone(), &terms, limit).len() < limit as usize
+        {
+            let mut any_term = filter;
+            any_term.should = term_conditions;
+            let candidates = self
+                .scroll_candidates(&collection_name, any_term, file_name, max_candidates)
+                .await?;
+            for (chunk_index, chunk) in candidates {
+                let found = chunks.iter().any(|(found_index, found)| {
+                    *found_index == chunk_index && found.document_id == chunk.document_id
+                });
+                if !found {
+                    chunks.push((chunk_index, chunk));
+                }
+            }
+        }
+
+        Ok(rank_keyword_matches(chunks, &terms, limit))
+    }
+
+    /// Read the chunks of up to `max_points` points matching `filter`, stopping the scroll
+    /// once that many were read
+    async fn scroll_candidates(
+        &self,
+        collection_name: &str,
+        filter: Filter,
+        file_name: &str,
+        max_points: u64,
+    ) -> Result<Vec<(usize, TextChunk)>> {
+        let mut chunks = Vec::new();
+        let mut read: u64 = 0;
+        self.scroll_pages(collection_name, Some(filter), false, |page| {
+            for point in page {
+                if read == max_points {
+                    return Ok(ControlFlow::Break(()));
+                }
+                read += 1;
+                chunks.extend(payload_chunk(&point.payload, file_name));
+            }
+            Ok(ControlFlow::Continue(()))
+        })
+        .await?;
+
+        Ok(chunks)
+    }
+
+    /// Read back every stored (contextualized) chunk of a collection, page by page, in
+    /// document and chunk order
+    pub async fn scroll_all(&self, file_name: &str) -> Result<Vec<TextChunk>> {
+        let collection_name = self.collection_name(file_name).await;
+        let points = self.scroll_points(&collection_name, None, false).await?;
+
+        Ok(ordered_chunks(
+            points.iter().map(|point| &point.payload),
+            file_name,
+        ))
+    }
+
+    /// Store exported chunks in a collection with their embeddings as they are, creating the
+    /// collection for their vectors when missing; returns the number of chunks stored
+    /// All embeddings must have the same length, checked before anything is created
+    pub async fn import_records(
+        &self,
+        file_name: &str,
+        records: Vec<ExportRecord>,
+    ) -> Result<usize> {
+        let collection_name = self.collection_name(file_name).await;
+        let (vector_size, raw_vectors) = record_dimensions(&records)?;
+
+        if !self.collection_exists(file_name).await? {
+            self.create_collection(file_name, vector_size as u64, raw_vectors)
+                .await?;
+        } else if self.vector_size(file_name).await? != Some(vector_size as u64)
+            || self.has_raw_vectors(file_name).await? != raw_vectors
+        {
+            return Err(anyhow::anyhow!(
+                "Collection {} holds vectors of other settings than the imported {}-dimensional ones{}",
+                collection_name,
+                vector_size,
+                if raw_vectors { " with raw vectors" } else { "" }
+            ));
+        }
+
+        let mut points = Vec::with_capacity(records.len());
+        for record in records {
+            let point_id = if self.collection_name(&record.document_id).await == collection_name {
+                record.chunk_index as u64
+            } else {
+                shared_point_id(&record.document_id, record.chunk_index as u64)
+            };
+            let mut payload = import_payload(&record)?;
+            if let Some(name) = named_collection_name(file_name) {
+                payload.insert(COLLECTION_KEY.to_string(), name.into());
+            }
+            match record.raw_embedding {
+                Some(raw_embedding) => {
+                    let vectors = HashMap::from([
+                        (CONTEXTUAL_VECTOR.to_string(), record.embedding),
+                        (RAW_VECTOR.to_string(), raw_embedding),
+                    ]);
+                    points.push(PointStruct::new(point_id, vectors, payload));
+                }
+                None => points.push(PointStruct::new(point_id, record.embedding, payload)),
+            }
+        }
+        let imported = points.len();
+        self.upsert_in_batches(&collection_name, points).await?;
+
+        Ok(imported)
+    }
+
+    /// Write every stored chunk of a collection with its embeddings to `writer` as JSON lines,
+    /// a scroll page at a time, returning the number of chunks written
+    /// With `document_id` set, only chunks of that document are written
+    pub async fn export_jsonl(
+        &self,
+        file_name: &str,
+        document_id: Option<&str>,
+        writer: &mut impl Write,
+    ) -> Result<usize> {
+        let collection_name = self.collection_name(file_name).await;
+        let mut exported = 0;
+
+        self.scroll_pages(
+            &collection_name,
+            document_id.map(document_filter),
+            true,
+            |points| {
+                for point in points {
+                    let record = export_record(point.payload, point.vectors)
+                        .with_context(|| format!("Failed to export {}", collection_name))?;
+                    serde_json::to_writer(&mut *writer, &record)?;
+                    writer.write_all(b"\n")?;
+                    exported += 1;
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )
+        .await?;
+
+        Ok(exported)
+    }
+
+    /// Read back the original (pre-context) chunks of a collection in chunk order
+    /// With `document_id` set, only chunks of that document are read
+    pub async fn scroll_original_chunks(
+        &self,
+        file_name: &str,
+        document_id: Option<&str>,
+    ) -> Result<Vec<TextChunk>> {
+        let collection_name = self.collection_name(file_name).await;
+        let points = self
+            .scroll_points(&collection_name, document_id.map(document_filter), false)
+            .await?;
+
+        let mut indexed_chunks = Vec::with_capacity(points.len());
+        for point in points {
+            let payload = point.payload;
+            let text = payload
+                .get("original_text")
+                .and_then(|v| v.as_str())
+                .with_context(|| {
+                    format!(
+                        "Collection {} was stored without original chunk text, re-ingest the document",
+                        collection_name
+                    )
+                })?;
+
+            let chunk_index = payload
+                .get("chunk_index")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(0);
+            let document_id = payload
+                .get("document_id")
+                .and_then(|v| v.as_str())
+                .cloned()
+                .unwrap_or_else(|| file_name.to_string());
+            let start_position = payload
+                .get("start_position")
+                .and_then(|v| v.as_integer())
+                .map(|v| v as usize)
+                .unwrap_or(0);
+
+            indexed_chunks.push((
+                chunk_index,
+                TextChunk {
+                    text: text.to_string(),
+                    token_count: payload_token_count(&payload, "original_token_count")
+                        .unwrap_or_else(|| crate::chunking::estimate_token_count(text)),
+                    document_id,
+                    start_position,
+                    breadcrumb: payload_breadcrumb(&payload),
+                    row: payload_row(&payload),
+                    page: payload_page(&payload),
+                },
+            ));
+        }
+
+        indexed_chunks.sort_by_key(|(chunk_index, _)| *chunk_index);
+
+        Ok(indexed_chunks.into_iter().map(|(_, chunk)| chunk).collect())
+    }
+
+    /// Read every point of a collection matching an optional filter with its payload, page by page
+    /// With `with_vectors`, the points' vectors are read as well
+    async fn scroll_points(
+        &self,
+        collection_name: &str,
+        filter: Option<Filter>,
+        with_vectors: bool,
+    ) -> Result<Vec<RetrievedPoint>> {
+        let mut points = Vec::new();
+        self.scroll_pages(collection_name, filter, with_vectors, |page| {
+            points.extend(page);
+            Ok(ControlFlow::Continue(()))
+        })
+        .await?;
+
+        Ok(points)
+    }
+
+    /// Hand every point of a collection matching an optional filter to `visit`, a page at a
+    /// time, so large collections need not be held in memory at once
+    /// The scroll stops early once `visit` breaks
+    async fn scroll_pages(
+        &self,
+        collection_name: &str,
+        filter: Option<Filter>,
+        with_vectors: bool,
+        mut visit: impl FnMut(Vec<RetrievedPoint>) -> Result<ControlFlow<()>>,
+    ) -> Result<()> {
+        let mut offset: Option<PointId> = None;
+
+        loop {
+            let (page, next_offset) = self
+                .scroll_page(collection_name, filter.clone(), with_vectors, offset)
+                .await?;
+
+            if visit(page)?.is_break() {
+                break;
+            }
+
+            match next_offset {
+                Some(next_offset) => offset = Some(next_offset),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read one page of the points of a collection matching an optional filter, starting at
+    /// `offset`, with the offset of the next page unless it was the last
+    async fn scroll_page(
+        &self,
+        collection_name: &str,
+        filter: Option<Filter>,
+        with_vectors: bool,
+        offset: Option<PointId>,
+    ) -> Result<(Vec<RetrievedPoint>, Option<PointId>)> {
+        let mut request = ScrollPointsBuilder::new(collection_name)
+            .limit(SCROLL_PAGE_SIZE)
+            .with_payload(true)
+            .with_vectors(with_vectors);
+        if let Some(offset) = offset {
+            request = request.offset(offset);
+        }
+        if let Some(filter) = filter {
+            request = request.filter(filter);
+        }
+
+        let response = self
+            .client
+            .scroll(request)
+            .await
+            .with_context(|| format!("Failed to scroll collection {}", collection_name))?;
+
+        Ok((response.result, response.next_page_offset))
+    }
+}
+
+/// Generate a collection name from a file name, after the tenant's collection prefix
+/// The sanitized name stays readable, and a hash of the exact file name keeps names such as
+/// `notes (1).txt` and `notes-1.txt` from sharing a collection
+fn get_collection_name(prefix: &str, file_name: &str) -> String {
+    format!(
+        "{}{}_{:08x}",
+        prefix,
+        sanitize_name(file_name),
+        fnv1a_hash(file_name.bytes()) as u32
+    )
+}
+
+/// Key addressing the named collection `name` wherever a file name selects a collection,
+/// kept apart from the collections of documents, such as one with the same name
+pub fn named_collection(name: &str) -> String {
+    format!("{}{}", NAMED_COLLECTION_KEY, name)
+}
+
+/// Name of the named collection a key addresses, None for a file name
+fn named_collection_name(file_name: &str) -> Option<&str> {
+    file_name.strip_prefix(NAMED_COLLECTION_KEY)
+}
+
+/// Whether a collection is one of a tenant's (or no tenant's) named by `collection_prefix`
+/// continued by `prefix`, or with `absolute` simply starts with `prefix`
+fn matches_collection_prefix(
+    name: &str,
+    collection_prefix: &str,
+    prefix: Option<&str>,
+    absolute: bool,
+) -> bool {
+    match prefix {
+        Some(prefix) if absolute => name.starts_with(prefix),
+        // Without a tenant, the collection prefix also starts the collections of every tenant,
+        // which sanitized names never contain the separator of
+        _ => name.strip_prefix(collection_prefix).is_some_and(|rest| {
+            rest.starts_with(prefix.unwrap_or_default()) && !rest.contains(TENANT_SEPARATOR)
+        }),
+    }
+}
+
+/// Collection name a file was given before collection names included a hash
+fn legacy_collection_name(prefix: &str, file_name: &str) -> String {
+    format!("{}{}", prefix, sanitize_name(file_name))
+}
+
+/// Prefix of every collection of a tenant, or of the collections without one
+fn collection_prefix(tenant_id: Option<&str>) -> String {
+    match tenant_id {
+        Some(tenant_id) => format!(
+            "{}{}{}",
+            COLLECTION_PREFIX,
+            sanitize_name(tenant_id),
+            TENANT_SEPARATOR
+        ),
+        None => COLLECTION_PREFIX.to_string(),
+    }
+}
+
+/// Replace non-alphanumeric characters with underscores and convert to lowercase
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Filter selecting the points of one document
+fn document_filter(document_id: &str) -> Filter {
+    Filter::must([Condition::matches("document_id", document_id.to_string())])
+}
+
+/// Payload conditions a search is restricted by
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchFilter<'a> {
+    /// Only chunks of this document
+    pub document_id: Option<&'a str>,
+    /// Only chunks stored at or after this Unix time (seconds)
+    pub ingested_after: Option<u64>,
+    /// Only chunks stored before this Unix time (seconds)
+    pub ingested_before: Option<u64>,
+}
+
+impl SearchFilter<'_> {
+    /// Qdrant filter requiring every set condition, or None when nothing is restricted
+    fn to_filter(self) -> Option<Filter> {
+        let mut conditions = Vec::new();
+        if let Some(document_id) = self.document_id {
+            conditions.push(Condition::matches("document_id", document_id.to_string()));
+        }
+        if self.ingested_after.is_some() || self.ingested_before.is_some() {
+            conditions.push(Condition::range(
+                INGESTED_AT_KEY,
+                Range {
+                    gte: self.ingested_after.map(|secs| secs as f64),
+                    lt: self.ingested_before.map(|secs| secs as f64),
+                    ..Default::default()
+                },
+            ));
+        }
+
+        (!conditions.is_empty()).then(|| Filter::must(conditions))
+    }
+}
+
+/// Point copying a point read back from another collection into a shared or named one,
+/// under an id derived from its document id and chunk index
+/// Copies into a named collection are marked with its `collection` name
+fn merged_point(point: RetrievedPoint, collection: Option<&str>) -> Result<PointStruct> {
+    let document_id = point.payload.get("document_id").and_then(|v| v.as_str());
+    let chunk_index = point
+        .payload
+        .get("chunk_index")
+        .and_then(|v| v.as_integer());
+    let (Some(document_id), Some(chunk_index)) = (document_id, chunk_index) else {
+        return Err(anyhow::anyhow!(
+            "A point has no document_id or chunk_index; run validate-collection --repair first"
+        ));
+    };
+    let point_id = shared_point_id(document_id, chunk_index as u64);
+
+    let vectors = match point.vectors.and_then(|vectors| vectors.vectors_options) {
+        Some(VectorsOptions::Vector(vector)) => Vectors::from(dense_values(vector)),
+        Some(VectorsOptions::Vectors(named)) => Vectors::from(
+            named
+                .vectors
+                .into_iter()
+                .map(|(name, vector)| (name, dense_values(vector)))
+                .collect::<HashMap<String, Vec<f32>>>(),
+        ),
+        None => return Err(anyhow::anyhow!("A point was read without its vectors")),
+    };
+
+    let mut payload = point.payload;
+    match collection {
+        Some(collection) => payload.insert(COLLECTION_KEY.to_string(), collection.into()),
+        None => payload.remove(COLLECTION_KEY),
+    };
+    Ok(PointStruct::new(point_id, vectors, payload))
+}
+
+/// Values of a dense vector read back from a collection
+fn dense_values(vector: VectorOutput) -> Vec<f32> {
+    match vector.vector {
+        Some(vector_output::Vector::Dense(dense)) => dense.data,
+        _ => vector.data,
+    }
+}
+
+/// Embedding model and task type stored with a chunk, when it has them
+fn payload_embedding_profile(payload: &HashMap<String, Value>) -> Option<EmbeddingProfile> {
+    let model = payload.get("embedding_model").and_then(|v| v.as_str())?;
+    let task_type = payload
+        .get("embedding_task_type")
+        .and_then(|v| v.as_str())
+        .and_then(|task_type| task_type.parse().ok());
+
+    Some(EmbeddingProfile {
+        model: model.clone(),
+        task_type,
+    })
+}
+
+/// Embedding profiles stored with a set of chunks, each once and in the order first seen
+/// Chunks without one (ingested before profiles were stored, or still being ingested) are skipped.
+fn distinct_embedding_profiles<'a>(
+    payloads: impl IntoIterator<Item = &'a HashMap<String, Value>>,
+) -> Vec<EmbeddingProfile> {
+    let mut profiles = Vec::new();
+    for profile in payloads.into_iter().filter_map(payload_embedding_profile) {
+        if !profiles.contains(&profile) {
+            profiles.push(profile);
+        }
+    }
+    profiles
+}
+
+/// Heading breadcrumb stored with a chunk split from a markdown document
+fn payload_breadcrumb(payload: &HashMap<String, Value>) -> Option<String> {
+    payload.get("breadcrumb").and_then(|v| v.as_str()).cloned()
+}
+
+/// Index of the first CSV or TSV row stored with a chunk split from a table file
+fn payload_row(payload: &HashMap<String, Value>) -> Option<usize> {
+    payload
+        .get("row")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+}
+
+/// Page a chunk split from a document with page breaks starts on
+fn payload_page(payload: &HashMap<String, Value>) -> Option<usize> {
+    payload
+        .get("page")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+}
+
+/// Token count stored under `key`, absent in chunks stored before counts were kept
+fn payload_token_count(payload: &HashMap<String, Value>, key: &str) -> Option<usize> {
+    payload
+        .get(key)
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+}
+
+/// Payload stored with a chunk at `chunk_index`, read back by `payload_chunk`
+fn chunk_payload(
+    chunk: &ContextualizedChunk,
+    chunk_index: u64,
+    ingested_at: u64,
+) -> serde_json::Value {
+    let mut payload = json!({
+        TEXT_KEY: chunk.contextualized_text,
+        "token_count": chunk.token_count,
+        "original_text": chunk.original_chunk.text,
+        "original_token_count": chunk.original_chunk.token_count,
+        "document_id": chunk.original_chunk.document_id,
+        "start_position": chunk.original_chunk.start_position,
+        "chunk_index": chunk_index,
+        INGESTED_AT_KEY: ingested_at,
+    });
+    if let Some(breadcrumb) = &chunk.original_chunk.breadcrumb {
+        payload["breadcrumb"] = json!(breadcrumb);
+    }
+    if let Some(row) = chunk.original_chunk.row {
+        payload["row"] = json!(row);
+    }
+    if let Some(page) = chunk.original_chunk.page {
+        payload["page"] = json!(page);
+    }
+    payload
+}
+
+/// Lowercased distinct terms of a query worth matching by keyword, keeping the characters
+/// inside a term such as the `_` of `get_collection_name` or the `-` of `ERR-42`
+fn keyword_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+
+    for term in keyword_tokens(query) {
+        if KEYWORD_STOPWORDS.contains(&term.as_str()) || terms.contains(&term) {
+            continue;
+        }
+        terms.push(term);
+    }
+
+    terms
+}
+
+/// Lowercased words of a text, split and trimmed the way `keyword_terms` splits a query
+fn keyword_tokens(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase()
+        })
+        .filter(|token| !token.is_empty())
+}
+
+/// Score chunks by the fraction of `terms` their text contains as whole words, returning
+/// the `limit` best; chunks scoring the same keep their order
+fn rank_keyword_matches(
+    chunks: Vec<(usize, TextChunk)>,
+    terms: &[String],
+    limit: u64,
+) -> Vec<ScoredChunk> {
+    let mut scored: Vec<ScoredChunk> = chunks
+        .into_iter()
+        .map(|(chunk_index, chunk)| {
+            let tokens: HashSet<String> = keyword_tokens(&chunk.text).collect();
+            let matched = terms.iter().filter(|term| tokens.contains(*term)).count();
+            ScoredChunk {
+                chunk,
+                score: 0.0,
+                keyword_score: Some(matched as f32 / terms.len() as f32),
+                chunk_index,
+            }
+        })
+        .filter(|scored| scored.keyword_score > Some(0.0))
+        .collect();
+
+    scored.sort_by(|a, b| {
+        let keyword_score = |scored: &ScoredChunk| scored.keyword_score.unwrap_or(0.0);
+        keyword_score(b).total_cmp(&keyword_score(a))
+    });
+    scored.truncate(limit as usize);
+    scored
+}
+
+/// Chunk index and (contextualized) chunk of a stored payload, None without text
+/// Chunks missing a document id belong to `file_name`
+fn payload_chunk(payload: &HashMap<String, Value>, file_name: &str) -> Option<(usize, TextChunk)> {
+    let text = payload.get(TEXT_KEY)?.as_str()?;
+    let document_id = payload
+        .get("document_id")
+        .and_then(|v| v.as_str())
+        .cloned()
+        .unwrap_or_else(|| file_name.to_string());
+    let start_position = payload
+        .get("start_position")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+        .unwrap_or(0);
+    let chunk_index = payload
+        .get("chunk_index")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+        .unwrap_or(0);
+
+    let chunk = TextChunk {
+        text: text.to_string(),
+        token_count: payload_token_count(payload, "token_count")
+            .unwrap_or_else(|| crate::chunking::estimate_token_count(text)),
+        document_id,
+        start_position,
+        breadcrumb: payload_breadcrumb(payload),
+        row: payload_row(payload),
+        page: payload_page(payload),
+    };
+    Some((chunk_index, chunk))
+}
+
+/// Export record of a stored point from its payload and vectors
+fn export_record(
+    payload: HashMap<String, Value>,
+    vectors: Option<VectorsOutput>,
+) -> Result<ExportRecord> {
+    let (embedding, raw_embedding) = match vectors.and_then(|vectors| vectors.vectors_options) {
+        Some(VectorsOptions::Vector(vector)) => (dense_values(vector), None),
+        Some(VectorsOptions::Vectors(mut named)) => {
+            let embedding = named
+                .vectors
+                .remove(CONTEXTUAL_VECTOR)
+                .map(dense_values)
+                .context("A point has no contextual vector")?;
+            (
+                embedding,
+                named.vectors.remove(RAW_VECTOR).map(dense_values),
+            )
+        }
+        None => return Err(anyhow::anyhow!("A point was read without its vectors")),
+    };
+
+    let fields = payload
+        .into_iter()
+        .map(|(key, value)| (key, value.into_json()))
+        .collect();
+    let mut record: ExportRecord = serde_json::from_value(serde_json::Value::Object(fields))
+        .context("A point is missing chunk fields; run validate-collection --repair first")?;
+    record.embedding = embedding;
+    record.raw_embedding = raw_embedding;
+
+    Ok(record)
+}
+
+/// Payload of an exported chunk as stored, without its embeddings
+fn import_payload(record: &ExportRecord) -> Result<HashMap<String, Value>> {
+    let mut fields = serde_json::to_value(record)?;
+    if let Some(fields) = fields.as_object_mut() {
+        fields.remove("embedding");
+        fields.remove("raw_embedding");
+    }
+
+    Ok(serde_json::from_value(fields)?)
+}
+
+/// Length of the embeddings of exported chunks, and whether they carry raw embeddings too
+/// Fails unless every chunk has embeddings of one and the same length
+pub fn record_dimensions(records: &[ExportRecord]) -> Result<(usize, bool)> {
+    let Some(first) = records.first() else {
+        return Err(anyhow::anyhow!("No chunks to import"));
+    };
+    let vector_size = first.embedding.len();
+    let raw_vectors = first.raw_embedding.is_some();
+    if vector_size == 0 {
+        return Err(anyhow::anyhow!(
+            "Chunk {} of {} has no embedding",
+            first.chunk_index,
+            first.document_id
+        ));
+    }
+
+    for record in records {
+        let raw_size = record.raw_embedding.as_ref().map(Vec::len);
+        if record.embedding.len() != vector_size {
+            return Err(anyhow::anyhow!(
+                "Chunk {} of {} has a {}-dimensional embedding, expected {}",
+                record.chunk_index,
+                record.document_id,
+                record.embedding.len(),
+                vector_size
+            ));
+        }
+        if raw_size.is_some() != raw_vectors || raw_size.is_some_and(|size| size != vector_size) {
+            return Err(anyhow::anyhow!(
+                "Chunk {} of {} has a raw embedding unlike the other chunks",
+                record.chunk_index,
+                record.document_id
+            ));
+        }
+    }
+
+    Ok((vector_size, raw_vectors))
+}
+
+/// Chunks of stored payloads ordered by document and chunk index, whatever order the points
+/// were read in; payloads without text are left out
+fn ordered_chunks<'a>(
+    payloads: impl IntoIterator<Item = &'a HashMap<String, Value>>,
+    file_name: &str,
+) -> Vec<TextChunk> {
+    let mut indexed_chunks: Vec<(usize, TextChunk)> = payloads
+        .into_iter()
+        .filter_map(|payload| payload_chunk(payload, file_name))
+        .collect();
+
+    indexed_chunks.sort_by(|(a_index, a), (b_index, b)| {
+        a.document_id.cmp(&b.document_id).then(a_index.cmp(b_index))
+    });
+    indexed_chunks.into_iter().map(|(_, chunk)| chunk).collect()
+}
+
+/// Point id of a chunk in a collection shared by several documents
+/// Stable across runs (FNV-1a over the document id and chunk index) so re-ingesting overwrites
+fn shared_point_id(document_id: &str, chunk_index: u64) -> u64 {
+    fnv1a_hash(
+        document_id
+            .bytes()
+            .chain([0])
+            .chain(chunk_index.to_le_bytes()),
+    )
+}
+
+/// 64-bit FNV-1a hash of a byte sequence, stable across runs and platforms
+fn fnv1a_hash(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.into_iter().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_filter() {
+        assert_eq!(SearchFilter::default().to_filter(), None);
+
+        let filter = SearchFilter {
+            document_id: Some("notes.md"),
+            ingested_after: Some(1_700_000_000),
+            ingested_before: None,
+        }
+        .to_filter()
+        .unwrap();
+        assert_eq!(filter.must.len(), 2);
+        assert_eq!(filter.must[0], document_filter("notes.md").must[0]);
+        assert_eq!(
+            filter.must[1],
+            Condition::range(
+                INGESTED_AT_KEY,
+                Range {
+                    gte: Some(1_700_000_000.0),
+                    ..Default::default()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_schema_report() {
+        let payload = |value: serde_json::Value| -> HashMap<String, Value> {
+            serde_json::from_value(value).unwrap()
+        };
+        let payloads = vec![
+            payload(json!({
+                "text": "context and chunk",
+                "original_text": "chunk",
+                "document_id": "old.txt",
+                "start_position": 0,
+            })),
+            payload(json!({
+                "text": "context and chunk",
+                "original_text": "chunk",
+                "document_id": "old.txt",
+                "start_position": 10,
+                "chunk_index": 1,
+            })),
+        ];
+
+        let report = SchemaReport::from_payloads(&payloads);
+        assert_eq!(report.sampled_points, 2);
+        assert_eq!(report.missing_keys(), vec!["chunk_index"]);
+        assert!(report.key_counts.contains(&("chunk_index", 1)));
+        assert!(report.key_counts.contains(&("text", 2)));
+    }
+
+    #[test]
+    fn test_tenant_collection_names() {
+        let untenanted = collection_prefix(None);
+        let acme = collection_prefix(Some("Acme"));
+        let globex = collection_prefix(Some("globex"));
+
+        assert_eq!(
+            get_collection_name(&untenanted, "Report.pdf"),
+            "rag_report_pdf_7cfdf087"
+        );
+        assert_eq!(
+            get_collection_name(&acme, "Report.pdf"),
+            "rag_acme-report_pdf_7cfdf087"
+        );
+        assert_ne!(
+            get_collection_name(&acme, "Report.pdf"),
+            get_collection_name(&globex, "Report.pdf")
+        );
+        // A tenant name cannot be mistaken for part of a document name
+        assert_ne!(
+            get_collection_name(&collection_prefix(Some("a")), "b_c"),
+            get_collection_name(&collection_prefix(Some("a_b")), "c")
+        );
+    }
+
+    #[test]
+    fn test_named_collections() {
+        let key = named_collection("handbooks");
+        assert_eq!(named_collection_name(&key), Some("handbooks"));
+        assert_eq!(named_collection_name("handbooks"), None);
+        assert_eq!(named_collection_name("guides/setup.md"), None);
+
+        // A named collection never takes the collection of a document, whatever its name
+        let prefix = collection_prefix(None);
+        let named = get_collection_name(
+            &format!("{}{}", prefix, NAMED_COLLECTION_PREFIX),
+            "handbooks",
+        );
+        assert_eq!(named, "rag_c_handbooks_6201fb22");
+        assert_ne!(named, get_collection_name(&prefix, "handbooks"));
+        assert_ne!(named, get_collection_name(&prefix, "c_handbooks"));
+        assert!(matches_collection_prefix(
+            &named,
+            &prefix,
+            Some(NAMED_COLLECTION_PREFIX),
+            false
+        ));
+    }
+
+    #[test]
+    fn test_matches_collection_prefix() {
+        let untenanted = collection_prefix(None);
+        let acme = collection_prefix(Some("acme"));
+
+        assert!(matches_collection_prefix(
+            "rag_notes_txt_1",
+            &untenanted,
+            None,
+            false
+        ));
+        assert!(matches_collection_prefix(
+            "rag_notes_txt_1",
+            &untenanted,
+            Some("notes"),
+            false
+        ));
+        assert!(!matches_collection_prefix(
+            "rag_report_pdf_1",
+            &untenanted,
+            Some("notes"),
+            false
+        ));
+        // A prefix is scoped to the tenant's collections
+        assert!(!matches_collection_prefix(
+            "rag_acme-notes_txt_1",
+            &untenanted,
+            Some("notes"),
+            false
+        ));
+        assert!(!matches_collection_prefix(
+            "rag_acme-notes_txt_1",
+            &untenanted,
+            None,
+            false
+        ));
+        assert!(matches_collection_prefix(
+            "rag_acme-notes_txt_1",
+            &acme,
+            Some("notes"),
+            false
+        ));
+        assert!(!matches_collection_prefix(
+            "other_notes",
+            &untenanted,
+            Some("notes"),
+            false
+        ));
+        // unless it is absolute
+        assert!(matches_collection_prefix(
+            "rag_acme-notes_txt_1",
+            &untenanted,
+            Some("rag_"),
+            true
+        ));
+        assert!(!matches_collection_prefix(
+            "rag_notes_txt_1",
+            &untenanted,
+            Some("notes"),
+            true
+        ));
+    }
+
+    #[test]
+    fn test_legacy_collection_name() {
+        let prefix = collection_prefix(None);
+        assert_eq!(
+            legacy_collection_name(&prefix, "Report.pdf"),
+            "rag_report_pdf"
+        );
+        assert!(get_collection_name(&prefix, "Report.pdf").starts_with("rag_report_pdf_"));
+        assert_eq!(
+            legacy_collection_name(&prefix, SHARED_COLLECTION),
+            "rag_shared"
+        );
+
+        // A legacy name ending like a hash is still told apart from a hashed one
+        let legacy = legacy_collection_name(&prefix, "notes_deadbeef");
+        assert_eq!(legacy, "rag_notes_deadbeef");
+        assert_ne!(get_collection_name(&prefix, "notes"), legacy);
+    }
+
+    #[test]
+    fn test_colliding_sanitized_names() {
+        let prefix = collection_prefix(None);
+        assert_eq!(
+            sanitize_name("notes (1).txt"),
+            sanitize_name("notes_(1).txt")
+        );
+
+        // Both sanitize to notes__1__txt, but still get collections of their own
+        let spaced = get_collection_name(&prefix, "notes (1).txt");
+        let underscored = get_collection_name(&prefix, "notes_(1).txt");
+        assert_ne!(spaced, underscored);
+        assert!(spaced.starts_with("rag_notes__1__txt_"));
+        assert!(underscored.starts_with("rag_notes__1__txt_"));
+        assert_eq!(spaced, get_collection_name(&prefix, "notes (1).txt"));
+        assert_ne!(
+            get_collection_name(&prefix, "Notes.txt"),
+            get_collection_name(&prefix, "notes.txt")
+        );
+    }
+
+    #[test]
+    fn test_chunk_payload_round_trip() {
+        let chunk = ContextualizedChunk {
+            original_chunk: TextChunk {
+                text: "Refunds take five days.".to_string(),
+                token_count: 6,
+                document_id: "policy.md".to_string(),
+                start_position: 120,
+                breadcrumb: Some("Policy > Refunds".to_string()),
+                row: Some(4),
+                page: Some(2),
+            },
+            contextualized_text: "From the refund section. Refunds take five days.".to_string(),
+            token_count: 11,
+        };
+        let payload: HashMap<String, Value> =
+            serde_json::from_value(chunk_payload(&chunk, 3, 1_700_000_000)).unwrap();
+
+        let (chunk_index, restored) = payload_chunk(&payload, "other.md").unwrap();
+        assert_eq!(chunk_index, 3);
+        assert_eq!(restored.text, chunk.contextualized_text);
+        assert_eq!(restored.token_count, 11);
+        assert_eq!(restored.document_id, "policy.md");
+        assert_eq!(restored.start_position, 120);
+        assert_eq!(restored.breadcrumb.as_deref(), Some("Policy > Refunds"));
+        assert_eq!(restored.row, Some(4));
+        assert_eq!(restored.page, Some(2));
+        assert_eq!(
+            payload_token_count(&payload, "original_token_count"),
+            Some(6)
+        );
+
+        // Chunks stored before token counts were kept fall back to an estimate
+        let mut old_payload = payload.clone();
+        old_payload.remove("token_count");
+        let (_, restored) = payload_chunk(&old_payload, "other.md").unwrap();
+        assert_eq!(
+            restored.token_count,
+            crate::chunking::estimate_token_count(&chunk.contextualized_text)
+        );
+    }
+
+    #[test]
+    fn test_keyword_terms() {
+        assert_eq!(
+            keyword_terms("What does error E1234 mean in get_collection_name()?"),
+            vec!["error", "e1234", "mean", "get_collection_name"]
+        );
+        assert_eq!(keyword_terms("What is it?"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_rank_keyword_matches() {
+        let chunk = |text: &str| TextChunk {
+            text: text.to_string(),
+            token_count: 4,
+            document_id: "doc.txt".to_string(),
+            start_position: 0,
+            breadcrumb: None,
+            row: None,
+            page: None,
+        };
+        let chunks = vec![
+            (0, chunk("An error is reported to the log")),
+            (1, chunk("Errors and terror are other words")),
+            (2, chunk("Error E1234, means the disk is full")),
+        ];
+
+        let ranked = rank_keyword_matches(chunks, &keyword_terms("error E1234"), 5);
+        // Keyword matches are not similarity scores
+        assert!(ranked.iter().all(|scored| scored.score == 0.0));
+        let ranked: Vec<(usize, f32)> = ranked
+            .iter()
+            .map(|scored| (scored.chunk_index, scored.keyword_score.unwrap()))
+            .collect();
+        // Only whole words match, so "Errors" and "terror" do not contain "error"
+        assert_eq!(ranked, vec![(2, 1.0), (0, 0.5)]);
+    }
+
+    #[test]
+    fn test_ordered_chunks() {
+        // More chunks than fit in one scroll page, read back out of order
+        let mut payloads: Vec<HashMap<String, Value>> = (0..250)
+            .map(|i| {
+                let chunk = ContextualizedChunk {
+                    original_chunk: TextChunk {
+                        text: format!("chunk {}", i),
+                        token_count: 2,
+                        document_id: "doc.txt".to_string(),
+                        start_position: i * 10,
+                        breadcrumb: None,
+                        row: None,
+                        page: None,
+                    },
+                    contextualized_text: format!("context {}", i),
+                    token_count: 2,
+                };
+                serde_json::from_value(chunk_payload(&chunk, i as u64, 0)).unwrap()
+            })
+            .collect();
+        payloads.reverse();
+        payloads.swap(0, 100);
+
+        let chunks = ordered_chunks(&payloads, "doc.txt");
+        assert_eq!(chunks.len(), 250);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.text, format!("context {}", i));
+            assert_eq!(chunk.start_position, i * 10);
+        }
+    }
+
+    #[test]
+    fn test_export_record() {
+        let chunk = ContextualizedChunk {
+            original_chunk: TextChunk {
+                text: "Refunds take five days.".to_string(),
+                token_count: 6,
+                document_id: "policy.md".to_string(),
+                start_position: 120,
+                breadcrumb: None,
+                row: None,
+                page: None,
+            },
+            contextualized_text: "From the refund section. Refunds take five days.".to_string(),
+            token_count: 11,
+        };
+        let payload: HashMap<String, Value> =
+            serde_json::from_value(chunk_payload(&chunk, 3, 1_700_000_000)).unwrap();
+        let vectors = VectorsOutput {
+            vectors_options: Some(VectorsOptions::Vector(VectorOutput {
+                vector: Some(vector_output::Vector::Dense(
+                    qdrant_client::qdrant::DenseVector {
+                        data: vec![0.1, -0.2, 0.3],
+                    },
+                )),
+                ..Default::default()
+            })),
+        };
+
+        let record = export_record(payload, Some(vectors)).unwrap();
+        assert_eq!(record.text, chunk.contextualized_text);
+        assert_eq!(record.document_id, "policy.md");
+        assert_eq!(record.start_position, 120);
+        assert_eq!(record.chunk_index, 3);
+        assert_eq!(record.embedding, vec![0.1, -0.2, 0.3]);
+        assert_eq!(record.raw_embedding, None);
+        assert_eq!(record.payload["original_text"], "Refunds take five days.");
+
+        // Payload fields stay top-level keys of the exported line and read back the same
+        let line = serde_json::to_string(&record).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["ingested_at"], 1_700_000_000);
+        assert!(json.get("raw_embedding").is_none());
+        assert_eq!(serde_json::from_str::<ExportRecord>(&line).unwrap(), record);
+
+        assert!(export_record(HashMap::new(), None).is_err());
+
+        // Importing the record stores the payload it was exported from
+        let original: HashMap<String, Value> =
+            serde_json::from_value(chunk_payload(&chunk, 3, 1_700_000_000)).unwrap();
+        assert_eq!(import_payload(&record).unwrap(), original);
+    }
+
+    #[test]
+    fn test_record_dimensions() {
+        let record = |embedding: Vec<f32>, raw_embedding: Option<Vec<f32>>| ExportRecord {
+            text: "chunk".to_string(),
+            document_id: "doc.txt".to_string(),
+            start_position: 0,
+            chunk_index: 0,
+            embedding,
+            raw_embedding,
+            payload: serde_json::Map::new(),
+        };
+
+        assert_eq!(
+            record_dimensions(&[record(vec![0.1, 0.2], None), record(vec![0.3, 0.4], None)])
+                .unwrap(),
+            (2, false)
+        );
+        assert_eq!(
+            record_dimensions(&[record(vec![0.1], Some(vec![0.2]))]).unwrap(),
+            (1, true)
+        );
+        assert!(record_dimensions(&[]).is_err());
+        assert!(record_dimensions(&[record(Vec::new(), None)]).is_err());
+        assert!(
+            record_dimensions(&[record(vec![0.1, 0.2], None), record(vec![0.3], None)]).is_err()
+        );
+        assert!(
+            record_dimensions(&[record(vec![0.1], Some(vec![0.2])), record(vec![0.3], None)])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_shared_point_id() {
+        assert_eq!(shared_point_id("a.txt", 0), shared_point_id("a.txt", 0));
+        assert_ne!(shared_point_id("a.txt", 0), shared_point_id("a.txt", 1));
+        assert_ne!(shared_point_id("a.txt", 0), shared_point_id("b.txt", 0));
+    }
+
+    #[test]
+    fn test_distinct_embedding_profiles() {
+        let payload = |model: Option<&str>, task_type: Option<&str>| -> HashMap<String, Value> {
+            serde_json::from_value(json!({
+                "text": "Refunds take five days.",
+                "embedding_model": model,
+                "embedding_task_type": task_type,
+            }))
+            .unwrap()
+        };
+
+        let payloads = vec![
+            payload(None, None),
+            payload(Some("text-embedding-004"), Some("RETRIEVAL_DOCUMENT")),
+            payload(Some("text-embedding-004"), Some("RETRIEVAL_DOCUMENT")),
+            payload(Some("gemini-embedding-001"), Some("RETRIEVAL_DOCUMENT")),
+            payload(Some("text-embedding-004"), None),
+        ];
+        let profiles = distinct_embedding_profiles(&payloads);
+        let models: Vec<&str> = profiles.iter().map(|p| p.model.as_str()).collect();
+        assert_eq!(
+            models,
+            vec![
+                "text-embedding-004",
+                "gemini-embedding-001",
+                "text-embedding-004"
+            ]
+        );
+        assert_eq!(
+            profiles[0].task_type,
+            Some(crate::gemini::TaskType::RetrievalDocument)
+        );
+        assert_eq!(profiles[2].task_type, None);
+
+        assert!(distinct_embedding_profiles(&payloads[..1]).is_empty());
+        assert_eq!(distinct_embedding_profiles(&payloads[1..3]).len(), 1);
+    }
+
+    #[test]
+    fn test_merged_point() {
+        let dense = |data: Vec<f32>| VectorOutput {
+            vector: Some(vector_output::Vector::Dense(
+                qdrant_client::qdrant::DenseVector { data },
+            )),
+            ..Default::default()
+        };
+        let payload: HashMap<String, Value> = serde_json::from_value(json!({
+            "document_id": "policy.md",
+            "chunk_index": 3,
+            "text": "Refunds take five days.",
+        }))
+        .unwrap();
+        let point = RetrievedPoint {
+            id: Some(PointId::from(3)),
+            payload: payload.clone(),
+            vectors: Some(VectorsOutput {
+                vectors_options: Some(VectorsOptions::Vectors(
+                    qdrant_client::qdrant::NamedVectorsOutput {
+                        vectors: HashMap::from([
+                            (CONTEXTUAL_VECTOR.to_string(), dense(vec![0.1, 0.2])),
+                            (RAW_VECTOR.to_string(), dense(vec![0.3, 0.4])),
+                        ]),
+                    },
+                )),
+            }),
+            ..Default::default()
+        };
+
+        let merged = merged_point(point.clone(), Some("handbooks")).unwrap();
+        assert_eq!(
+            merged.id,
+            Some(PointId::from(shared_point_id("policy.md", 3)))
+        );
+        assert_eq!(
+            merged.vectors,
+            Some(Vectors::from(HashMap::from([
+                (CONTEXTUAL_VECTOR.to_string(), vec![0.1, 0.2]),
+                (RAW_VECTOR.to_string(), vec![0.3, 0.4]),
+            ])))
+        );
+        assert_eq!(merged.payload["text"], payload["text"]);
+        assert_eq!(merged.payload[COLLECTION_KEY], Value::from("handbooks"));
+
+        // Chunks of a named collection merged into the shared one no longer belong to it
+        let mut marked = point.clone();
+        marked
+            .payload
+            .insert(COLLECTION_KEY.to_string(), Value::from("handbooks"));
+        let merged = merged_point(marked, None).unwrap();
+        assert!(!merged.payload.contains_key(COLLECTION_KEY));
+
+        let without_vectors = RetrievedPoint {
+            vectors: None,
+            ..point
+        };
+        assert!(merged_point(without_vectors, None).is_err());
+        assert!(merged_point(RetrievedPoint::default(), None).is_err());
+    }
 }
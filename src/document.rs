@@ -1,9 +1,18 @@
+use crate::chunking::PAGE_BREAK;
 use anyhow::{Context, Result};
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
 use log::{debug, info, warn};
 use mime_guess::from_path;
-use pdf_extract::extract_text;
+use pdf_extract::{output_doc_page, PlainTextOutput};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 /// Represents a document with its content and metadata
 #[derive(Debug, Clone)]
@@ -16,10 +25,141 @@ pub struct Document {
     pub mime_type: String,
 }
 
+/// Documents loaded from a directory tree by `Document::from_directory`
+#[derive(Debug, Default)]
+pub struct DirectoryDocuments {
+    /// Loaded documents, identified by their path relative to the directory
+    pub documents: Vec<Document>,
+    /// Files skipped because their format is not supported
+    pub unsupported: usize,
+    /// Supported files that could not be read
+    pub failed: usize,
+}
+
+/// Options controlling PDF text extraction
+#[derive(Debug, Clone)]
+pub struct PdfOptions {
+    /// Number of threads extracting pages in parallel
+    pub extract_threads: usize,
+    /// Strip lines repeated on at least this fraction of pages, such as running headers and
+    /// footers; page numbers within them are ignored when comparing lines
+    pub boilerplate_threshold: Option<f32>,
+    /// Password of an encrypted PDF; without it, only PDFs with an empty password open
+    pub password: Option<String>,
+}
+
+impl Default for PdfOptions {
+    fn default() -> Self {
+        PdfOptions {
+            extract_threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            boilerplate_threshold: None,
+            password: None,
+        }
+    }
+}
+
+/// Fewest pages on which lines are compared to find boilerplate
+const MIN_BOILERPLATE_PAGES: usize = 3;
+
+/// Non-empty lines at the top and at the bottom of a page that may be a header or footer
+const BOILERPLATE_EDGE_LINES: usize = 3;
+
+/// MIME type of Word (DOCX) documents
+const DOCX_MIME_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
 impl Document {
     /// Create a new document from a file path
     pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self> {
-        let path = file_path.as_ref();
+        Self::from_file_with_options(file_path, &PdfOptions::default())
+    }
+
+    /// Create a new document from a file path with custom PDF extraction options
+    pub fn from_file_with_options<P: AsRef<Path>>(
+        file_path: P,
+        pdf_options: &PdfOptions,
+    ) -> Result<Self> {
+        Self::load(
+            file_path.as_ref(),
+            None,
+            &ExtractorRegistry::with_pdf_options(pdf_options),
+        )
+    }
+
+    /// Create a new document from a file path, reading it with the extractors of `registry`
+    pub fn from_file_with_registry<P: AsRef<Path>>(
+        file_path: P,
+        registry: &ExtractorRegistry,
+    ) -> Result<Self> {
+        Self::load(file_path.as_ref(), None, registry)
+    }
+
+    /// Create a new document from a file path, reading it as the given MIME type instead of
+    /// the one guessed from its extension
+    pub fn from_file_with_mime<P: AsRef<Path>>(
+        file_path: P,
+        mime_type: &str,
+        pdf_options: &PdfOptions,
+    ) -> Result<Self> {
+        Self::load(
+            file_path.as_ref(),
+            Some(mime_type),
+            &ExtractorRegistry::with_pdf_options(pdf_options),
+        )
+    }
+
+    /// Load every supported file under a directory, including its subdirectories
+    /// Documents are identified by their path relative to `dir` (with '/' separators) so
+    /// files of the same name in different subdirectories stay apart
+    /// Unsupported and unreadable files are skipped with a warning and counted
+    pub fn from_directory<P: AsRef<Path>>(dir: P) -> Result<DirectoryDocuments> {
+        Self::from_directory_with_options(dir, &PdfOptions::default())
+    }
+
+    /// Load every supported file under a directory with custom PDF extraction options
+    pub fn from_directory_with_options<P: AsRef<Path>>(
+        dir: P,
+        pdf_options: &PdfOptions,
+    ) -> Result<DirectoryDocuments> {
+        let dir = dir.as_ref();
+        let mut paths = Vec::new();
+        collect_files(dir, &mut paths)?;
+        paths.sort();
+
+        let registry = ExtractorRegistry::with_pdf_options(pdf_options);
+        let mut loaded = DirectoryDocuments::default();
+        for path in paths {
+            let mime_type = from_path(&path).first_or_octet_stream().to_string();
+            if !registry.supports(&mime_type) {
+                warn!(
+                    "Skipping {}: unsupported format {}",
+                    path.display(),
+                    mime_type
+                );
+                loaded.unsupported += 1;
+                continue;
+            }
+
+            let document_id = relative_document_id(dir, &path)?;
+            match Self::load(&path, Some(mime_type.as_str()), &registry) {
+                Ok(document) => loaded.documents.push(Document {
+                    document_id,
+                    ..document
+                }),
+                Err(e) => {
+                    warn!("Failed to read {}: {:#}", path.display(), e);
+                    loaded.failed += 1;
+                }
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    /// Read a document as `mime_type`, or as the type detected from its path when not given
+    fn load(path: &Path, mime_type: Option<&str>, registry: &ExtractorRegistry) -> Result<Self> {
         let file_name = path
             .file_name()
             .context("Invalid file name")?
@@ -27,13 +167,19 @@ impl Document {
             .context("Invalid file name encoding")?
             .to_string();
 
-        // Detect MIME type
-        let mime = from_path(path).first_or_octet_stream();
-        let mime_type = mime.to_string();
-        debug!("Detected MIME type: {}", mime_type);
+        let mime_type = match mime_type {
+            Some(mime_type) => mime_type.to_string(),
+            None => {
+                // Detect MIME type
+                let mime = from_path(path).first_or_octet_stream();
+                let mime_type = mime.to_string();
+                debug!("Detected MIME type: {}", mime_type);
+                mime_type
+            }
+        };
 
         // Read content based on file type
-        let content = read_document_content(path, &mime_type)?;
+        let content = registry.extract(path, &mime_type)?;
 
         Ok(Document {
             content,
@@ -43,43 +189,830 @@ impl Document {
     }
 }
 
+/// Add the files under `dir` to `paths`, descending into subdirectories
+/// Symbolic links to directories are not followed, so link cycles cannot recurse forever
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), paths)?;
+        } else if entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Id of a document found under `dir`: its relative path, joined with '/' on every platform
+fn relative_document_id(dir: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    let parts = relative
+        .iter()
+        .map(|part| part.to_str().context("Invalid file name encoding"))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Ids a document given by path may be indexed under, most specific first
+/// A file ingested on its own has its file name as id, and one found in a directory its path
+/// relative to that directory, so every trailing part of the path is a candidate, such as
+/// `docs/guides/setup.md`, `guides/setup.md` and `setup.md`
+pub fn document_id_candidates(path: &Path) -> Result<Vec<String>> {
+    let parts = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_str().context("Invalid file name encoding")),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if parts.is_empty() {
+        return Err(anyhow::anyhow!("Invalid file name"));
+    }
+
+    Ok((0..parts.len())
+        .map(|start| parts[start..].join("/"))
+        .collect())
+}
+
+/// Reads the text of documents in the formats it supports
+/// Register custom extractors with `ExtractorRegistry::register` to read more formats
+pub trait DocumentExtractor: Send + Sync {
+    /// Whether documents of this MIME type can be read
+    fn supports(&self, mime_type: &str) -> bool;
+
+    /// Read the text of a document
+    fn extract(&self, path: &Path) -> Result<String>;
+}
+
+/// Extractors consulted in order for the first one supporting a document's MIME type
+pub struct ExtractorRegistry {
+    extractors: Vec<Box<dyn DocumentExtractor>>,
+}
+
+impl ExtractorRegistry {
+    /// Registry of the built-in extractors, reading PDFs with the given options
+    pub fn with_pdf_options(pdf_options: &PdfOptions) -> Self {
+        Self {
+            extractors: vec![
+                Box::new(PdfExtractor {
+                    options: pdf_options.clone(),
+                }),
+                Box::new(EpubExtractor),
+                Box::new(DocxExtractor),
+                Box::new(HtmlExtractor),
+                Box::new(TextExtractor),
+            ],
+        }
+    }
+
+    /// Add an extractor, taking precedence over those registered before it
+    pub fn register<E: DocumentExtractor + 'static>(&mut self, extractor: E) {
+        self.extractors.insert(0, Box::new(extractor));
+    }
+
+    /// Whether a registered extractor reads documents of this MIME type
+    pub fn supports(&self, mime_type: &str) -> bool {
+        self.extractor(mime_type).is_some()
+    }
+
+    /// Read the text of a document with the extractor for its MIME type
+    pub fn extract(&self, path: &Path, mime_type: &str) -> Result<String> {
+        let extractor = self.extractor(mime_type).with_context(|| {
+            format!(
+                "Unsupported document format: {}. Only text, HTML, PDF, EPUB and DOCX files are supported.",
+                mime_type
+            )
+        })?;
+        extractor.extract(path)
+    }
+
+    fn extractor(&self, mime_type: &str) -> Option<&dyn DocumentExtractor> {
+        self.extractors
+            .iter()
+            .find(|extractor| extractor.supports(mime_type))
+            .map(|extractor| extractor.as_ref())
+    }
+}
+
+impl Default for ExtractorRegistry {
+    fn default() -> Self {
+        Self::with_pdf_options(&PdfOptions::default())
+    }
+}
+
 /// Read content from a document based on its MIME type
-pub fn read_document_content<P: AsRef<Path>>(file_path: P, mime_type: &str) -> Result<String> {
-    let path = file_path.as_ref();
+pub fn read_document_content<P: AsRef<Path>>(
+    file_path: P,
+    mime_type: &str,
+    pdf_options: &PdfOptions,
+) -> Result<String> {
+    ExtractorRegistry::with_pdf_options(pdf_options).extract(file_path.as_ref(), mime_type)
+}
+
+/// Reads PDF documents page by page
+struct PdfExtractor {
+    options: PdfOptions,
+}
+
+impl DocumentExtractor for PdfExtractor {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type.starts_with("application/pdf")
+    }
+
+    fn extract(&self, path: &Path) -> Result<String> {
+        info!("Processing PDF document: {}", path.display());
+        let mut pages = extract_pdf_pages(
+            path,
+            self.options.extract_threads,
+            self.options.password.as_deref(),
+        )
+        .with_context(|| format!("Failed to extract text from PDF: {}", path.display()))?;
+        info!("Extracted {} PDF pages", pages.len());
+
+        // Running headers and footers would repeat in every chunk they fall into
+        if let Some(threshold) = self.options.boilerplate_threshold {
+            let removed = strip_repeated_lines(&mut pages, threshold);
+            info!("Removed {} repeated header and footer lines", removed);
+        }
+        // Stray control bytes would break the JSON sent to Qdrant and Gemini; the page breaks put
+        // between pages afterwards are the only ones kept
+        let content = pages
+            .iter()
+            .map(|page| sanitize_extracted_text(page))
+            .collect::<Vec<_>>()
+            .join(&format!("\n\n{}\n\n", PAGE_BREAK));
+
+        // PDF extraction can sometimes include excessive whitespace
+        let cleaned_content = normalize_whitespace(&content);
+        // Justified text is often hyphenated at line ends
+        let cleaned_content = dehyphenate(&cleaned_content);
+
+        if cleaned_content.is_empty() {
+            warn!("Extracted PDF content is empty or contains only whitespace");
+        }
+
+        Ok(cleaned_content)
+    }
+}
+
+/// Reads EPUB e-books chapter by chapter
+struct EpubExtractor;
+
+impl DocumentExtractor for EpubExtractor {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type.starts_with("application/epub+zip")
+    }
+
+    fn extract(&self, path: &Path) -> Result<String> {
+        info!("Processing EPUB document: {}", path.display());
+        let content = extract_epub_text(path)
+            .with_context(|| format!("Failed to extract text from EPUB: {}", path.display()))?;
+
+        if content.is_empty() {
+            warn!("Extracted EPUB content is empty or contains only whitespace");
+        }
+
+        Ok(content)
+    }
+}
+
+/// Reads the paragraphs of Word (DOCX) documents
+struct DocxExtractor;
+
+impl DocumentExtractor for DocxExtractor {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type.starts_with(DOCX_MIME_TYPE)
+    }
+
+    fn extract(&self, path: &Path) -> Result<String> {
+        info!("Processing DOCX document: {}", path.display());
+        let file = fs::File::open(path)?;
+        let mut archive = zip::ZipArchive::new(file)
+            .with_context(|| format!("Not a valid DOCX archive: {}", path.display()))?;
+        let markup = read_zip_entry(&mut archive, "word/document.xml")?;
+        let content = docx_to_text(&markup);
+
+        if content.is_empty() {
+            warn!("Extracted DOCX content is empty or contains only whitespace");
+        }
+
+        Ok(content)
+    }
+}
+
+/// Reads HTML pages, keeping their readable text rather than the markup
+struct HtmlExtractor;
+
+impl DocumentExtractor for HtmlExtractor {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type.starts_with("text/html") || mime_type.starts_with("application/xhtml+xml")
+    }
+
+    fn extract(&self, path: &Path) -> Result<String> {
+        info!("Processing HTML document: {}", path.display());
+        let markup = read_text_file(path)?;
+        let content = html_to_text(&markup);
+
+        if content.is_empty() {
+            warn!("Extracted HTML content is empty or contains only whitespace");
+        }
+
+        Ok(content)
+    }
+}
+
+/// Reads plain text documents as they are
+struct TextExtractor;
+
+impl DocumentExtractor for TextExtractor {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type.starts_with("text/")
+    }
+
+    fn extract(&self, path: &Path) -> Result<String> {
+        info!("Processing text document: {}", path.display());
+        read_text_file(path)
+    }
+}
+
+/// Read a text file in whatever encoding it was saved in
+fn read_text_file(path: &Path) -> Result<String> {
+    let bytes =
+        fs::read(path).with_context(|| format!("Failed to read text file: {}", path.display()))?;
+    Ok(decode_text(&bytes))
+}
+
+/// Decode text by its byte order mark, as UTF-8 when valid, or else in the encoding
+/// detected from its bytes
+/// Text that the detected encoding cannot decode either is read as UTF-8 with the invalid
+/// bytes replaced, so a stray byte never stops an ingest
+fn decode_text(bytes: &[u8]) -> String {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        debug!(
+            "Decoding text as {} by its byte order mark",
+            encoding.name()
+        );
+        let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        return text.into_owned();
+    }
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, true);
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+    if had_errors {
+        warn!(
+            "Text is neither valid UTF-8 nor {}, replacing the undecodable bytes",
+            encoding.name()
+        );
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+
+    info!("Detected the {} text encoding", encoding.name());
+    text.into_owned()
+}
+
+/// Remove the header and footer lines that appear on at least `threshold` of the pages,
+/// returning how many were removed
+/// Only the first and last `BOILERPLATE_EDGE_LINES` non-empty lines of a page are considered,
+/// so repeated lines of the body, such as table rows, are kept
+/// Lines are compared with digits masked, so "Page 3 of 10" matches "Page 4 of 10"
+fn strip_repeated_lines(pages: &mut [String], threshold: f32) -> usize {
+    if pages.len() < MIN_BOILERPLATE_PAGES {
+        return 0;
+    }
+
+    // Count each line once per page it appears on
+    let mut page_counts: HashMap<String, usize> = HashMap::new();
+    for page in pages.iter() {
+        let mut keys: Vec<String> = edge_lines(page)
+            .into_iter()
+            .filter_map(|(_, key)| key)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            *page_counts.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    let min_pages = (threshold * pages.len() as f32).ceil().max(2.0) as usize;
+    let mut removed = 0;
+    for page in pages.iter_mut() {
+        let repeated: Vec<usize> = edge_lines(page)
+            .into_iter()
+            .filter(|(_, key)| {
+                key.as_ref()
+                    .is_some_and(|key| page_counts[key] >= min_pages)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        if repeated.is_empty() {
+            continue;
+        }
+        removed += repeated.len();
+        let kept: Vec<&str> = page
+            .lines()
+            .enumerate()
+            .filter(|(index, _)| !repeated.contains(index))
+            .map(|(_, line)| line)
+            .collect();
+        *page = kept.join("\n");
+    }
+
+    removed
+}
+
+/// Indexes of the first and last `BOILERPLATE_EDGE_LINES` non-empty lines of a page, each
+/// with its `boilerplate_key`
+fn edge_lines(page: &str) -> Vec<(usize, Option<String>)> {
+    let non_empty: Vec<(usize, &str)> = page
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .collect();
+    let bottom_start = non_empty
+        .len()
+        .saturating_sub(BOILERPLATE_EDGE_LINES)
+        .max(BOILERPLATE_EDGE_LINES.min(non_empty.len()));
+
+    non_empty[..BOILERPLATE_EDGE_LINES.min(non_empty.len())]
+        .iter()
+        .chain(&non_empty[bottom_start..])
+        .map(|&(index, line)| (index, boilerplate_key(line)))
+        .collect()
+}
+
+/// Form of a line used to recognize it across pages: trimmed, whitespace collapsed and
+/// digits masked, or None for a line of digits alone, which bodies repeat by chance
+fn boilerplate_key(line: &str) -> Option<String> {
+    if !line.chars().any(char::is_alphabetic) {
+        return None;
+    }
+
+    Some(
+        line.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .map(|c| if c.is_ascii_digit() { '#' } else { c })
+            .collect(),
+    )
+}
+
+/// Extract the text of every PDF page, spreading pages over `threads` worker threads
+/// Pages are returned in document order regardless of which worker extracted them
+fn extract_pdf_pages(path: &Path, threads: usize, password: Option<&str>) -> Result<Vec<String>> {
+    let mut doc = lopdf::Document::load(path)?;
+    if doc.is_encrypted() {
+        // Many PDFs are encrypted with an empty user password
+        if let Err(e) = doc.decrypt(password.unwrap_or("")) {
+            return Err(match password {
+                Some(_) => anyhow::anyhow!("Failed to decrypt PDF with the given password: {}", e),
+                None => anyhow::anyhow!("PDF is password-protected; provide --pdf-password"),
+            });
+        }
+    }
+
+    let page_numbers: Vec<u32> = doc.get_pages().keys().copied().collect();
+    let threads = threads.clamp(1, page_numbers.len().max(1));
+    debug!(
+        "Extracting {} PDF pages with {} threads",
+        page_numbers.len(),
+        threads
+    );
+
+    // Workers pull the next page index from a shared counter so slow pages don't stall others
+    let next_page = AtomicUsize::new(0);
+    let mut pages: Vec<(usize, Result<String>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut extracted = Vec::new();
+                    loop {
+                        let index = next_page.fetch_add(1, Ordering::Relaxed);
+                        let Some(&page_number) = page_numbers.get(index) else {
+                            break;
+                        };
+                        extracted.push((index, extract_pdf_page(&doc, page_number)));
+                    }
+                    extracted
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("PDF extraction thread panicked"))
+            .collect()
+    });
+
+    // Reassemble pages in their original order
+    pages.sort_by_key(|(index, _)| *index);
+    readable_pages(pages.into_iter().map(|(_, page)| page).collect())
+}
+
+/// Text of each extracted page, with pages that failed to extract left empty so that the rest
+/// keep their page numbers
+/// Fails only when no page could be extracted at all.
+fn readable_pages(pages: Vec<Result<String>>) -> Result<Vec<String>> {
+    let mut first_error = None;
+    let mut failed = 0;
+    let mut texts = Vec::with_capacity(pages.len());
+
+    for page in pages {
+        match page {
+            Ok(text) => texts.push(text),
+            Err(e) => {
+                warn!("Skipping unreadable PDF page: {:#}", e);
+                failed += 1;
+                first_error.get_or_insert(e);
+                texts.push(String::new());
+            }
+        }
+    }
+
+    match first_error {
+        Some(e) if failed == texts.len() => Err(e.context("No PDF page could be extracted")),
+        _ => Ok(texts),
+    }
+}
+
+/// Extract the text of a single PDF page
+fn extract_pdf_page(doc: &lopdf::Document, page_number: u32) -> Result<String> {
+    let mut text = String::new();
+    {
+        let mut output = PlainTextOutput::new(&mut text);
+        output_doc_page(doc, &mut output, page_number)
+            .map_err(|e| anyhow::anyhow!("Failed to extract page {}: {}", page_number, e))?;
+    }
+    Ok(text)
+}
+
+/// Extract the text of an EPUB book, chapter by chapter in reading (spine) order
+fn extract_epub_text(path: &Path) -> Result<String> {
+    let file = fs::File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file).context("Not a valid EPUB archive")?;
+
+    // The container points at the package document that lists the chapters
+    let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+    let package_path = epub_package_path(&container)?;
+    let package = read_zip_entry(&mut archive, &package_path)?;
+
+    // Chapter paths are relative to the package document
+    let base_dir = match package_path.rfind('/') {
+        Some(i) => &package_path[..=i],
+        None => "",
+    };
+
+    let mut chapters = Vec::new();
+    for href in epub_spine(&package)? {
+        let markup = read_zip_entry(&mut archive, &format!("{}{}", base_dir, href))?;
+        let text = markup_to_text(&markup);
+        if !text.is_empty() {
+            chapters.push(text);
+        }
+    }
+    debug!("Extracted {} EPUB chapters", chapters.len());
+
+    // Chapter breaks become paragraph separators for the chunker
+    Ok(chapters.join("\n\n"))
+}
+
+/// Convert the main part of a DOCX document to plain text, one paragraph per `w:p` element
+/// Line breaks within a paragraph are kept and tabs become spaces
+fn docx_to_text(markup: &str) -> String {
+    let mut reader = Reader::from_str(markup);
+    let mut text = String::new();
+    // Whether the reader is inside a `w:t` run of text
+    let mut in_text = false;
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Stopped parsing malformed DOCX markup: {}", e);
+                break;
+            }
+        };
+
+        match event {
+            Event::Start(e) if e.local_name().as_ref() == b"t" => in_text = true,
+            Event::End(e) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"p" => text.push_str("\n\n"),
+                _ => {}
+            },
+            Event::Empty(e) => match e.local_name().as_ref() {
+                b"tab" => text.push(' '),
+                b"br" | b"cr" => text.push('\n'),
+                _ => {}
+            },
+            Event::Text(e) if in_text => {
+                let content = e
+                    .unescape()
+                    .map(|content| content.into_owned())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&e).into_owned());
+                text.push_str(&content);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
 
-    match mime_type {
-        // Handle PDF documents
-        mime if mime.starts_with("application/pdf") => {
-            info!("Processing PDF document: {}", path.display());
-            let content = extract_text(path)
-                .with_context(|| format!("Failed to extract text from PDF: {}", path.display()))?;
+    clean_paragraphs(&text)
+}
+
+/// Read a text entry from a zip archive
+fn read_zip_entry(archive: &mut zip::ZipArchive<fs::File>, name: &str) -> Result<String> {
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("Missing archive entry {}", name))?;
+    let mut content = String::new();
+    entry
+        .read_to_string(&mut content)
+        .with_context(|| format!("Failed to read archive entry {}", name))?;
+    Ok(content)
+}
 
-            // PDF extraction can sometimes include excessive whitespace
-            let cleaned_content = normalize_whitespace(&content);
+/// Find the package document (content.opf) path in an EPUB container.xml
+fn epub_package_path(container: &str) -> Result<String> {
+    let mut reader = Reader::from_str(container);
 
-            if cleaned_content.is_empty() {
-                warn!("Extracted PDF content is empty or contains only whitespace");
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                if let Some(path) = xml_attribute(&e, "full-path")? {
+                    return Ok(path);
+                }
             }
+            Event::Eof => return Err(anyhow::anyhow!("EPUB container has no rootfile")),
+            _ => {}
+        }
+    }
+}
+
+/// List chapter paths from an EPUB package document in spine order
+fn epub_spine(package: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(package);
+    let mut manifest = HashMap::new();
+    let mut spine = Vec::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"item" => {
+                    if let (Some(id), Some(href)) =
+                        (xml_attribute(&e, "id")?, xml_attribute(&e, "href")?)
+                    {
+                        manifest.insert(id, href);
+                    }
+                }
+                b"itemref" => {
+                    if let Some(idref) = xml_attribute(&e, "idref")? {
+                        spine.push(idref);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(spine
+        .iter()
+        .filter_map(|idref| manifest.get(idref).cloned())
+        .collect())
+}
+
+/// Read and unescape an attribute of an XML element
+fn xml_attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    match element.try_get_attribute(name)? {
+        Some(attribute) => Ok(Some(attribute.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+/// Convert an HTML page to plain text like `markup_to_text`
+/// Script and style contents are removed first, since characters such as '<' in them would
+/// otherwise be parsed as markup
+fn html_to_text(markup: &str) -> String {
+    let mut markup = markup.to_string();
+    for element in ["script", "style"] {
+        markup = strip_raw_text_element(&markup, element);
+    }
+    markup_to_text(&markup)
+}
+
+/// Remove every `element` with its content from HTML, matching tag names in any case
+/// An element left open runs to the end of the markup
+fn strip_raw_text_element(markup: &str, element: &str) -> String {
+    // ASCII lowercasing keeps byte offsets, so positions found in `lower` apply to `markup`
+    let lower = markup.to_ascii_lowercase();
+    let open = format!("<{}", element);
+    let close = format!("</{}", element);
+
+    let mut stripped = String::with_capacity(markup.len());
+    let mut position = 0;
+    while let Some(start) = find_tag(&lower, &open, position) {
+        stripped.push_str(&markup[position..start]);
+        position = match find_tag(&lower, &close, start + open.len()) {
+            Some(end) => lower[end..]
+                .find('>')
+                .map_or(markup.len(), |gt| end + gt + 1),
+            None => markup.len(),
+        };
+    }
+    stripped.push_str(&markup[position..]);
+    stripped
+}
 
-            Ok(cleaned_content)
+/// Byte offset of the first tag starting with `prefix` at or after `from`, skipping longer
+/// tag names that merely begin with it (such as "<scripts" for "<script")
+fn find_tag(lower: &str, prefix: &str, from: usize) -> Option<usize> {
+    let mut from = from;
+    while let Some(offset) = lower[from..].find(prefix) {
+        let start = from + offset;
+        let next = lower[start + prefix.len()..].chars().next();
+        if next.map_or(true, |c| c == '>' || c == '/' || c.is_whitespace()) {
+            return Some(start);
         }
+        from = start + prefix.len();
+    }
+    None
+}
+
+/// Convert (X)HTML markup to plain text, separating block elements with paragraph breaks
+/// Script, style and head contents are dropped
+fn markup_to_text(markup: &str) -> String {
+    let mut reader = Reader::from_str(markup);
+    // Tolerate mismatched end tags in hand-written markup
+    reader.config_mut().check_end_names = false;
+
+    let mut text = String::new();
+    // Depth of nesting inside an element whose content is dropped
+    let mut skip_depth = 0;
 
-        // Handle plain text documents
-        mime if mime.starts_with("text/") => {
-            info!("Processing text document: {}", path.display());
-            let content = fs::read_to_string(path)
-                .with_context(|| format!("Failed to read text file: {}", path.display()))?;
-            Ok(content)
+    loop {
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Stopped parsing malformed markup: {}", e);
+                break;
+            }
+        };
+
+        match event {
+            // HTML void elements are written without an end tag, so they never nest
+            Event::Start(e) if is_void_element(&e.local_name().as_ref().to_ascii_lowercase()) => {
+                if skip_depth == 0 && e.local_name().as_ref().eq_ignore_ascii_case(b"br") {
+                    text.push('\n');
+                }
+            }
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_ascii_lowercase();
+                if skip_depth > 0 || is_skipped_element(&name) {
+                    skip_depth += 1;
+                } else if is_block_element(&name) {
+                    text.push_str("\n\n");
+                }
+            }
+            Event::End(e) => {
+                let name = e.local_name().as_ref().to_ascii_lowercase();
+                if skip_depth > 0 {
+                    skip_depth -= 1;
+                } else if is_block_element(&name) {
+                    text.push_str("\n\n");
+                }
+            }
+            Event::Empty(e) => {
+                if skip_depth == 0 && e.local_name().as_ref().eq_ignore_ascii_case(b"br") {
+                    text.push('\n');
+                }
+            }
+            Event::Text(e) if skip_depth == 0 => {
+                let content = e
+                    .unescape_with(resolve_entity)
+                    .map(|content| content.into_owned())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&e).into_owned());
+                push_inline_text(&mut text, &content);
+            }
+            Event::CData(e) if skip_depth == 0 => {
+                push_inline_text(&mut text, &String::from_utf8_lossy(&e));
+            }
+            Event::Eof => break,
+            _ => {}
         }
+    }
+
+    clean_paragraphs(&text)
+}
+
+/// Elements whose content is never readable text
+fn is_skipped_element(name: &[u8]) -> bool {
+    matches!(name, b"script" | b"style" | b"head")
+}
+
+/// HTML elements that have no content or end tag
+fn is_void_element(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"area"
+            | b"base"
+            | b"br"
+            | b"col"
+            | b"embed"
+            | b"hr"
+            | b"img"
+            | b"input"
+            | b"link"
+            | b"meta"
+            | b"source"
+            | b"track"
+            | b"wbr"
+    )
+}
 
-        // Unsupported format
-        _ => Err(anyhow::anyhow!(
-            "Unsupported document format: {}. Only text and PDF files are supported.",
-            mime_type
-        )),
+/// Elements that start a new paragraph
+fn is_block_element(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"p" | b"div"
+            | b"section"
+            | b"article"
+            | b"blockquote"
+            | b"pre"
+            | b"li"
+            | b"tr"
+            | b"table"
+            | b"h1"
+            | b"h2"
+            | b"h3"
+            | b"h4"
+            | b"h5"
+            | b"h6"
+    )
+}
+
+/// Resolve XML and common HTML named entities
+fn resolve_entity(entity: &str) -> Option<&'static str> {
+    match entity {
+        "lt" => Some("<"),
+        "gt" => Some(">"),
+        "amp" => Some("&"),
+        "apos" => Some("'"),
+        "quot" => Some("\""),
+        "nbsp" => Some(" "),
+        "ndash" => Some("\u{2013}"),
+        "mdash" => Some("\u{2014}"),
+        "hellip" => Some("\u{2026}"),
+        "lsquo" => Some("\u{2018}"),
+        "rsquo" => Some("\u{2019}"),
+        "ldquo" => Some("\u{201C}"),
+        "rdquo" => Some("\u{201D}"),
+        _ => None,
+    }
+}
+
+/// Append inline text, turning source line breaks and indentation into plain spaces
+fn push_inline_text(text: &mut String, content: &str) {
+    for c in content.chars() {
+        text.push(if c.is_whitespace() { ' ' } else { c });
     }
 }
 
+/// Collapse spaces within lines and drop empty lines and paragraphs
+fn clean_paragraphs(text: &str) -> String {
+    text.split("\n\n")
+        .map(|paragraph| {
+            paragraph
+                .lines()
+                .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Remove control characters other than newlines and tabs, and the replacement characters
+/// left where the extractor met undecodable bytes
+fn sanitize_extracted_text(text: &str) -> String {
+    text.chars()
+        .filter(|&c| {
+            (!c.is_control() || c == '\n' || c == '\t') && c != char::REPLACEMENT_CHARACTER
+        })
+        .collect()
+}
+
 /// Normalize whitespace in text (remove multiple consecutive spaces, newlines, etc.)
 fn normalize_whitespace(text: &str) -> String {
     // Replace multiple spaces with a single space
@@ -125,6 +1058,46 @@ fn normalize_whitespace(text: &str) -> String {
     normalized.trim().to_string()
 }
 
+/// Rejoin words hyphenated across line breaks ("develop-\nment" becomes "development")
+/// Only joins when the hyphen ends a line and the next line continues in lowercase,
+/// so compounds like "state-of-the-art" and hyphens before capitalized words are kept
+fn dehyphenate(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '-' && i > 0 && chars[i - 1].is_alphabetic() {
+            // Skip trailing spaces to check whether the hyphen ends the line
+            let mut line_end = i + 1;
+            while line_end < chars.len() && chars[line_end] == ' ' {
+                line_end += 1;
+            }
+
+            if line_end < chars.len() && chars[line_end] == '\n' {
+                // Skip indentation of the continuation line
+                let mut next = line_end + 1;
+                while next < chars.len() && chars[next] == ' ' {
+                    next += 1;
+                }
+
+                if next < chars.len() && chars[next].is_lowercase() {
+                    // Drop the hyphen and the line break, continue with the word
+                    i = next;
+                    continue;
+                }
+            }
+        }
+
+        result.push(c);
+        i += 1;
+    }
+
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,4 +1109,237 @@ mod tests {
             "This has multiple spaces.\n\nAnd multiple newlines.\nAnd Windows line endings.";
         assert_eq!(normalize_whitespace(text), expected);
     }
+
+    #[test]
+    fn test_readable_pages() {
+        let pages = readable_pages(vec![
+            Ok("First page.".to_string()),
+            Err(anyhow::anyhow!("Failed to extract page 2: bad font")),
+            Ok("Third page.".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(pages, vec!["First page.", "", "Third page."]);
+
+        let error =
+            readable_pages(vec![Err(anyhow::anyhow!("Failed to extract page 1"))]).unwrap_err();
+        assert!(format!("{:#}", error).contains("No PDF page could be extracted"));
+
+        // Page breaks between pages survive the cleanup of the joined text
+        let joined = format!("One line-\nbreak.\n\n{}\n\nNext page.", PAGE_BREAK);
+        assert_eq!(
+            dehyphenate(&normalize_whitespace(&joined)),
+            format!("One linebreak.\n\n{}\n\nNext page.", PAGE_BREAK)
+        );
+    }
+
+    #[test]
+    fn test_sanitize_extracted_text() {
+        let text = "Total:\u{0}\t42\u{7}\r\nNext\u{c} page \u{FFFD}line\u{1b}.";
+        assert_eq!(sanitize_extracted_text(text), "Total:\t42\nNext page line.");
+    }
+
+    #[test]
+    fn test_markup_to_text() {
+        let markup = r#"<html><head><title>Book</title><style>p { margin: 0 }</style></head>
+<body><h1>Chapter One</h1>
+<p>It was a
+    dark&nbsp;and stormy night.</p><p>Then <em>nothing</em> happened.<br/>The end.</p>
+</body></html>"#;
+        let expected =
+            "Chapter One\n\nIt was a dark and stormy night.\n\nThen nothing happened.\nThe end.";
+        assert_eq!(markup_to_text(markup), expected);
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let markup = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Guide</title></head>
+<body><script type="text/javascript">if (a < b && b > c) { track("view"); }</script>
+<h2>Install</h2><p>Run the<br>installer.</p><STYLE>p > a { color: red }</STYLE>
+<p>Then restart.</p><SCRIPT src="analytics.js"></SCRIPT></body></html>"#;
+
+        let text = html_to_text(markup);
+        assert_eq!(text, "Install\n\nRun the\ninstaller.\n\nThen restart.");
+        assert!(!text.contains("track"));
+    }
+
+    #[test]
+    fn test_from_file_with_mime() {
+        let path = std::env::temp_dir().join(format!("notes-{}.txt", std::process::id()));
+        fs::write(&path, "# Notes\n\nSome text.").unwrap();
+
+        let detected = Document::from_file(&path).unwrap();
+        let forced =
+            Document::from_file_with_mime(&path, "text/markdown", &PdfOptions::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(detected.mime_type, "text/plain");
+        assert_eq!(forced.mime_type, "text/markdown");
+        assert_eq!(forced.content, "# Notes\n\nSome text.");
+    }
+
+    #[test]
+    fn test_docx_extractor() {
+        let path = std::env::temp_dir().join(format!("report-{}.docx", std::process::id()));
+        let document_xml = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+<w:body>
+<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Quarterly report</w:t></w:r></w:p>
+<w:p><w:r><w:t xml:space="preserve">Revenue grew </w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t>12%</w:t></w:r><w:r><w:br/><w:t>Costs &amp; hiring were flat.</w:t></w:r></w:p>
+<w:p/>
+</w:body>
+</w:document>"#;
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        writer
+            .start_file(
+                "word/document.xml",
+                zip::write::SimpleFileOptions::default(),
+            )
+            .unwrap();
+        std::io::Write::write_all(&mut writer, document_xml.as_bytes()).unwrap();
+        writer.finish().unwrap();
+
+        let document = Document::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(document.mime_type, DOCX_MIME_TYPE);
+        assert_eq!(
+            document.content,
+            "Quarterly report\n\nRevenue grew 12%\nCosts & hiring were flat."
+        );
+    }
+
+    #[test]
+    fn test_registered_extractor() {
+        struct Shouting;
+
+        impl DocumentExtractor for Shouting {
+            fn supports(&self, mime_type: &str) -> bool {
+                mime_type == "text/plain"
+            }
+
+            fn extract(&self, path: &Path) -> Result<String> {
+                Ok(fs::read_to_string(path)?.to_uppercase())
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("quiet-{}.txt", std::process::id()));
+        fs::write(&path, "hello").unwrap();
+
+        let mut registry = ExtractorRegistry::default();
+        registry.register(Shouting);
+        let document = Document::from_file_with_registry(&path, &registry).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(document.content, "HELLO");
+        assert!(!registry.supports("application/zip"));
+    }
+
+    #[test]
+    fn test_decode_text() {
+        let russian =
+            "Отчёт о продажах за третий квартал: выручка выросла, а расходы остались прежними.";
+        let (cp1251, _, _) = encoding_rs::WINDOWS_1251.encode(russian);
+        assert!(std::str::from_utf8(&cp1251).is_err());
+        assert_eq!(decode_text(&cp1251), russian);
+
+        let (latin1, _, _) = encoding_rs::WINDOWS_1252.encode("Le café était déjà fermé à Noël.");
+        assert_eq!(decode_text(&latin1), "Le café était déjà fermé à Noël.");
+
+        let mut with_bom = vec![0xEF, 0xBB, 0xBF];
+        with_bom.extend_from_slice("Plain".as_bytes());
+        assert_eq!(decode_text(&with_bom), "Plain");
+        assert_eq!(decode_text("Straße".as_bytes()), "Straße");
+    }
+
+    #[test]
+    fn test_from_directory() {
+        let dir = std::env::temp_dir().join(format!("docs-{}", std::process::id()));
+        fs::create_dir_all(dir.join("guides")).unwrap();
+        fs::write(dir.join("readme.txt"), "Top level.").unwrap();
+        fs::write(dir.join("guides").join("readme.txt"), "Nested.").unwrap();
+        fs::write(dir.join("image.png"), [0x89, b'P', b'N', b'G']).unwrap();
+
+        let loaded = Document::from_directory(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let ids: Vec<&str> = loaded
+            .documents
+            .iter()
+            .map(|document| document.document_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["guides/readme.txt", "readme.txt"]);
+        assert_eq!(loaded.documents[0].content, "Nested.");
+        assert_eq!(loaded.unsupported, 1);
+        assert_eq!(loaded.failed, 0);
+    }
+
+    #[test]
+    fn test_document_id_candidates() {
+        assert_eq!(
+            document_id_candidates(Path::new("./docs/guides/setup.md")).unwrap(),
+            vec!["docs/guides/setup.md", "guides/setup.md", "setup.md"]
+        );
+        // The id of a file indexed on its own is given as is
+        assert_eq!(
+            document_id_candidates(Path::new("setup.md")).unwrap(),
+            vec!["setup.md"]
+        );
+        assert!(document_id_candidates(Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_strip_repeated_lines() {
+        let bodies = [
+            "Revenue grew.",
+            "Costs fell.",
+            "Hiring slowed.",
+            "Outlook is stable.",
+        ];
+        let mut pages: Vec<String> = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| {
+                format!(
+                    "ACME Annual Report\n{}\nConfidential  -  Page {} of 4",
+                    body,
+                    i + 1
+                )
+            })
+            .collect();
+        pages[2].push_str("\nA line only on this page.");
+
+        let removed = strip_repeated_lines(&mut pages, 0.75);
+        assert_eq!(removed, 8);
+        assert_eq!(pages[0], "Revenue grew.");
+        assert_eq!(pages[2], "Hiring slowed.\nA line only on this page.");
+
+        // Lines repeated in the middle of pages, or of digits alone, are content
+        let mut pages: Vec<String> = ["one", "two", "three", "four"]
+            .iter()
+            .enumerate()
+            .map(|(i, word)| {
+                format!(
+                    "Report\n{w} a\n{w} b\nTotal 100\n{w} c\n{w} d\n{}",
+                    i + 1,
+                    w = word
+                )
+            })
+            .collect();
+        let removed = strip_repeated_lines(&mut pages, 0.75);
+        assert_eq!(removed, 4);
+        assert_eq!(pages[0], "one a\none b\nTotal 100\none c\none d\n1");
+
+        // Too few pages to tell boilerplate from content
+        let mut pages = vec!["Title\nOne".to_string(), "Title\nTwo".to_string()];
+        assert_eq!(strip_repeated_lines(&mut pages, 0.5), 0);
+    }
+
+    #[test]
+    fn test_dehyphenate() {
+        let text = "The develop-\nment of state-of-the-art systems.\nPre-\nVictorian era and a dash -\nhere.";
+        let expected =
+            "The development of state-of-the-art systems.\nPre-\nVictorian era and a dash -\nhere.";
+        assert_eq!(dehyphenate(text), expected);
+    }
 }
@@ -1,6 +1,10 @@
 use crate::context::ContextualizedChunk;
-use crate::gemini::{Embedding, GeminiClient};
-use anyhow::Result;
+use crate::gemini::{Embedding, GeminiClient, TaskType};
+use anyhow::{Context, Result};
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
 
 // Using Embedding from gemini module
 
@@ -17,6 +21,137 @@ pub struct ContextualEmbedding {
 
 // Methods moved to gemini module
 
+/// Source of embeddings for chunks and questions
+#[allow(async_fn_in_trait)]
+pub trait Embedder {
+    /// Embed texts to be stored and searched, in the order given
+    async fn embed_documents(&self, texts: &[&str]) -> Result<Vec<Embedding>>;
+
+    /// Embed a question to search with
+    async fn embed_query(&self, text: &str) -> Result<Embedding>;
+}
+
+impl Embedder for GeminiClient {
+    async fn embed_documents(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        self.get_document_embeddings(texts).await
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Embedding> {
+        self.get_query_embedding(text).await
+    }
+}
+
+/// Embedder computing vectors locally from the words of a text, for tests without network
+/// Every word is hashed to one dimension, so texts sharing words get similar vectors and the
+/// same text always gets the same vector
+#[cfg(feature = "deterministic-embeddings")]
+#[derive(Debug, Clone, Copy)]
+pub struct DeterministicEmbedder {
+    dimension: usize,
+}
+
+#[cfg(feature = "deterministic-embeddings")]
+impl DeterministicEmbedder {
+    /// Create an embedder producing vectors of `dimension` values
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            dimension: dimension.max(1),
+        }
+    }
+
+    /// Unit-length vector of the hashed words of a text, all zeros for a text without words
+    pub fn embed(&self, text: &str) -> Embedding {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let mut values = vec![0.0f32; self.dimension];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let hash = word.to_lowercase().bytes().fold(FNV_OFFSET, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            });
+            // The top bit picks the sign so unrelated words tend to cancel out
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            values[(hash % self.dimension as u64) as usize] += sign;
+        }
+
+        let norm = values.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            values.iter_mut().for_each(|value| *value /= norm);
+        }
+
+        Embedding { values }
+    }
+}
+
+#[cfg(feature = "deterministic-embeddings")]
+impl Embedder for DeterministicEmbedder {
+    async fn embed_documents(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        Ok(texts.iter().map(|text| self.embed(text)).collect())
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Embedding> {
+        Ok(self.embed(text))
+    }
+}
+
+/// Embeddings kept on disk, one JSON file per embedded text
+/// Files are named by a SHA-256 of the model, task type and text, so re-indexing an edited
+/// document only embeds the chunks whose text changed
+#[derive(Debug, Clone)]
+pub struct EmbeddingCache {
+    dir: PathBuf,
+}
+
+impl EmbeddingCache {
+    /// Cache stored in `dir`, created when the first embedding is written
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        EmbeddingCache { dir: dir.into() }
+    }
+
+    /// Key of a text's embedding by a model for a task type
+    pub fn key(model: &str, task_type: Option<TaskType>, text: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(model.as_bytes());
+        hasher.update([0]);
+        hasher.update(task_type.map_or("", TaskType::as_str).as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Cached embedding under a key; unreadable entries count as missing
+    pub fn get(&self, key: &str) -> Option<Embedding> {
+        let path = self.path(key);
+        let json = fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&json) {
+            Ok(embedding) => Some(embedding),
+            Err(e) => {
+                debug!("Ignoring unreadable cache entry {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Store an embedding under a key
+    /// The entry is written to a temporary file first so readers never see part of it
+    pub fn insert(&self, key: &str, embedding: &Embedding) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let path = self.path(key);
+        let temp_path = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&temp_path, serde_json::to_string(embedding)?)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
 /// Extension trait to add contextual embedding methods to GeminiClient
 #[allow(async_fn_in_trait)]
 pub trait ContextualEmbeddingExt {
@@ -41,7 +176,7 @@ impl ContextualEmbeddingExt for GeminiClient {
     ) -> Result<ContextualEmbedding> {
         // Generate embedding for the contextualized text instead of the original chunk
         let embedding = self
-            .get_embedding(&contextualized_chunk.contextualized_text)
+            .get_document_embedding(&contextualized_chunk.contextualized_text)
             .await?;
 
         Ok(ContextualEmbedding {
@@ -50,22 +185,117 @@ impl ContextualEmbeddingExt for GeminiClient {
         })
     }
 
-    /// Generate embeddings for multiple contextualized chunks
+    /// Generate embeddings for multiple contextualized chunks with batch requests
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "embed_chunks", skip_all, fields(chunks = chunks.len()))
+    )]
     async fn get_contextual_embeddings(
         &self,
         chunks: Vec<ContextualizedChunk>,
     ) -> Result<Vec<ContextualEmbedding>> {
-        let mut embeddings = Vec::new();
-
-        for chunk in chunks {
-            let embedding = self.get_contextual_embedding(chunk).await?;
-            embeddings.push(embedding);
-        }
+        let texts: Vec<&str> = chunks
+            .iter()
+            .map(|chunk| chunk.contextualized_text.as_str())
+            .collect();
+        let embeddings = self.get_document_embeddings(&texts).await?;
 
-        Ok(embeddings)
+        // Embeddings come back in the order of the texts
+        Ok(embeddings
+            .into_iter()
+            .zip(chunks)
+            .map(|(embedding, contextualized_chunk)| ContextualEmbedding {
+                embedding,
+                contextualized_chunk,
+            })
+            .collect())
     }
 
     // Using get_embedding from gemini module
 
     // Using generate_answer from gemini module
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_cache() {
+        let dir = std::env::temp_dir().join(format!("embedding-cache-{}", std::process::id()));
+        let cache = EmbeddingCache::new(&dir);
+        let key = EmbeddingCache::key(
+            "models/text-embedding-004",
+            Some(TaskType::RetrievalDocument),
+            "Refunds take five days.",
+        );
+
+        assert!(cache.get(&key).is_none());
+        cache
+            .insert(
+                &key,
+                &Embedding {
+                    values: vec![0.25, -0.5],
+                },
+            )
+            .unwrap();
+        let cached = cache.get(&key).map(|embedding| embedding.values);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(cached, Some(vec![0.25, -0.5]));
+
+        // Any change to the model, task type or text changes the key
+        assert_eq!(key.len(), 64);
+        assert_ne!(
+            key,
+            EmbeddingCache::key(
+                "models/embedding-001",
+                Some(TaskType::RetrievalDocument),
+                "Refunds take five days."
+            )
+        );
+        assert_ne!(
+            key,
+            EmbeddingCache::key(
+                "models/text-embedding-004",
+                Some(TaskType::RetrievalQuery),
+                "Refunds take five days."
+            )
+        );
+        assert_ne!(
+            key,
+            EmbeddingCache::key(
+                "models/text-embedding-004",
+                Some(TaskType::RetrievalDocument),
+                "Refunds take six days."
+            )
+        );
+    }
+
+    #[cfg(feature = "deterministic-embeddings")]
+    #[tokio::test]
+    async fn test_deterministic_embedder() {
+        let embedder = DeterministicEmbedder::new(64);
+        let documents = [
+            "Refunds are accepted within 30 days of purchase",
+            "The office is closed on public holidays",
+        ];
+
+        let first = embedder.embed_documents(&documents).await.unwrap();
+        let second = embedder.embed_documents(&documents).await.unwrap();
+        assert_eq!(first[0].values, second[0].values);
+        assert_eq!(first[0].values.len(), 64);
+        assert!((first[0].norm() - 1.0).abs() < 1e-5);
+
+        // The document sharing the question's words ranks first
+        let query = embedder.embed_query("Are refunds accepted?").await.unwrap();
+        let similarity = |embedding: &Embedding| -> f32 {
+            embedding
+                .values
+                .iter()
+                .zip(&query.values)
+                .map(|(a, b)| a * b)
+                .sum()
+        };
+        assert!(similarity(&first[0]) > similarity(&first[1]));
+    }
+}
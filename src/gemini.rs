@@ -1,6 +1,55 @@
-use anyhow::Result;
+use crate::chunking::estimate_token_count;
+use crate::embeddings::EmbeddingCache;
+use anyhow::{Context, Result};
+use futures::stream::{self, Stream, StreamExt};
+use log::{debug, warn};
+use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::sleep;
+
+const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+const MAX_EMBEDDING_BATCH_SIZE: usize = 100; // Most texts a batchEmbedContents request accepts
+const MAX_EMBEDDING_INPUT_TOKENS: usize = 2048; // Input limit of the embedding models
+const DEFAULT_EMPTY_RESPONSE_RETRIES: u32 = 2; // Retries of generations that returned no text
+const EMPTY_RETRY_TEMPERATURE_STEP: f32 = 0.2; // Temperature added per retry of an empty response
+const DEFAULT_EMBEDDING_CACHE_DIR: &str = ".embedding-cache"; // Where embeddings are cached on disk
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30; // Longest a request may take before failing
+const DEFAULT_EMBEDDING_CONCURRENCY: usize = 4; // Embedding batch requests sent at the same time
+
+/// Sampling settings used when generating answers
+pub const ANSWER_GENERATION: GenerationParams = GenerationParams {
+    temperature: 0.2,
+    top_p: 0.8,
+    top_k: 40,
+    max_output_tokens: 1024,
+};
+
+/// Default sampling settings used when generating chunk context
+pub const CONTEXT_GENERATION: GenerationParams = GenerationParams {
+    temperature: 0.2,
+    top_p: 0.8,
+    top_k: 40,
+    max_output_tokens: 512, // Shorter output for context generation
+};
+
+/// Sampling settings of a text generation request
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GenerationParams {
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: i32,
+    pub max_output_tokens: i32,
+}
 
 /// Configuration for Gemini API
 #[derive(Clone)]
@@ -10,21 +59,303 @@ pub struct GeminiConfig {
     pub embedding_model: String,
     pub generate_model: String,
     pub contextualize_model: String,
+    /// Model ordering retrieved chunks by relevance, kept cheap since it runs on every question
+    pub rerank_model: String,
+    /// Texts embedded per batch request (the API accepts up to 100)
+    pub embedding_batch_size: usize,
+    /// Batch requests of one embedding call in flight at once; all requests still share the
+    /// client's request limit
+    pub embedding_concurrency: usize,
+    /// Embedding model used once the primary one fails; must produce vectors of the same size
+    pub fallback_embedding_model: Option<String>,
+    /// Which part of a text too long to embed is dropped
+    pub embedding_truncation: TruncationStrategy,
+    /// Sampling settings of context generation requests
+    pub context_generation: GenerationParams,
+    /// How requests failing with 429, 500 or 503 are retried
+    pub retry: RetryPolicy,
+    /// Blocking thresholds sent with generation requests; categories left out use the API's
+    pub safety_settings: Vec<SafetySetting>,
+    /// Extra generation attempts after a response came back without text
+    pub empty_response_retries: u32,
+    /// Directory caching embeddings by content, or None to embed every text anew
+    pub embedding_cache_dir: Option<PathBuf>,
+    /// Longest a request may take, from connecting until its response is read, so a hung
+    /// connection fails instead of stalling the run
+    /// A streamed answer may take longer, as long as each piece arrives within it
+    pub request_timeout: Duration,
+}
+
+/// Retries of requests failing with a rate limit (429), a transient server error (500, 503)
+/// or without a response, such as on a reset connection or a timeout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per request, including the first (1 disables retries)
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubling with each further retry
+    pub initial_backoff: Duration,
+    /// Longest backoff between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the given failed attempt (1 for the first), honoring the
+    /// server's Retry-After when it sent one, up to `max_backoff`
+    /// Without Retry-After, the exponential backoff is jittered by up to half to spread retries
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>, jitter: f64) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_backoff);
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// What an embedding is used for, letting the model tune it for that side of retrieval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TaskType {
+    /// A chunk stored for retrieval
+    RetrievalDocument,
+    /// A question searched against stored chunks
+    RetrievalQuery,
+}
+
+impl TaskType {
+    /// Name of the task type in the API
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskType::RetrievalDocument => "RETRIEVAL_DOCUMENT",
+            TaskType::RetrievalQuery => "RETRIEVAL_QUERY",
+        }
+    }
+}
+
+impl FromStr for TaskType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "RETRIEVAL_DOCUMENT" => Ok(TaskType::RetrievalDocument),
+            "RETRIEVAL_QUERY" => Ok(TaskType::RetrievalQuery),
+            _ => Err(anyhow::anyhow!("Unknown task type: {}", s)),
+        }
+    }
+}
+
+/// Model and task type chunks were embedded with, which questions searching them must match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingProfile {
+    pub model: String,
+    /// None for chunks embedded without a task type
+    pub task_type: Option<TaskType>,
+}
+
+impl EmbeddingProfile {
+    /// Task type of questions searched against these chunks
+    pub fn query_task_type(&self) -> Option<TaskType> {
+        self.task_type.map(|_| TaskType::RetrievalQuery)
+    }
+}
+
+/// Category of harmful content that generation requests can block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HarmCategory {
+    #[serde(rename = "HARM_CATEGORY_HARASSMENT")]
+    Harassment,
+    #[serde(rename = "HARM_CATEGORY_HATE_SPEECH")]
+    HateSpeech,
+    #[serde(rename = "HARM_CATEGORY_SEXUALLY_EXPLICIT")]
+    SexuallyExplicit,
+    #[serde(rename = "HARM_CATEGORY_DANGEROUS_CONTENT")]
+    DangerousContent,
+}
+
+impl FromStr for HarmCategory {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "harassment" => Ok(HarmCategory::Harassment),
+            "hate_speech" => Ok(HarmCategory::HateSpeech),
+            "sexually_explicit" => Ok(HarmCategory::SexuallyExplicit),
+            "dangerous_content" => Ok(HarmCategory::DangerousContent),
+            _ => Err(anyhow::anyhow!(
+                "Unknown harm category: {} (expected harassment, hate_speech, sexually_explicit or dangerous_content)",
+                s
+            )),
+        }
+    }
+}
+
+/// Lowest probability of harm at which a response is blocked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HarmBlockThreshold {
+    /// Never block
+    BlockNone,
+    /// Block only content with a high probability of harm
+    BlockOnlyHigh,
+    /// Block content with a medium or high probability of harm
+    BlockMediumAndAbove,
+    /// Block content with a low, medium or high probability of harm
+    BlockLowAndAbove,
+}
+
+impl FromStr for HarmBlockThreshold {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "block_none" => Ok(HarmBlockThreshold::BlockNone),
+            "block_only_high" => Ok(HarmBlockThreshold::BlockOnlyHigh),
+            "block_medium_and_above" => Ok(HarmBlockThreshold::BlockMediumAndAbove),
+            "block_low_and_above" => Ok(HarmBlockThreshold::BlockLowAndAbove),
+            _ => Err(anyhow::anyhow!(
+                "Unknown block threshold: {} (expected block_none, block_only_high, block_medium_and_above or block_low_and_above)",
+                s
+            )),
+        }
+    }
+}
+
+/// Blocking threshold for one harm category
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SafetySetting {
+    pub category: HarmCategory,
+    pub threshold: HarmBlockThreshold,
+}
+
+/// Parse comma-separated `category=threshold` pairs, such as
+/// "dangerous_content=block_none,harassment=block_only_high"
+pub fn parse_safety_settings(s: &str) -> Result<Vec<SafetySetting>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (category, threshold) = pair
+                .split_once('=')
+                .with_context(|| format!("Expected category=threshold, got {}", pair))?;
+            Ok(SafetySetting {
+                category: category.trim().parse()?,
+                threshold: threshold.trim().parse()?,
+            })
+        })
+        .collect()
+}
+
+/// Part of an embedding input that is dropped when it exceeds the model's input limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TruncationStrategy {
+    /// Drop the end of the text
+    #[default]
+    Tail,
+    /// Drop the start of the text
+    Head,
+    /// Drop the middle, keeping the start and the end
+    Middle,
+}
+
+impl FromStr for TruncationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "tail" => Ok(TruncationStrategy::Tail),
+            "head" => Ok(TruncationStrategy::Head),
+            "middle" => Ok(TruncationStrategy::Middle),
+            _ => Err(anyhow::anyhow!(
+                "Unknown truncation strategy: {} (expected tail, head or middle)",
+                s
+            )),
+        }
+    }
 }
 
 impl GeminiConfig {
     /// Create a new configuration from environment variables
     pub fn from_env() -> Result<Self> {
-        let api_key = env::var("GEMINI_API_KEY")?;
-        let base_url = env::var("GEMINI_BASE_URL").expect("GEMINI_BASE_URL not set");
-        
+        let api_key = env::var("GEMINI_API_KEY").context("GEMINI_API_KEY not set")?;
+        let base_url = env::var("GEMINI_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string());
+        let base_url = normalize_base_url(&base_url)
+            .with_context(|| format!("Invalid GEMINI_BASE_URL: {}", base_url))?;
+
         // Default models if not specified
-        let embedding_model = env::var("EMBEDDING_MODEL")
-            .unwrap_or_else(|_| "models/text-embedding-004".to_string());
+        let embedding_model =
+            env::var("EMBEDDING_MODEL").unwrap_or_else(|_| "models/text-embedding-004".to_string());
         let generate_model = env::var("GENERATE_MODEL")
             .unwrap_or_else(|_| "models/gemini-2.5-flash-preview-05-20".to_string());
         let contextualize_model = env::var("CONTEXTUALIZE_MODEL")
             .unwrap_or_else(|_| "models/gemini-2.0-flash-lite".to_string());
+        let rerank_model =
+            env::var("RERANK_MODEL").unwrap_or_else(|_| "models/gemini-2.0-flash-lite".to_string());
+        let fallback_embedding_model = env::var("FALLBACK_EMBEDDING_MODEL").ok();
+        let embedding_batch_size = match env::var("EMBEDDING_BATCH_SIZE") {
+            Ok(size) => size
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|size| (1..=MAX_EMBEDDING_BATCH_SIZE).contains(size))
+                .with_context(|| {
+                    format!(
+                        "Invalid EMBEDDING_BATCH_SIZE: {} (expected 1 to {})",
+                        size, MAX_EMBEDDING_BATCH_SIZE
+                    )
+                })?,
+            Err(_) => MAX_EMBEDDING_BATCH_SIZE,
+        };
+        let embedding_concurrency =
+            env_or("EMBEDDING_CONCURRENCY", DEFAULT_EMBEDDING_CONCURRENCY)?.max(1);
+        let embedding_truncation = match env::var("EMBEDDING_TRUNCATION") {
+            Ok(strategy) => strategy
+                .parse()
+                .with_context(|| format!("Invalid EMBEDDING_TRUNCATION: {}", strategy))?,
+            Err(_) => TruncationStrategy::default(),
+        };
+        let retry = RetryPolicy {
+            max_attempts: env_or("GEMINI_MAX_ATTEMPTS", RetryPolicy::default().max_attempts)?
+                .max(1),
+            ..RetryPolicy::default()
+        };
+        let safety_settings = match env::var("GEMINI_SAFETY_SETTINGS") {
+            Ok(settings) => parse_safety_settings(&settings)
+                .with_context(|| format!("Invalid GEMINI_SAFETY_SETTINGS: {}", settings))?,
+            Err(_) => Vec::new(),
+        };
+        let empty_response_retries = env_or(
+            "GEMINI_EMPTY_RESPONSE_RETRIES",
+            DEFAULT_EMPTY_RESPONSE_RETRIES,
+        )?;
+        let embedding_cache_dir = Some(PathBuf::from(
+            env::var("EMBEDDING_CACHE_DIR")
+                .unwrap_or_else(|_| DEFAULT_EMBEDDING_CACHE_DIR.to_string()),
+        ));
+        let request_timeout = Duration::from_secs(
+            env_or("GEMINI_REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT_SECS)?.max(1),
+        );
+        let context_generation = GenerationParams {
+            temperature: env_or("CONTEXT_TEMPERATURE", CONTEXT_GENERATION.temperature)?,
+            top_p: env_or("CONTEXT_TOP_P", CONTEXT_GENERATION.top_p)?,
+            top_k: env_or("CONTEXT_TOP_K", CONTEXT_GENERATION.top_k)?,
+            max_output_tokens: env_or(
+                "CONTEXT_MAX_OUTPUT_TOKENS",
+                CONTEXT_GENERATION.max_output_tokens,
+            )?,
+        };
 
         Ok(GeminiConfig {
             api_key,
@@ -32,22 +363,271 @@ impl GeminiConfig {
             embedding_model,
             generate_model,
             contextualize_model,
+            rerank_model,
+            embedding_batch_size,
+            embedding_concurrency,
+            fallback_embedding_model,
+            embedding_truncation,
+            context_generation,
+            retry,
+            safety_settings,
+            empty_response_retries,
+            embedding_cache_dir,
+            request_timeout,
         })
     }
 }
 
+/// A fraction between 0 and 1 that varies between calls, for spreading out retries
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    f64::from(nanos % 1000) / 1000.0
+}
+
+/// Parse an environment variable, using `default` when it is not set
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .ok()
+            .with_context(|| format!("Invalid {}: {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Validate that a base URL is an absolute http(s) URL and strip trailing slashes
+fn normalize_base_url(base_url: &str) -> Result<String> {
+    let trimmed = base_url.trim().trim_end_matches('/');
+
+    let url = Url::parse(trimmed)
+        .map_err(|e| anyhow::anyhow!("{} (expected a full URL such as {})", e, DEFAULT_BASE_URL))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(anyhow::anyhow!(
+            "unsupported scheme '{}', expected http or https (e.g. {})",
+            url.scheme(),
+            DEFAULT_BASE_URL
+        ));
+    }
+
+    if url.host_str().is_none() {
+        return Err(anyhow::anyhow!("missing host (e.g. {})", DEFAULT_BASE_URL));
+    }
+
+    Ok(trimmed.to_string())
+}
+
 /// Client for interacting with Gemini API
 #[derive(Clone)]
 pub struct GeminiClient {
     config: GeminiConfig,
     client: reqwest::Client,
+    /// Budget of in-flight requests shared by all clones of this client
+    request_permits: Option<Arc<Semaphore>>,
+    /// Set once the primary embedding model failed, shared by all clones of this client
+    using_fallback_embedding: Arc<AtomicBool>,
+    /// Embeddings computed before, consulted before embedding texts in batches
+    embedding_cache: Option<EmbeddingCache>,
+    /// Tokens billed for the requests of this client and all its clones
+    usage: Arc<Mutex<UsageStats>>,
+}
+
+/// Tokens billed for Gemini requests, as reported in their responses' usage metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UsageStats {
+    /// Tokens of the prompts and embedded texts
+    pub prompt_tokens: u64,
+    /// Tokens generated by the model
+    pub output_tokens: u64,
+}
+
+impl UsageStats {
+    /// Tokens used since `earlier`, a snapshot taken before
+    pub fn since(self, earlier: UsageStats) -> UsageStats {
+        UsageStats {
+            prompt_tokens: self.prompt_tokens.saturating_sub(earlier.prompt_tokens),
+            output_tokens: self.output_tokens.saturating_sub(earlier.output_tokens),
+        }
+    }
+}
+
+impl fmt::Display for UsageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} prompt tokens / {} output tokens",
+            group_digits(self.prompt_tokens),
+            group_digits(self.output_tokens)
+        )
+    }
+}
+
+/// A number with its digits grouped by thousands, such as 1,204,300
+fn group_digits(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
 }
 
 impl GeminiClient {
     /// Create a new Gemini client
     pub fn new(config: GeminiConfig) -> Self {
-        let client = reqwest::Client::new();
-        GeminiClient { config, client }
+        // Requests set their own timeout, so streamed answers are not cut short
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.request_timeout)
+            .build()
+            .expect("Failed to build the HTTP client");
+        let embedding_cache = config.embedding_cache_dir.clone().map(EmbeddingCache::new);
+        GeminiClient {
+            config,
+            client,
+            request_permits: None,
+            using_fallback_embedding: Arc::new(AtomicBool::new(false)),
+            embedding_cache,
+            usage: Arc::new(Mutex::new(UsageStats::default())),
+        }
+    }
+
+    /// Embed every text anew instead of reusing cached embeddings
+    pub fn without_embedding_cache(mut self) -> Self {
+        self.embedding_cache = None;
+        self
+    }
+
+    /// Limit the number of in-flight requests across this client and all its clones
+    pub fn with_request_limit(mut self, max_in_flight: usize) -> Self {
+        self.request_permits = Some(Arc::new(Semaphore::new(max_in_flight.max(1))));
+        self
+    }
+
+    /// Wait for a free request slot when a request limit is set
+    async fn acquire_request_permit(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        match &self.request_permits {
+            Some(permits) => Ok(Some(permits.acquire().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a JSON POST request, retrying rate limited and transient server errors and
+    /// requests that got no response
+    /// Other failures are returned at once for the caller to report, naming `operation`
+    /// when the request timed out
+    /// The request timeout covers reading the response, except for a `streamed` one whose
+    /// pieces are each read within it
+    /// Each attempt takes a request slot of its own, returned with the response to be held
+    /// until it is read, so waiting out a backoff does not hold up other requests
+    async fn post_with_retry<T: Serialize>(
+        &self,
+        url: &str,
+        body: &T,
+        operation: &str,
+        streamed: bool,
+    ) -> Result<(reqwest::Response, Option<SemaphorePermit<'_>>)> {
+        let policy = self.config.retry;
+        let mut attempt = 1;
+
+        loop {
+            let permit = self.acquire_request_permit().await?;
+            let request = self.client.post(url).json(body);
+            // None when a streamed request got no response in time
+            let sent = if streamed {
+                tokio::time::timeout(self.config.request_timeout, request.send())
+                    .await
+                    .ok()
+            } else {
+                Some(request.timeout(self.config.request_timeout).send().await)
+            };
+            let last_attempt = attempt >= policy.max_attempts;
+
+            let (failure, retry_after) = match sent {
+                Some(Ok(response)) => {
+                    let status = response.status();
+                    let retryable = matches!(
+                        status,
+                        StatusCode::TOO_MANY_REQUESTS
+                            | StatusCode::INTERNAL_SERVER_ERROR
+                            | StatusCode::SERVICE_UNAVAILABLE
+                    );
+                    if !retryable || last_attempt {
+                        return Ok((response, permit));
+                    }
+
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    (status.to_string(), retry_after)
+                }
+                // Only a request that could not even be built would fail the same way again
+                Some(Err(e)) if last_attempt || e.is_builder() => {
+                    return Err(self.request_error(operation, e))
+                }
+                Some(Err(e)) if e.is_timeout() => (
+                    format!("timeout after {:?}", self.config.request_timeout),
+                    None,
+                ),
+                Some(Err(e)) => (e.to_string(), None),
+                None if last_attempt => return Err(self.timeout_error(operation)),
+                None => (
+                    format!("timeout after {:?}", self.config.request_timeout),
+                    None,
+                ),
+            };
+            drop(permit);
+
+            let delay = policy.delay(attempt, retry_after, jitter());
+            warn!(
+                "Gemini {} request failed with {} (attempt {}/{}), retrying in {:?}",
+                operation, failure, attempt, policy.max_attempts, delay
+            );
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Tokens billed for the requests of this client and its clones so far
+    pub fn usage(&self) -> UsageStats {
+        *self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Add the tokens a response reports to the client's usage
+    fn record_usage(&self, metadata: Option<&UsageMetadata>) {
+        let Some(metadata) = metadata else {
+            return;
+        };
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        usage.prompt_tokens += metadata.prompt_token_count;
+        usage.output_tokens += metadata.candidates_token_count;
+    }
+
+    /// Error of a failed request, saying which operation timed out when it did
+    fn request_error(&self, operation: &str, error: reqwest::Error) -> anyhow::Error {
+        if error.is_timeout() {
+            self.timeout_error(operation)
+        } else {
+            error.into()
+        }
+    }
+
+    /// Error of an `operation` request that timed out
+    fn timeout_error(&self, operation: &str) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Gemini {} request timed out after {:?}",
+            operation,
+            self.config.request_timeout
+        )
     }
 
     /// Get the client configuration
@@ -55,8 +635,140 @@ impl GeminiClient {
         &self.config
     }
 
-    /// Generate embeddings for a text
+    /// Embedding model in use: the fallback once the primary model has failed
+    fn embedding_model(&self) -> &str {
+        match &self.config.fallback_embedding_model {
+            Some(fallback) if self.using_fallback_embedding.load(Ordering::Relaxed) => fallback,
+            _ => &self.config.embedding_model,
+        }
+    }
+
+    /// Switch to the fallback embedding model when the primary model itself failed
+    /// Returns whether the failed request should be retried
+    fn switch_to_fallback_embedding(&self, error: &anyhow::Error) -> bool {
+        let Some(fallback) = &self.config.fallback_embedding_model else {
+            return false;
+        };
+        let Some(api_error) = error.downcast_ref::<ApiError>() else {
+            return false;
+        };
+        if api_error.model != self.config.embedding_model || !api_error.is_model_error() {
+            return false;
+        }
+
+        // Only the first failure logs; requests already in flight just retry on the fallback
+        if !self.using_fallback_embedding.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Embedding model {} failed ({}), switching to fallback model {}",
+                self.config.embedding_model, error, fallback
+            );
+        }
+
+        true
+    }
+
+    /// Length of the vectors produced by the embedding model in use, found by embedding a probe
+    pub async fn embedding_dimension(&self) -> Result<usize> {
+        let embedding = self
+            .embed_text("dimension check", Some(TaskType::RetrievalDocument))
+            .await
+            .context("Failed to probe the embedding dimension")?;
+
+        Ok(embedding.values.len())
+    }
+
+    /// Length of the vectors produced by the fallback embedding model, when one is set
+    pub async fn fallback_embedding_dimension(&self) -> Result<Option<usize>> {
+        let Some(fallback) = &self.config.fallback_embedding_model else {
+            return Ok(None);
+        };
+
+        let embedding = self
+            .request_embedding("dimension check", fallback, None)
+            .await
+            .with_context(|| format!("Fallback embedding model {} failed", fallback))?;
+
+        Ok(Some(embedding.values.len()))
+    }
+
+    /// Generate embeddings for a text, moving to the fallback model if the primary one fails
     pub async fn get_embedding(&self, text: &str) -> Result<Embedding> {
+        self.embed_text(text, None).await
+    }
+
+    /// Generate embeddings for a chunk being indexed
+    pub async fn get_document_embedding(&self, text: &str) -> Result<Embedding> {
+        self.embed_text(text, Some(TaskType::RetrievalDocument))
+            .await
+    }
+
+    /// Generate embeddings for a question searched against indexed documents
+    pub async fn get_query_embedding(&self, text: &str) -> Result<Embedding> {
+        self.embed_text(text, Some(TaskType::RetrievalQuery)).await
+    }
+
+    /// Model and task type chunks are embedded with now
+    pub fn document_embedding_profile(&self) -> EmbeddingProfile {
+        EmbeddingProfile {
+            model: self.embedding_model().to_string(),
+            task_type: Some(TaskType::RetrievalDocument),
+        }
+    }
+
+    /// Generate embeddings for a text stored alongside chunks embedded as `profile` describes
+    /// The profile's model is used as it is, without moving to the fallback model
+    pub async fn get_document_embedding_like(
+        &self,
+        text: &str,
+        profile: &EmbeddingProfile,
+    ) -> Result<Embedding> {
+        self.request_embedding(text, &profile.model, profile.task_type)
+            .await
+    }
+
+    /// Generate embeddings for questions searched against chunks embedded as `profile`
+    /// describes, in the order of the texts
+    pub async fn get_query_embeddings_like(
+        &self,
+        texts: &[&str],
+        profile: &EmbeddingProfile,
+    ) -> Result<Vec<Embedding>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+
+        for batch in texts.chunks(self.config.embedding_batch_size.max(1)) {
+            embeddings.extend(
+                self.request_embedding_batch(batch, &profile.model, profile.query_task_type())
+                    .await?,
+            );
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Generate embeddings for a text for the given task, moving to the fallback model if the
+    /// primary one fails
+    async fn embed_text(&self, text: &str, task_type: Option<TaskType>) -> Result<Embedding> {
+        match self
+            .request_embedding(text, self.embedding_model(), task_type)
+            .await
+        {
+            Err(e) if self.switch_to_fallback_embedding(&e) => {
+                self.request_embedding(text, self.embedding_model(), task_type)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Generate embeddings for a text with the given model
+    async fn request_embedding(
+        &self,
+        text: &str,
+        model: &str,
+        task_type: Option<TaskType>,
+    ) -> Result<Embedding> {
+        let text = self.clip_embedding_input(text);
+
         #[derive(Serialize)]
         struct EmbeddingContent<'a> {
             parts: Vec<Part<'a>>,
@@ -66,69 +778,407 @@ impl GeminiClient {
         struct EmbeddingRequest<'a> {
             model: &'a str,
             content: EmbeddingContent<'a>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            task_type: Option<TaskType>,
         }
 
         let request = EmbeddingRequest {
-            model: &self.config.embedding_model,
+            model,
             content: EmbeddingContent {
-                parts: vec![Part { text }],
+                parts: vec![Part { text: &text }],
             },
+            task_type,
         };
 
-        let url = format!("{}/{}:embedContent?key={}", 
-            self.config.base_url, 
-            self.config.embedding_model,
-            self.config.api_key);
+        let url = format!(
+            "{}/{}:embedContent?key={}",
+            self.config.base_url, model, self.config.api_key
+        );
 
-        let response = self.client.post(&url).json(&request).send().await?;
+        let (response, _permit) = self
+            .post_with_retry(&url, &request, "embedding", false)
+            .await?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow::anyhow!(
-                "API request failed: {} {}",
-                status,
-                error_text
-            ));
+            return Err(ApiError::from_response(response, model).await.into());
         }
 
-        let response_data: EmbeddingResponse = response.json().await?;
+        let response_data: EmbeddingResponse = response
+            .json()
+            .await
+            .map_err(|e| self.request_error("embedding", e))?;
+        self.record_usage(response_data.usage_metadata.as_ref());
 
         Ok(Embedding {
             values: response_data.embedding.values,
         })
     }
 
-    /// Generate text using Gemini model
+    /// Clip a text to the embedding input limit with the configured truncation strategy
+    fn clip_embedding_input<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let clipped = truncate_to_tokens(
+            text,
+            MAX_EMBEDDING_INPUT_TOKENS,
+            self.config.embedding_truncation,
+        );
+        if let Cow::Owned(_) = clipped {
+            debug!(
+                "Truncated a {}-token embedding input ({:?})",
+                estimate_token_count(text),
+                self.config.embedding_truncation
+            );
+        }
+        clipped
+    }
+
+    /// Generate embeddings for many texts with batch requests
+    /// Embeddings are returned in the order of the texts
+    pub async fn get_embeddings(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        self.embed_texts(texts, None).await
+    }
+
+    /// Generate embeddings for chunks being indexed, in the order of the texts
+    pub async fn get_document_embeddings(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        self.embed_texts(texts, Some(TaskType::RetrievalDocument))
+            .await
+    }
+
+    /// Generate embeddings for questions searched against indexed documents, in the order of
+    /// the texts
+    pub async fn get_query_embeddings(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        self.embed_texts(texts, Some(TaskType::RetrievalQuery))
+            .await
+    }
+
+    /// Generate embeddings for many texts for the given task with batch requests, up to
+    /// `embedding_concurrency` of them at once
+    /// Texts embedded before with the same model and task type are read from the cache
+    async fn embed_texts(
+        &self,
+        texts: &[&str],
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<Embedding>> {
+        let cache_key = |text: &str| EmbeddingCache::key(self.embedding_model(), task_type, text);
+        let mut embeddings: Vec<Option<Embedding>> = match &self.embedding_cache {
+            Some(cache) => texts
+                .iter()
+                .map(|text| cache.get(&cache_key(text)))
+                .collect(),
+            None => vec![None; texts.len()],
+        };
+
+        let missing: Vec<usize> = (0..texts.len())
+            .filter(|&i| embeddings[i].is_none())
+            .collect();
+        if self.embedding_cache.is_some() {
+            debug!(
+                "{} of {} embeddings found in the cache",
+                texts.len() - missing.len(),
+                texts.len()
+            );
+        }
+
+        let batches =
+            missing
+                .chunks(self.config.embedding_batch_size.max(1))
+                .map(|batch| async move {
+                    let batch_texts: Vec<&str> = batch.iter().map(|&i| texts[i]).collect();
+                    let batch_embeddings =
+                        self.get_embedding_batch(&batch_texts, task_type).await?;
+                    Ok::<_, anyhow::Error>((batch, batch_embeddings))
+                });
+        let mut batches =
+            stream::iter(batches).buffer_unordered(self.config.embedding_concurrency.max(1));
+
+        // Batches finish in any order; each embedding goes back to the position of its text
+        while let Some(result) = batches.next().await {
+            let (batch, batch_embeddings) = result?;
+            for (&i, embedding) in batch.iter().zip(batch_embeddings) {
+                if let Some(cache) = &self.embedding_cache {
+                    // A cache that cannot be written only costs the saving, not the ingest
+                    if let Err(e) = cache.insert(&cache_key(texts[i]), &embedding) {
+                        warn!("Failed to cache an embedding: {:#}", e);
+                    }
+                }
+                embeddings[i] = Some(embedding);
+            }
+        }
+
+        embeddings
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .context("Missing embeddings for some texts")
+    }
+
+    /// Generate embeddings for one batch of texts, moving to the fallback model if needed
+    async fn get_embedding_batch(
+        &self,
+        texts: &[&str],
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<Embedding>> {
+        match self
+            .request_embedding_batch(texts, self.embedding_model(), task_type)
+            .await
+        {
+            Err(e) if self.switch_to_fallback_embedding(&e) => {
+                self.request_embedding_batch(texts, self.embedding_model(), task_type)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Generate embeddings for one batch of texts in a single request with the given model
+    async fn request_embedding_batch(
+        &self,
+        texts: &[&str],
+        model: &str,
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<Embedding>> {
+        #[derive(Serialize)]
+        struct EmbeddingContent<'a> {
+            parts: Vec<Part<'a>>,
+        }
+
+        #[derive(Serialize)]
+        struct EmbeddingRequest<'a> {
+            model: &'a str,
+            content: EmbeddingContent<'a>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            task_type: Option<TaskType>,
+        }
+
+        #[derive(Serialize)]
+        struct BatchEmbeddingRequest<'a> {
+            requests: Vec<EmbeddingRequest<'a>>,
+        }
+
+        let clipped_texts: Vec<Cow<str>> = texts
+            .iter()
+            .map(|text| self.clip_embedding_input(text))
+            .collect();
+        let request = BatchEmbeddingRequest {
+            requests: clipped_texts
+                .iter()
+                .map(|text| EmbeddingRequest {
+                    model,
+                    content: EmbeddingContent {
+                        parts: vec![Part { text }],
+                    },
+                    task_type,
+                })
+                .collect(),
+        };
+
+        let url = format!(
+            "{}/{}:batchEmbedContents?key={}",
+            self.config.base_url, model, self.config.api_key
+        );
+
+        let (response, _permit) = self
+            .post_with_retry(&url, &request, "embedding", false)
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(ApiError::from_response(response, model).await.into());
+        }
+
+        let response_data: BatchEmbeddingResponse = response
+            .json()
+            .await
+            .map_err(|e| self.request_error("embedding", e))?;
+        self.record_usage(response_data.usage_metadata.as_ref());
+
+        // Embeddings are paired with texts by position, so a short response cannot be used
+        if response_data.embeddings.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "Batch embedding returned {} embeddings for {} texts",
+                response_data.embeddings.len(),
+                texts.len()
+            ));
+        }
+
+        Ok(response_data
+            .embeddings
+            .into_iter()
+            .map(|embedding| Embedding {
+                values: embedding.values,
+            })
+            .collect())
+    }
+
+    /// Generate text using Gemini model, optionally steered by a system instruction
+    /// A response without text, such as one a safety filter emptied, is retried up to
+    /// `empty_response_retries` times at a slightly higher temperature; a blocked prompt and
+    /// failed requests are not
+    #[allow(clippy::too_many_arguments)]
     pub async fn generate_text(
         &self,
         prompt: &str,
+        system_instruction: Option<&str>,
         model: &str,
         temperature: f32,
         top_p: f32,
         top_k: i32,
         max_output_tokens: i32,
     ) -> Result<String> {
+        let params = GenerationParams {
+            temperature,
+            top_p,
+            top_k,
+            max_output_tokens,
+        };
+        let result = self
+            .generate(prompt, system_instruction, model, params)
+            .await?;
+        Ok(result.text)
+    }
+
+    /// Generate text like `generate_text`, also returning why the model stopped
+    pub async fn generate(
+        &self,
+        prompt: &str,
+        system_instruction: Option<&str>,
+        model: &str,
+        params: GenerationParams,
+    ) -> Result<GenerationResult> {
+        self.generate_after(&[], prompt, system_instruction, model, params)
+            .await
+    }
+
+    /// Generate text like `generate`, sending the earlier turns of a conversation before the
+    /// prompt
+    async fn generate_after(
+        &self,
+        history: &[ConversationTurn],
+        prompt: &str,
+        system_instruction: Option<&str>,
+        model: &str,
+        params: GenerationParams,
+    ) -> Result<GenerationResult> {
+        let url = format!(
+            "{}/{}:generateContent?key={}",
+            self.config.base_url,
+            model, // Use the model parameter
+            self.config.api_key
+        );
+
+        let mut attempt = 0;
+        loop {
+            let request = GenerateRequest {
+                model,
+                contents: conversation_contents(history, prompt),
+                system_instruction: system_instruction.map(|text| SystemInstruction {
+                    parts: vec![Part { text }],
+                }),
+                generation_config: GenerationConfig {
+                    // Resampling at the same temperature tends to come back empty again
+                    temperature: (params.temperature
+                        + EMPTY_RETRY_TEMPERATURE_STEP * attempt as f32)
+                        .min(1.0)
+                        .max(params.temperature),
+                    top_p: params.top_p,
+                    top_k: params.top_k,
+                    max_output_tokens: params.max_output_tokens,
+                },
+                safety_settings: &self.config.safety_settings,
+            };
+
+            let (response, _permit) = self
+                .post_with_retry(&url, &request, "generate", false)
+                .await?;
+
+            if !response.status().is_success() {
+                let error_text = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown error".to_string());
+                return Err(anyhow::anyhow!("API request failed: {}", error_text));
+            }
+
+            let response_data: GenerateResponse = response
+                .json()
+                .await
+                .map_err(|e| self.request_error("generate", e))?;
+            self.record_usage(response_data.usage_metadata.as_ref());
+            match response_data.into_result() {
+                Err(e)
+                    if e.is::<EmptyResponse>() && attempt < self.config.empty_response_retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "{}, retrying ({}/{})",
+                        e, attempt, self.config.empty_response_retries
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Generate a response based on context and question
+    /// Uses `generate_model` (Gemini 2.5 Flash Preview 05-20 by default) unless `model` overrides it
+    /// Earlier turns of the conversation in `history` let the question refer back to them
+    pub async fn generate_answer(
+        &self,
+        context: &str,
+        question: &str,
+        model: Option<&str>,
+        system_instruction: Option<&str>,
+        history: &[ConversationTurn],
+    ) -> Result<String> {
+        let prompt = answer_prompt(context, question);
+
+        let result = self
+            .generate_after(
+                history,
+                &prompt,
+                system_instruction,
+                model.unwrap_or(&self.config.generate_model),
+                ANSWER_GENERATION,
+            )
+            .await?;
+        warn_if_cut_off(result.finish_reason.as_deref());
+
+        Ok(result.text)
+    }
+
+    /// Generate an answer like `generate_answer`, yielding its text piece by piece as the
+    /// model writes it
+    /// Only the request itself is retried; an empty answer ends the stream without text
+    pub async fn generate_answer_stream(
+        &self,
+        context: &str,
+        question: &str,
+        model: Option<&str>,
+        system_instruction: Option<&str>,
+        history: &[ConversationTurn],
+    ) -> Result<impl Stream<Item = Result<String>> + '_> {
+        let model = model.unwrap_or(&self.config.generate_model);
+        let prompt = answer_prompt(context, question);
         let request = GenerateRequest {
             model,
-            contents: vec![Content::new_with_role(prompt, "user")],
+            contents: conversation_contents(history, &prompt),
+            system_instruction: system_instruction.map(|text| SystemInstruction {
+                parts: vec![Part { text }],
+            }),
             generation_config: GenerationConfig {
-                temperature,
-                top_p,
-                top_k,
-                max_output_tokens,
+                temperature: ANSWER_GENERATION.temperature,
+                top_p: ANSWER_GENERATION.top_p,
+                top_k: ANSWER_GENERATION.top_k,
+                max_output_tokens: ANSWER_GENERATION.max_output_tokens,
             },
+            safety_settings: &self.config.safety_settings,
         };
 
-        let url = format!("{}/{}:generateContent?key={}", 
-            self.config.base_url, 
-            model, // Use the model parameter
-            self.config.api_key);
+        let url = format!(
+            "{}/{}:streamGenerateContent?alt=sse&key={}",
+            self.config.base_url, model, self.config.api_key
+        );
 
-        let response = self.client.post(&url).json(&request).send().await?;
+        let (response, permit) = self
+            .post_with_retry(&url, &request, "generate", true)
+            .await?;
 
         if !response.status().is_success() {
             let error_text = response
@@ -138,61 +1188,427 @@ impl GeminiClient {
             return Err(anyhow::anyhow!("API request failed: {}", error_text));
         }
 
-        let response_data: GenerateResponse = response.json().await?;
+        // Server-sent events arrive in arbitrary pieces; each complete one holds a response
+        let state = AnswerStream {
+            response,
+            buffer: Vec::new(),
+            deltas: VecDeque::new(),
+            done: false,
+            usage: None,
+            _permit: permit,
+        };
+        Ok(stream::unfold(state, move |mut state| async move {
+            loop {
+                if let Some(delta) = state.deltas.pop_front() {
+                    return Some((delta, state));
+                }
+                if state.done {
+                    self.record_usage(state.usage.take().as_ref());
+                    return None;
+                }
+
+                // A stalled stream fails like a hung request, however long the answer runs
+                let chunk =
+                    tokio::time::timeout(self.config.request_timeout, state.response.chunk())
+                        .await
+                        .map_err(|_| self.timeout_error("generate"))
+                        .and_then(|chunk| chunk.map_err(|e| self.request_error("generate", e)));
+                match chunk {
+                    Ok(Some(bytes)) => state.buffer.extend(bytes.iter().filter(|&&b| b != b'\r')),
+                    Ok(None) => {
+                        state.done = true;
+                        state.buffer.extend_from_slice(b"\n\n");
+                    }
+                    Err(e) => {
+                        state.done = true;
+                        return Some((Err(e), state));
+                    }
+                }
 
-        // Extract the generated text from the response
-        response_data
-            .candidates
-            .into_iter()
-            .next()
-            .and_then(|c| c.content.parts.into_iter().next())
-            .map(|p| p.text)
-            .ok_or_else(|| anyhow::anyhow!("No response generated"))
+                for data in take_sse_events(&mut state.buffer) {
+                    match serde_json::from_str::<GenerateResponse>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|mut response| {
+                            // Every piece reports the usage so far, so only the last one counts
+                            if let Some(usage) = response.usage_metadata.take() {
+                                state.usage = Some(usage);
+                            }
+                            response.into_delta()
+                        }) {
+                        Ok((text, finish_reason)) => {
+                            warn_if_cut_off(finish_reason.as_deref());
+                            if !text.is_empty() {
+                                state.deltas.push_back(Ok(text));
+                            }
+                        }
+                        Err(e) => {
+                            state.deltas.push_back(Err(e));
+                            state.done = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }))
     }
 
-    /// Generate a response based on context and question
-    /// Uses Gemini 2.5 Flash Preview 05-20 by default for question answering
-    pub async fn generate_answer(&self, context: &str, question: &str) -> Result<String> {
-        let prompt = format!("Context: {}\n\nQuestion: {}", context, question);
+    /// Ask the model whether a context contains the answer to a question, without answering it
+    pub async fn context_contains_answer(
+        &self,
+        context: &str,
+        question: &str,
+        model: Option<&str>,
+    ) -> Result<bool> {
+        let prompt = sufficiency_prompt(context, question);
+
+        let verdict = self
+            .generate_text(
+                &prompt,
+                None,
+                model.unwrap_or(&self.config.generate_model),
+                0.0,
+                1.0,
+                1,
+                8, // A single yes or no
+            )
+            .await?;
+
+        Ok(verdict.trim().to_lowercase().starts_with("yes"))
+    }
+
+    /// Rephrase a question `count` different ways with the context model, for retrieval
+    pub async fn paraphrase_question(&self, question: &str, count: usize) -> Result<Vec<String>> {
+        let prompt = paraphrase_prompt(question, count);
+
+        let response = self
+            .generate_text(
+                &prompt,
+                None,
+                &self.config.contextualize_model,
+                0.7, // Varied wording is the point
+                0.95,
+                40,
+                256,
+            )
+            .await?;
+
+        Ok(parse_paraphrases(&response, count))
+    }
+
+    /// Rewrite a follow-up question into one that can be understood without the conversation,
+    /// to search for its answer; the question itself is kept when the model returns nothing
+    pub async fn standalone_question(
+        &self,
+        history: &[ConversationTurn],
+        question: &str,
+    ) -> Result<String> {
+        let prompt = standalone_question_prompt(history, question);
+
+        let response = self
+            .generate_text(
+                &prompt,
+                None,
+                &self.config.contextualize_model,
+                0.0, // The same conversation should search the same way
+                0.95,
+                40,
+                256,
+            )
+            .await?;
+
+        let rewritten = response.trim();
+        Ok(if rewritten.is_empty() {
+            question.to_string()
+        } else {
+            rewritten.to_string()
+        })
+    }
+
+    /// Write a short passage that could answer a question, to search by in place of the
+    /// question (HyDE)
+    pub async fn hypothetical_answer(&self, question: &str) -> Result<String> {
+        let prompt = hypothetical_answer_prompt(question);
 
         self.generate_text(
             &prompt,
-            &self.config.generate_model,
+            None,
+            &self.config.contextualize_model,
             0.2,
             0.8,
             40,
-            1024,
+            256, // A paragraph is enough to search by
         )
         .await
     }
 
+    /// Order passages by relevance to a question with the rerank model
+    /// Returns passage indices, most relevant first; passages the model leaves out keep their
+    /// order at the end
+    pub async fn rerank(&self, question: &str, passages: &[&str]) -> Result<Vec<usize>> {
+        let prompt = rerank_prompt(question, passages);
+
+        let response = self
+            .generate_text(
+                &prompt,
+                None,
+                &self.config.rerank_model,
+                0.0,
+                1.0,
+                1,
+                64, // A short list of numbers
+            )
+            .await?;
+
+        Ok(parse_rerank_order(&response, passages.len()))
+    }
+
     /// Generate context using Gemini 2.0 Flash-Lite model specifically for summarization
     pub async fn generate_context(&self, prompt: &str) -> Result<String> {
+        let params = self.config.context_generation;
         self.generate_text(
             prompt,
+            None,
             &self.config.contextualize_model,
-            0.2,
-            0.8,
-            40,
-            512, // Shorter output for context generation
+            params.temperature,
+            params.top_p,
+            params.top_k,
+            params.max_output_tokens,
         )
         .await
     }
 }
 
+/// Failed API response, kept typed so callers can tell model errors from others
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    model: String,
+    message: String,
+}
+
+impl ApiError {
+    /// Read the error body of a failed response
+    async fn from_response(response: reqwest::Response, model: &str) -> Self {
+        let status = response.status();
+        let message = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+
+        ApiError {
+            status,
+            model: model.to_string(),
+            message,
+        }
+    }
+
+    /// Whether the error means the requested model is unknown or unusable
+    fn is_model_error(&self) -> bool {
+        self.status == StatusCode::NOT_FOUND
+            || (self.status == StatusCode::BAD_REQUEST
+                && self.message.to_lowercase().contains("model"))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "API request failed for {}: {} {}",
+            self.model, self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Build the prompt sent to the model for answering a question from context
+/// The context passages are numbered, and the model is asked to cite them inline
+pub fn answer_prompt(context: &str, question: &str) -> String {
+    format!(
+        "Context: {}\n\nQuestion: {}\n\nCite the numbered context passages that support each sentence of the answer inline, such as [1] or [2][3].",
+        context, question
+    )
+}
+
+/// Build the prompt asking the model to make a follow-up question self-contained
+pub fn standalone_question_prompt(history: &[ConversationTurn], question: &str) -> String {
+    let conversation: String = history
+        .iter()
+        .map(|turn| format!("User: {}\nAssistant: {}\n\n", turn.question, turn.answer))
+        .collect();
+    format!(
+        "{}Follow-up question: {}\n\nRewrite the follow-up question so it can be understood without the conversation above, replacing pronouns and references with what they refer to. Reply with only the rewritten question.",
+        conversation, question
+    )
+}
+
+/// Build the prompt asking the model whether a context answers a question
+pub fn sufficiency_prompt(context: &str, question: &str) -> String {
+    format!(
+        "Context: {}\n\nQuestion: {}\n\nDoes the context contain the information needed to answer the question? Reply with only yes or no.",
+        context, question
+    )
+}
+
+/// Shorten a text to about `max_tokens` by dropping whole words from the part `strategy`
+/// names; texts within the limit are returned as they are
+fn truncate_to_tokens(text: &str, max_tokens: usize, strategy: TruncationStrategy) -> Cow<'_, str> {
+    if estimate_token_count(text) <= max_tokens {
+        return Cow::Borrowed(text);
+    }
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let kept = match strategy {
+        TruncationStrategy::Tail => {
+            let start = words_within(words.iter().copied(), max_tokens);
+            words[..start].join(" ")
+        }
+        TruncationStrategy::Head => {
+            let end = words_within(words.iter().rev().copied(), max_tokens);
+            words[words.len() - end..].join(" ")
+        }
+        TruncationStrategy::Middle => {
+            let start = words_within(words.iter().copied(), max_tokens / 2);
+            let end = words_within(words.iter().rev().copied(), max_tokens - max_tokens / 2);
+            format!(
+                "{} {}",
+                words[..start].join(" "),
+                words[words.len() - end..].join(" ")
+            )
+        }
+    };
+    Cow::Owned(kept)
+}
+
+/// Number of words, taken in order, that fit within `budget` tokens
+fn words_within<'a>(words: impl Iterator<Item = &'a str>, budget: usize) -> usize {
+    let mut spent = 0;
+    words
+        .take_while(|word| {
+            spent += estimate_token_count(word);
+            spent <= budget
+        })
+        .count()
+}
+
+/// Build the prompt asking the model for alternative phrasings of a question
+pub fn paraphrase_prompt(question: &str, count: usize) -> String {
+    format!(
+        "Rewrite the following question {} different ways, keeping its meaning but varying the wording. Reply with one rewritten question per line and nothing else.\n\nQuestion: {}",
+        count, question
+    )
+}
+
+/// Read up to `count` paraphrases from the model's reply, one per line, dropping any list
+/// numbering or bullets
+fn parse_paraphrases(response: &str, count: usize) -> Vec<String> {
+    response
+        .lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .trim_start_matches(['.', ')', '-', '*'])
+                .trim()
+        })
+        .filter(|line| !line.is_empty())
+        .take(count)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Build the prompt asking the model for a passage that answers a question
+pub fn hypothetical_answer_prompt(question: &str) -> String {
+    format!(
+        "Write a short passage, as it might appear in a document, that answers the following question. Reply with only the passage.\n\nQuestion: {}",
+        question
+    )
+}
+
+/// Build the prompt asking the model to order numbered passages by relevance to a question
+pub fn rerank_prompt(question: &str, passages: &[&str]) -> String {
+    let mut prompt = String::new();
+    for (i, passage) in passages.iter().enumerate() {
+        prompt.push_str(&format!("[{}] {}\n\n", i + 1, passage));
+    }
+    prompt.push_str(&format!(
+        "Question: {}\n\nOrder the passages above from most to least relevant to the question. Reply with only their numbers, separated by commas.",
+        question
+    ));
+    prompt
+}
+
+/// Read the passage order from the model's reply as 0-based indices, ignoring numbers out of
+/// range or repeated and appending passages the reply left out
+fn parse_rerank_order(response: &str, count: usize) -> Vec<usize> {
+    let mut order = Vec::with_capacity(count);
+    for number in response
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|number| number.parse::<usize>().ok())
+    {
+        if (1..=count).contains(&number) && !order.contains(&(number - 1)) {
+            order.push(number - 1);
+        }
+    }
+    for i in 0..count {
+        if !order.contains(&i) {
+            order.push(i);
+        }
+    }
+    order
+}
+
 /// Representation of a vector embedding
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Embedding {
     pub values: Vec<f32>,
 }
 
+impl Embedding {
+    /// Euclidean (L2) length of the vector
+    pub fn norm(&self) -> f32 {
+        self.values.iter().map(|v| v * v).sum::<f32>().sqrt()
+    }
+
+    /// The vector scaled to unit (L2) length; an all-zero vector is returned unchanged
+    pub fn normalized(&self) -> Embedding {
+        let norm = self.norm();
+        if norm == 0.0 {
+            return self.clone();
+        }
+        Embedding {
+            values: self.values.iter().map(|v| v / norm).collect(),
+        }
+    }
+}
+
 // Shared request/response structures for the Gemini API
 
 // EmbeddingRequest struct is defined inline in get_embedding method
 
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 struct EmbeddingResponse {
     embedding: EmbeddingData,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BatchEmbeddingResponse {
+    embeddings: Vec<EmbeddingData>,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+/// Tokens a response reports it was billed for
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u64,
+    // Left out by embedding responses
+    #[serde(default)]
+    candidates_token_count: u64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -204,7 +1620,16 @@ struct EmbeddingData {
 struct GenerateRequest<'a> {
     model: &'a str,
     contents: Vec<Content<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<SystemInstruction<'a>>,
     generation_config: GenerationConfig,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    safety_settings: &'a [SafetySetting],
+}
+
+#[derive(Serialize)]
+struct SystemInstruction<'a> {
+    parts: Vec<Part<'a>>,
 }
 
 #[derive(Serialize)]
@@ -222,6 +1647,25 @@ impl<'a> Content<'a> {
     }
 }
 
+/// A question asked earlier in a conversation and the answer it got
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationTurn {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Contents of a request: the earlier turns as alternating user and model messages, then
+/// the prompt
+fn conversation_contents<'a>(history: &'a [ConversationTurn], prompt: &'a str) -> Vec<Content<'a>> {
+    let mut contents = Vec::with_capacity(history.len() * 2 + 1);
+    for turn in history {
+        contents.push(Content::new_with_role(&turn.question, "user"));
+        contents.push(Content::new_with_role(&turn.answer, "model"));
+    }
+    contents.push(Content::new_with_role(prompt, "user"));
+    contents
+}
+
 #[derive(Serialize)]
 struct Part<'a> {
     text: &'a str,
@@ -236,17 +1680,173 @@ struct GenerationConfig {
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 struct GenerateResponse {
+    // Left out when the prompt itself was blocked
+    #[serde(default)]
     candidates: Vec<Candidate>,
+    prompt_feedback: Option<PromptFeedback>,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+impl GenerateResponse {
+    /// Fail when the prompt itself was blocked, naming the reason
+    fn check_blocked(&self) -> Result<()> {
+        match self.prompt_feedback.as_ref().and_then(|f| f.block_reason.as_ref()) {
+            Some(block_reason) => Err(anyhow::anyhow!(
+                "Prompt blocked by Gemini (block reason: {}); adjust GEMINI_SAFETY_SETTINGS if it is legitimate",
+                block_reason
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Text of the first candidate and why it ended, or an error naming why the response was
+    /// blocked
+    fn into_result(self) -> Result<GenerationResult> {
+        self.check_blocked()?;
+
+        let Some(candidate) = self.candidates.into_iter().next() else {
+            return Err(EmptyResponse {
+                finish_reason: None,
+            }
+            .into());
+        };
+        match candidate
+            .content
+            .and_then(|content| content.parts.into_iter().next())
+        {
+            Some(part) => Ok(GenerationResult {
+                text: part.text,
+                finish_reason: candidate.finish_reason,
+            }),
+            None => Err(EmptyResponse {
+                finish_reason: Some(
+                    candidate
+                        .finish_reason
+                        .unwrap_or_else(|| "unknown".to_string()),
+                ),
+            }
+            .into()),
+        }
+    }
+
+    /// Text of one piece of a streamed response, possibly empty, and the finish reason the
+    /// last piece carries
+    fn into_delta(self) -> Result<(String, Option<String>)> {
+        self.check_blocked()?;
+
+        let Some(candidate) = self.candidates.into_iter().next() else {
+            return Ok((String::new(), None));
+        };
+        let text = candidate
+            .content
+            .map(|content| {
+                content
+                    .parts
+                    .into_iter()
+                    .map(|part| part.text)
+                    .collect::<String>()
+            })
+            .unwrap_or_default();
+
+        Ok((text, candidate.finish_reason))
+    }
+}
+
+/// Text generated by the model
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationResult {
+    pub text: String,
+    /// Why the model stopped, such as STOP, MAX_TOKENS or SAFETY
+    pub finish_reason: Option<String>,
+}
+
+/// Progress of reading a streamed answer
+struct AnswerStream<'a> {
+    response: reqwest::Response,
+    /// Received bytes not yet forming a complete event
+    buffer: Vec<u8>,
+    /// Parsed pieces of text (or the error ending the stream) not yet yielded
+    deltas: VecDeque<Result<String>>,
+    done: bool,
+    /// Usage reported by the latest piece, recorded once the stream ends
+    usage: Option<UsageMetadata>,
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+/// Remove the complete server-sent events from the start of `buffer`, returning their data
+/// Events end with a blank line; data split over several lines is joined with newlines
+fn take_sse_events(buffer: &mut Vec<u8>) -> Vec<String> {
+    let mut events = Vec::new();
+
+    while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+        let event: Vec<u8> = buffer.drain(..end + 2).collect();
+        let event = String::from_utf8_lossy(&event);
+        let data: Vec<&str> = event
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.strip_prefix(' ').unwrap_or(data))
+            .collect();
+        if !data.is_empty() {
+            events.push(data.join("\n"));
+        }
+    }
+
+    events
+}
+
+/// Warn when an answer ended because it hit the output limit or a safety filter, since it
+/// otherwise reads like a complete one
+fn warn_if_cut_off(finish_reason: Option<&str>) {
+    match finish_reason {
+        Some("MAX_TOKENS") => warn!(
+            "The answer was cut off at the {}-token output limit",
+            ANSWER_GENERATION.max_output_tokens
+        ),
+        Some("SAFETY") => warn!("The answer was cut short by a safety filter"),
+        _ => {}
+    }
+}
+
+/// A generation came back without text, which a new attempt may not
+#[derive(Debug)]
+struct EmptyResponse {
+    finish_reason: Option<String>,
+}
+
+impl fmt::Display for EmptyResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.finish_reason {
+            Some(finish_reason) => write!(
+                f,
+                "No response generated (finish reason: {})",
+                finish_reason
+            ),
+            None => write!(f, "No response generated"),
+        }
+    }
+}
+
+impl std::error::Error for EmptyResponse {}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 struct Candidate {
-    content: ResponseContent,
+    // Left out when the candidate was blocked
+    content: Option<ResponseContent>,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct ResponseContent {
+    #[serde(default)]
     parts: Vec<ResponsePart>,
 }
 
@@ -254,3 +1854,709 @@ struct ResponseContent {
 struct ResponsePart {
     text: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Configuration pointing at a local mock server
+    fn test_config(base_url: String) -> GeminiConfig {
+        GeminiConfig {
+            api_key: "test-key".to_string(),
+            base_url,
+            embedding_model: "models/text-embedding-004".to_string(),
+            generate_model: "models/generate".to_string(),
+            contextualize_model: "models/contextualize".to_string(),
+            rerank_model: "models/rerank".to_string(),
+            embedding_batch_size: MAX_EMBEDDING_BATCH_SIZE,
+            embedding_concurrency: DEFAULT_EMBEDDING_CONCURRENCY,
+            fallback_embedding_model: None,
+            embedding_truncation: TruncationStrategy::default(),
+            context_generation: CONTEXT_GENERATION,
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            },
+            safety_settings: Vec::new(),
+            empty_response_retries: DEFAULT_EMPTY_RESPONSE_RETRIES,
+            embedding_cache_dir: None,
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+        }
+    }
+
+    #[test]
+    fn test_embedding_profile() {
+        let profile = EmbeddingProfile {
+            model: "models/text-embedding-004".to_string(),
+            task_type: Some("retrieval_document".parse().unwrap()),
+        };
+        assert_eq!(profile.task_type, Some(TaskType::RetrievalDocument));
+        assert_eq!(profile.query_task_type(), Some(TaskType::RetrievalQuery));
+        assert_eq!(TaskType::RetrievalQuery.as_str(), "RETRIEVAL_QUERY");
+
+        // Chunks embedded without a task type are searched without one
+        let untyped = EmbeddingProfile {
+            task_type: None,
+            ..profile
+        };
+        assert_eq!(untyped.query_task_type(), None);
+    }
+
+    #[test]
+    fn test_safety_settings() {
+        let settings =
+            parse_safety_settings("dangerous_content=block_none, HARASSMENT=BLOCK_ONLY_HIGH")
+                .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                SafetySetting {
+                    category: HarmCategory::DangerousContent,
+                    threshold: HarmBlockThreshold::BlockNone,
+                },
+                SafetySetting {
+                    category: HarmCategory::Harassment,
+                    threshold: HarmBlockThreshold::BlockOnlyHigh,
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(settings[0]).unwrap(),
+            serde_json::json!({
+                "category": "HARM_CATEGORY_DANGEROUS_CONTENT",
+                "threshold": "BLOCK_NONE",
+            })
+        );
+        assert!(parse_safety_settings("dangerous_content").is_err());
+        assert!(parse_safety_settings("violence=block_none").is_err());
+    }
+
+    #[test]
+    fn test_blocked_response() {
+        let response = |json: &str| serde_json::from_str::<GenerateResponse>(json).unwrap();
+
+        let result = response(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hi"}]},"finishReason":"MAX_TOKENS"}]}"#,
+        )
+        .into_result()
+        .unwrap();
+        assert_eq!(result.text, "Hi");
+        assert_eq!(result.finish_reason.as_deref(), Some("MAX_TOKENS"));
+
+        let error = response(r#"{"promptFeedback":{"blockReason":"SAFETY"}}"#)
+            .into_result()
+            .unwrap_err();
+        assert!(error.to_string().contains("block reason: SAFETY"));
+
+        let error = response(r#"{"candidates":[{"finishReason":"SAFETY"}]}"#)
+            .into_result()
+            .unwrap_err();
+        assert!(error.to_string().contains("finish reason: SAFETY"));
+    }
+
+    #[test]
+    fn test_conversation_contents() {
+        let history = vec![ConversationTurn {
+            question: "Tell me about plan X".to_string(),
+            answer: "Plan X includes support.".to_string(),
+        }];
+
+        let contents =
+            serde_json::to_value(conversation_contents(&history, "What about its pricing?"))
+                .unwrap();
+        assert_eq!(
+            contents,
+            serde_json::json!([
+                { "parts": [{ "text": "Tell me about plan X" }], "role": "user" },
+                { "parts": [{ "text": "Plan X includes support." }], "role": "model" },
+                { "parts": [{ "text": "What about its pricing?" }], "role": "user" },
+            ])
+        );
+
+        let prompt = standalone_question_prompt(&history, "What about its pricing?");
+        assert!(prompt.starts_with(
+            "User: Tell me about plan X\nAssistant: Plan X includes support.\n\nFollow-up question: What about its pricing?"
+        ));
+    }
+
+    #[test]
+    fn test_stream_events() {
+        let mut buffer =
+            b"data: {\"a\":1}\n\n: keep-alive\n\ndata: {\"b\":\ndata: 2}\n\ndata: {\"c\"".to_vec();
+        assert_eq!(
+            take_sse_events(&mut buffer),
+            vec![r#"{"a":1}"#.to_string(), "{\"b\":\n2}".to_string()]
+        );
+        assert_eq!(buffer, br#"data: {"c""#);
+
+        buffer.extend_from_slice(b":3}\n\n");
+        assert_eq!(take_sse_events(&mut buffer), vec![r#"{"c":3}"#.to_string()]);
+        assert!(buffer.is_empty());
+
+        let delta = |json: &str| {
+            serde_json::from_str::<GenerateResponse>(json)
+                .unwrap()
+                .into_delta()
+        };
+        assert_eq!(
+            delta(r#"{"candidates":[{"content":{"parts":[{"text":"Hel"},{"text":"lo"}]}}]}"#)
+                .unwrap(),
+            ("Hello".to_string(), None)
+        );
+        assert_eq!(
+            delta(r#"{"candidates":[{"finishReason":"STOP"}]}"#).unwrap(),
+            (String::new(), Some("STOP".to_string()))
+        );
+        assert!(delta(r#"{"promptFeedback":{"blockReason":"OTHER"}}"#).is_err());
+    }
+
+    /// Serve HTTP requests on a local port, answering each request body with the status and
+    /// JSON body returned by `respond`; returns the server's base URL
+    async fn mock_server<F>(respond: F) -> String
+    where
+        F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let respond = Arc::new(respond);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 8192];
+                    // Serve every request sent over the connection
+                    loop {
+                        let Some(head_end) =
+                            request.windows(4).position(|window| window == b"\r\n\r\n")
+                        else {
+                            match socket.read(&mut buffer).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(read) => request.extend_from_slice(&buffer[..read]),
+                            }
+                            continue;
+                        };
+
+                        let head = String::from_utf8_lossy(&request[..head_end]).to_lowercase();
+                        let content_length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .and_then(|length| length.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        let request_end = head_end + 4 + content_length;
+                        if request.len() < request_end {
+                            match socket.read(&mut buffer).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(read) => request.extend_from_slice(&buffer[..read]),
+                            }
+                            continue;
+                        }
+
+                        let body = String::from_utf8_lossy(&request[head_end + 4..request_end])
+                            .to_string();
+                        request.drain(..request_end);
+
+                        let (status, response_body) = respond(&body);
+                        let response = format!(
+                            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            status,
+                            response_body.len(),
+                            response_body
+                        );
+                        if socket.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        base_url
+    }
+
+    #[test]
+    fn test_normalize_base_url() {
+        assert_eq!(
+            normalize_base_url("https://generativelanguage.googleapis.com/v1beta/").unwrap(),
+            "https://generativelanguage.googleapis.com/v1beta"
+        );
+        assert_eq!(
+            normalize_base_url("http://localhost:8080").unwrap(),
+            "http://localhost:8080"
+        );
+        assert!(normalize_base_url("generativelanguage.googleapis.com").is_err());
+        assert!(normalize_base_url("localhost:8080").is_err());
+        assert!(normalize_base_url("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        let text = "alpha beta gamma delta epsilon zeta eta theta";
+
+        let short = truncate_to_tokens(text, 10, TruncationStrategy::Tail);
+        assert!(matches!(short, Cow::Borrowed(_)));
+
+        assert_eq!(
+            truncate_to_tokens(text, 4, TruncationStrategy::Tail),
+            "alpha beta gamma delta"
+        );
+        assert_eq!(
+            truncate_to_tokens(text, 4, TruncationStrategy::Head),
+            "epsilon zeta eta theta"
+        );
+        assert_eq!(
+            truncate_to_tokens(text, 4, TruncationStrategy::Middle),
+            "alpha beta eta theta"
+        );
+    }
+
+    #[test]
+    fn test_parse_paraphrases() {
+        let response = "1. What is the refund policy?\n2) How do refunds work?\n\n- Can I get my money back?\n* When are returns refunded?";
+        assert_eq!(
+            parse_paraphrases(response, 3),
+            vec![
+                "What is the refund policy?",
+                "How do refunds work?",
+                "Can I get my money back?"
+            ]
+        );
+        assert!(parse_paraphrases("  \n", 3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_embeddings_keeps_order() {
+        // Embed each text "text {n}" as the vector [n]
+        let base_url = mock_server(|body| {
+            let request: serde_json::Value = serde_json::from_str(body).unwrap();
+            let embeddings: Vec<serde_json::Value> = request["requests"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    let text = request["content"]["parts"][0]["text"].as_str().unwrap();
+                    let n: f32 = text.trim_start_matches("text ").parse().unwrap();
+                    serde_json::json!({ "values": [n] })
+                })
+                .collect();
+            (
+                200,
+                serde_json::json!({ "embeddings": embeddings }).to_string(),
+            )
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        // More texts than fit in one batch request
+        let texts: Vec<String> = (0..250).map(|n| format!("text {}", n)).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let embeddings = client.get_embeddings(&texts).await.unwrap();
+
+        let values: Vec<f32> = embeddings
+            .iter()
+            .map(|embedding| embedding.values[0])
+            .collect();
+        let expected: Vec<f32> = (0..250).map(|n| n as f32).collect();
+        assert_eq!(values, expected);
+    }
+
+    #[tokio::test]
+    async fn test_usage_metadata() {
+        let base_url = mock_server(|body| {
+            let response = if body.contains("\"requests\"") {
+                r#"{"embeddings": [{"values": [1.0]}], "usageMetadata": {"promptTokenCount": 7}}"#
+            } else {
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}]}}], "usageMetadata": {"promptTokenCount": 1200, "candidatesTokenCount": 30, "totalTokenCount": 1230}}"#
+            };
+            (200, response.to_string())
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        client.get_embeddings(&["text"]).await.unwrap();
+        client
+            .generate("Hello", None, "models/generate", CONTEXT_GENERATION)
+            .await
+            .unwrap();
+        let before = client.usage();
+        client
+            .generate("Hello", None, "models/generate", CONTEXT_GENERATION)
+            .await
+            .unwrap();
+
+        let expected = UsageStats {
+            prompt_tokens: 2407,
+            output_tokens: 60,
+        };
+        assert_eq!(client.usage(), expected);
+        assert_eq!(
+            client.usage().since(before).to_string(),
+            "1,200 prompt tokens / 30 output tokens"
+        );
+    }
+
+    #[test]
+    fn test_group_digits() {
+        assert_eq!(group_digits(0), "0");
+        assert_eq!(group_digits(999), "999");
+        assert_eq!(group_digits(58200), "58,200");
+        assert_eq!(group_digits(1204300), "1,204,300");
+    }
+
+    #[test]
+    fn test_normalized_embedding() {
+        let embedding = Embedding {
+            values: vec![3.0, -4.0, 0.0, 12.0],
+        };
+
+        let normalized = embedding.normalized();
+        assert_eq!(normalized.values.len(), embedding.values.len());
+        assert!((normalized.norm() - 1.0).abs() < 1e-6);
+        assert!((normalized.values[0] - 3.0 / 13.0).abs() < 1e-6);
+
+        // Normalizing again leaves the vector as it is
+        let twice = normalized.normalized();
+        assert_eq!(twice.values.len(), embedding.values.len());
+        for (once, again) in normalized.values.iter().zip(&twice.values) {
+            assert!((once - again).abs() < 1e-6);
+        }
+
+        let zero = Embedding {
+            values: vec![0.0; 3],
+        };
+        assert_eq!(zero.normalized().values, vec![0.0; 3]);
+    }
+
+    #[tokio::test]
+    async fn test_embedding_cache() {
+        // Embed each text as its length, counting the texts sent
+        let requested = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requested.clone();
+        let base_url = mock_server(move |body| {
+            let request: serde_json::Value = serde_json::from_str(body).unwrap();
+            let embeddings: Vec<serde_json::Value> = request["requests"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let text = request["content"]["parts"][0]["text"].as_str().unwrap();
+                    serde_json::json!({ "values": [text.len() as f32] })
+                })
+                .collect();
+            (
+                200,
+                serde_json::json!({ "embeddings": embeddings }).to_string(),
+            )
+        })
+        .await;
+        let cache_dir = std::env::temp_dir().join(format!("embeddings-{}", std::process::id()));
+        let client = GeminiClient::new(GeminiConfig {
+            embedding_cache_dir: Some(cache_dir.clone()),
+            ..test_config(base_url)
+        });
+
+        client.get_document_embeddings(&["a", "bb"]).await.unwrap();
+        let embeddings = client
+            .get_document_embeddings(&["a", "ccc", "bb"])
+            .await
+            .unwrap();
+        // Questions are embedded for another task, so they miss the chunks' entries
+        client.get_query_embeddings(&["a"]).await.unwrap();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+
+        let values: Vec<f32> = embeddings
+            .iter()
+            .map(|embedding| embedding.values[0])
+            .collect();
+        assert_eq!(values, vec![1.0, 3.0, 2.0]);
+        assert_eq!(requested.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_embedding_concurrency() {
+        // Hold each request briefly, recording the most requests in flight at once
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let most_in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (current, most) = (in_flight.clone(), most_in_flight.clone());
+        let base_url = mock_server(move |body| {
+            let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(30));
+            current.fetch_sub(1, Ordering::SeqCst);
+
+            let request: serde_json::Value = serde_json::from_str(body).unwrap();
+            let text = request["requests"][0]["content"]["parts"][0]["text"]
+                .as_str()
+                .unwrap();
+            let n: f32 = text.trim_start_matches("text ").parse().unwrap();
+            (
+                200,
+                serde_json::json!({ "embeddings": [{ "values": [n] }] }).to_string(),
+            )
+        })
+        .await;
+        let client = GeminiClient::new(GeminiConfig {
+            embedding_batch_size: 1,
+            embedding_concurrency: 3,
+            ..test_config(base_url)
+        });
+
+        let texts: Vec<String> = (0..12).map(|n| format!("text {}", n)).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let embeddings = client.get_embeddings(&texts).await.unwrap();
+
+        let values: Vec<f32> = embeddings
+            .iter()
+            .map(|embedding| embedding.values[0])
+            .collect();
+        let expected: Vec<f32> = (0..12).map(|n| n as f32).collect();
+        assert_eq!(values, expected);
+        let most_in_flight = most_in_flight.load(Ordering::SeqCst);
+        assert!(most_in_flight > 1, "requests were not sent concurrently");
+        assert!(most_in_flight <= 3, "{} requests in flight", most_in_flight);
+    }
+
+    #[tokio::test]
+    async fn test_retry_rate_limited_requests() {
+        use std::sync::atomic::AtomicUsize;
+
+        // Rate limited twice, then answered
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let base_url = mock_server(move |_| {
+            if server_calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                (429, r#"{"error": "rate limited"}"#.to_string())
+            } else {
+                (200, r#"{"embedding": {"values": [0.5]}}"#.to_string())
+            }
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        let embedding = client.get_embedding("text").await.unwrap();
+        assert_eq!(embedding.values, vec![0.5]);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Client errors are not retried
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let base_url = mock_server(move |_| {
+            server_calls.fetch_add(1, Ordering::SeqCst);
+            (403, r#"{"error": "forbidden"}"#.to_string())
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        assert!(client.get_embedding("text").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_dropped_connections() {
+        // Drop the first connection without answering, then answer on the next one
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buffer = [0; 8192];
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.read(&mut buffer).await;
+            drop(socket);
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.read(&mut buffer).await;
+            let body = r#"{"embedding": {"values": [0.5]}}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            sleep(Duration::from_secs(10)).await;
+        });
+        let client = GeminiClient::new(test_config(base_url));
+
+        let embedding = client.get_embedding("text").await.unwrap();
+        assert_eq!(embedding.values, vec![0.5]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_empty_responses() {
+        use std::sync::atomic::AtomicUsize;
+
+        // Emptied by a safety filter once, then answered
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let base_url = mock_server(move |_| {
+            if server_calls.fetch_add(1, Ordering::SeqCst) < 1 {
+                (
+                    200,
+                    r#"{"candidates": [{"finishReason": "SAFETY"}]}"#.to_string(),
+                )
+            } else {
+                (
+                    200,
+                    r#"{"candidates": [{"content": {"parts": [{"text": "Answer"}]}}]}"#.to_string(),
+                )
+            }
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        let text = client
+            .generate_text("prompt", None, "models/generate", 0.1, 0.9, 40, 64)
+            .await
+            .unwrap();
+        assert_eq!(text, "Answer");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // A blocked prompt is not retried
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let base_url = mock_server(move |_| {
+            server_calls.fetch_add(1, Ordering::SeqCst);
+            (
+                200,
+                r#"{"promptFeedback": {"blockReason": "SAFETY"}}"#.to_string(),
+            )
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        assert!(client
+            .generate_text("prompt", None, "models/generate", 0.1, 0.9, 40, 64)
+            .await
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_embedding_task_type() {
+        let base_url = mock_server(|body| {
+            let request: serde_json::Value = serde_json::from_str(body).unwrap();
+            let values = if request["task_type"] == "RETRIEVAL_QUERY" {
+                [1.0]
+            } else {
+                [0.0]
+            };
+            (
+                200,
+                serde_json::json!({ "embedding": { "values": values } }).to_string(),
+            )
+        })
+        .await;
+        let client = GeminiClient::new(test_config(base_url));
+
+        let query = client.get_query_embedding("question").await.unwrap();
+        assert_eq!(query.values, vec![1.0]);
+        // Without a task the field is left out
+        let plain = client.get_embedding("question").await.unwrap();
+        assert_eq!(plain.values, vec![0.0]);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+
+        assert_eq!(policy.delay(1, None, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.delay(2, None, 0.0), Duration::from_secs(2));
+        assert_eq!(policy.delay(3, None, 1.0), Duration::from_secs(2));
+        assert_eq!(policy.delay(10, None, 0.0), Duration::from_secs(5));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(3)), 0.0),
+            Duration::from_secs(3)
+        );
+        // A server asking for a long wait cannot stall the run beyond the longest backoff
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(3600)), 0.0),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn test_parse_rerank_order() {
+        assert_eq!(parse_rerank_order("3, 1, 2", 3), vec![2, 0, 1]);
+        // Out of range and repeated numbers are ignored, missing passages go last
+        assert_eq!(parse_rerank_order("[4] [2] [2] [7]", 4), vec![3, 1, 0, 2]);
+        assert_eq!(parse_rerank_order("none", 2), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // Accept connections but never answer them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                connections.push(socket);
+            }
+        });
+
+        let client = GeminiClient::new(GeminiConfig {
+            request_timeout: Duration::from_millis(100),
+            ..test_config(base_url)
+        });
+
+        let error = client.get_embeddings(&["text"]).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("embedding request timed out"));
+
+        let error = client
+            .generate("Hello", None, "models/generate", CONTEXT_GENERATION)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("generate request timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_timeout_per_piece() {
+        // Stream an answer in pieces 60ms apart, then stall without ending the response
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 8192];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            for piece in ["Slow", " but", " steady"] {
+                let event = format!(
+                    "data: {{\"candidates\":[{{\"content\":{{\"parts\":[{{\"text\":\"{}\"}}]}}}}]}}\n\n",
+                    piece
+                );
+                socket.write_all(event.as_bytes()).await.unwrap();
+                sleep(Duration::from_millis(60)).await;
+            }
+            sleep(Duration::from_secs(10)).await;
+        });
+
+        let client = GeminiClient::new(GeminiConfig {
+            request_timeout: Duration::from_millis(100),
+            ..test_config(base_url)
+        });
+        let stream = client
+            .generate_answer_stream("context", "question", None, None, &[])
+            .await
+            .unwrap();
+        let pieces: Vec<Result<String>> = stream.collect().await;
+
+        // The answer takes longer than the timeout, but only the stall fails
+        let text: String = pieces
+            .iter()
+            .filter_map(|piece| piece.as_ref().ok())
+            .cloned()
+            .collect();
+        assert_eq!(text, "Slow but steady");
+        let error = pieces.last().unwrap().as_ref().unwrap_err();
+        assert!(format!("{:#}", error).contains("generate request timed out"));
+    }
+}
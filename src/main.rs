@@ -1,32 +1,723 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
 use log::{error, info};
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use gemini_rag::database::{QdrantClient, QdrantConfig};
-use gemini_rag::document::Document;
+use gemini_rag::chunking::{ChunkQualityFilter, ChunkingConfig, CHUNK_STATS_BUCKET_TOKENS};
+use gemini_rag::context::ContextMode;
+use gemini_rag::database::{
+    named_collection, ExportRecord, QdrantClient, QdrantConfig, RetrievalDefaults,
+    SHARED_COLLECTION,
+};
+use gemini_rag::document::{document_id_candidates, Document, PdfOptions};
 use gemini_rag::gemini::{GeminiClient, GeminiConfig};
-use gemini_rag::rag::RagEngine;
+use gemini_rag::rag::{
+    append_answer, AnswerFormat, CollectionMode, OnExists, RagConfig, RagEngine, RetrievalMode,
+    SearchMode, TokenCounting, DEFAULT_ANSWER_CACHE_TTL,
+};
+
+/// Chunks shown by debug-retrieve without --top-k
+const DEBUG_RETRIEVE_TOP_K: u64 = 4;
 
 /// A RAG (Retrieval-Augmented Generation) application using Gemini embeddings and Qdrant
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the chunks retrieved for a question without generating an answer
+    DebugRetrieve {
+        /// Document whose collection is searched (its file name, as used when indexing)
+        collection: String,
+
+        /// Question to retrieve chunks for
+        question: String,
+
+        /// Print the full text of every chunk after the table
+        #[arg(long)]
+        full: bool,
+
+        // Retrieval settings, as for query, so the same chunks are shown; --top-k defaults to 4
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+
+    /// Show how the stored chunks of a document are distributed by size in tokens
+    ChunkStats {
+        /// Document whose chunks are examined (its file name, as used when indexing)
+        collection: String,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+
+    /// Answer questions about already indexed documents without reading them again
+    Query {
+        /// Only answer from this document; without it, every document in the shared
+        /// collection is searched
+        #[arg(long)]
+        document: Option<String>,
+
+        /// Answer this question, print the answer and exit instead of asking for questions
+        question: Option<String>,
+
+        /// Append each question and answer to this file
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+
+    /// Report which payload fields the stored chunks of a collection are missing
+    ValidateCollection {
+        /// Document whose collection is checked (its file name, as used when indexing); left
+        /// out for the shared or a named collection
+        document: Option<String>,
+
+        /// Backfill the missing fields that can be derived
+        #[arg(long)]
+        repair: bool,
+
+        /// Which collection is checked: the document's own or the shared one
+        #[arg(long, default_value = "per-document")]
+        collection_mode: CollectionMode,
+
+        /// Named collection to check, as given with --collection when indexing
+        #[arg(long, conflicts_with = "collection_mode")]
+        collection: Option<String>,
+    },
+
+    /// Copy the chunks of one collection into another without embedding them again
+    MergeCollections {
+        /// Document whose collection is copied (its file name, as used when indexing)
+        source: String,
+
+        /// Collection the chunks are copied into: "shared", or the name of a named collection
+        /// as given with --collection; created when missing
+        dest: String,
+    },
+
+    /// List the indexed documents with the number of chunks stored for each
+    List,
+
+    /// Delete every collection whose name starts with a prefix
+    Clear {
+        /// Prefix of the collection names after the current tenant's (QDRANT_TENANT)
+        /// collection prefix, such as "notes" for "rag_notes..."; without it, every
+        /// collection of the current tenant is deleted
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// Match --prefix against whole collection names, such as "rag_", of any tenant
+        #[arg(long, requires = "prefix")]
+        absolute_prefix: bool,
+
+        /// Delete without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Delete an indexed document
+    Delete {
+        /// Id of the document, such as `guides/setup.md` for a file indexed from a directory,
+        /// or its path, whose trailing parts are tried from the longest
+        file_path: PathBuf,
+
+        /// Where the document is stored: its own collection or the shared one
+        #[arg(long, default_value = "per-document")]
+        collection_mode: CollectionMode,
+
+        /// Named collection the document is stored in, as given with --collection when indexing
+        #[arg(long, conflicts_with = "collection_mode")]
+        collection: Option<String>,
+
+        /// Delete without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Write an indexed document's chunks and embeddings to a JSONL file, one chunk per line
+    Export {
+        /// Id of the document, such as `guides/setup.md` for a file indexed from a directory,
+        /// or its path, whose trailing parts are tried from the longest
+        file_path: PathBuf,
+
+        /// File the chunks are written to
+        output: PathBuf,
+
+        /// Where the document is stored: its own collection or the shared one
+        #[arg(long, default_value = "per-document")]
+        collection_mode: CollectionMode,
+
+        /// Named collection the document is stored in, as given with --collection when indexing
+        #[arg(long, conflicts_with = "collection_mode")]
+        collection: Option<String>,
+    },
+
+    /// Restore chunks and embeddings written by export, without calling Gemini
+    Import {
+        /// JSONL file written by export
+        input: PathBuf,
+
+        /// Where the documents are stored: their own collections or the shared one
+        #[arg(long, default_value = "per-document")]
+        collection_mode: CollectionMode,
+
+        /// Store the documents in this named collection instead
+        #[arg(long, conflicts_with = "collection_mode")]
+        collection: Option<String>,
+    },
+
+    /// Ingest every file of a directory tree into the shared collection, several at a time
+    IngestDir {
+        /// Directory whose files, including those in subdirectories, are ingested
+        dir: PathBuf,
+
+        /// Number of documents ingested at the same time; combine with
+        /// --max-concurrent-requests to bound the Gemini requests they share
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+        concurrency: u64,
+
+        #[command(flatten)]
+        pdf: PdfArgs,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+
+    /// Rebuild every indexed collection from its stored chunks with the current settings
+    ReindexAll {
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+}
+
+/// Arguments for indexing a document and answering questions about it interactively
+#[derive(clap::Args, Debug)]
 struct Args {
-    /// Path to the document to process (supports text and PDF)
-    #[arg(index = 1)]
-    file_path: String,
+    /// Path to the document to process (text, Markdown, CSV/TSV, HTML, PDF, EPUB or DOCX)
+    #[arg(index = 1, required = true)]
+    file_path: Option<String>,
+
+    /// Answer this question, print the answer and exit instead of asking for questions
+    #[arg(index = 2)]
+    question: Option<String>,
+
+    /// Append each question and answer to this file
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// What to do when the document is already indexed: skip, append or replace
+    #[arg(long, default_value = "skip")]
+    on_exists: OnExists,
+
+    /// Regenerate chunk context and embeddings for an already indexed document
+    #[arg(long)]
+    recontextualize: bool,
+
+    /// Read the document as this MIME type, such as text/markdown, instead of the one guessed
+    /// from its extension
+    #[arg(long)]
+    mime_type: Option<String>,
+
+    #[command(flatten)]
+    pdf: PdfArgs,
+
+    /// Print the estimated ingestion cost and exit without indexing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Embedding price in dollars per 1K tokens, for the --dry-run estimate
+    #[arg(long)]
+    embedding_price: Option<f64>,
+
+    /// Context model input price in dollars per 1K tokens, for the --dry-run estimate
+    #[arg(long)]
+    context_input_price: Option<f64>,
+
+    /// Context model output price in dollars per 1K tokens, for the --dry-run estimate
+    #[arg(long)]
+    context_output_price: Option<f64>,
+
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+/// Arguments for reading PDF files
+#[derive(clap::Args, Debug)]
+struct PdfArgs {
+    /// Number of threads used to extract PDF pages (defaults to the number of CPUs)
+    #[arg(long)]
+    pdf_threads: Option<usize>,
+
+    /// Strip PDF lines repeated on at least this fraction of pages (headers and footers)
+    #[arg(long, value_parser = parse_weight)]
+    pdf_boilerplate_threshold: Option<f32>,
+
+    /// Password of an encrypted PDF
+    #[arg(long)]
+    pdf_password: Option<String>,
+}
+
+impl PdfArgs {
+    /// Build the PDF extraction options from the command line settings
+    fn pdf_options(&self) -> PdfOptions {
+        let mut pdf_options = PdfOptions::default();
+        if let Some(pdf_threads) = self.pdf_threads {
+            pdf_options.extract_threads = pdf_threads;
+        }
+        pdf_options.boilerplate_threshold = self.pdf_boilerplate_threshold;
+        pdf_options.password = self.pdf_password.clone();
+        pdf_options
+    }
+}
+
+/// Settings of the RAG engine shared by the commands that index or answer
+#[derive(clap::Args, Debug)]
+struct EngineArgs {
+    /// Model used to answer questions, overriding GENERATE_MODEL for this run
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Drop chunks whose share of alphabetic characters is below this ratio (0.0-1.0)
+    #[arg(long, value_parser = parse_weight)]
+    min_alpha_ratio: Option<f32>,
+
+    /// Drop chunks with fewer distinct words than this
+    #[arg(long)]
+    min_distinct_words: Option<usize>,
+
+    /// Print the full prompt sent to the model along with each answer
+    #[arg(long)]
+    show_prompt: bool,
+
+    /// Maximum number of Gemini requests in flight at once
+    #[arg(long)]
+    max_concurrent_requests: Option<usize>,
+
+    /// Chunks whose context is generated at the same time with --context-mode llm
+    /// (defaults to 4; the 30 requests per minute context limit still applies)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    context_concurrency: Option<u64>,
+
+    /// How chunks get context before embedding: llm, title (document title and heading) or none
+    #[arg(long, default_value = "llm")]
+    context_mode: ContextMode,
+
+    /// Embed chunks as they are, without generating context for them (same as --context-mode
+    /// none); halves the API calls of indexing
+    #[arg(long, conflicts_with = "context_mode")]
+    no_context: bool,
+
+    /// Answer formatting: markdown, or plain to strip markdown for plain terminals
+    #[arg(long, default_value = "markdown")]
+    answer_format: AnswerFormat,
+
+    /// Answer from the top chunk alone when its similarity score reaches this value
+    #[arg(long)]
+    single_chunk_score: Option<f32>,
+
+    /// Skip answer generation when the best chunk scores below this value
+    #[arg(long)]
+    generation_min_score: Option<f32>,
+
+    /// Prefix the text sent for embedding with the document title and heading
+    #[arg(long)]
+    embedding_prefix: bool,
+
+    /// System instruction sent with every question (change it in a session with /system)
+    #[arg(long)]
+    system_prompt: Option<String>,
+
+    /// Answer length or shape guidance such as "in one sentence" (change it with /style)
+    #[arg(long)]
+    answer_style: Option<String>,
+
+    /// Write a JSON trace of every answer and its inputs to this directory
+    #[arg(long)]
+    trace_dir: Option<PathBuf>,
+
+    /// Number of chunks used to answer, overriding the document's stored default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    top_k: Option<u64>,
+
+    /// Ignore chunks scoring below this, overriding the document's stored default
+    #[arg(long)]
+    min_score: Option<f32>,
+
+    /// Number of chunks to answer from, stored with newly ingested documents
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    default_top_k: Option<u64>,
+
+    /// Lowest chunk score to answer from, stored with newly ingested documents
+    #[arg(long)]
+    default_min_score: Option<f32>,
+
+    /// Store each document in its own collection (per-document) or all in one (shared)
+    #[arg(long, default_value = "per-document")]
+    collection_mode: CollectionMode,
+
+    /// Store every document in this named collection instead of one named after the file;
+    /// implies --collection-mode shared
+    #[arg(
+        long,
+        conflicts_with = "collection_mode",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    collection: Option<String>,
+
+    /// Also store a vector of the raw chunk text in new collections, and weight its search
+    /// against the contextual one by this (0 to 1) when answering
+    #[arg(long, value_parser = parse_weight)]
+    raw_vector_weight: Option<f32>,
+
+    /// Also search this many model-written paraphrases of each question, merging the results
+    #[arg(long, default_value_t = 0)]
+    multi_query: usize,
+
+    /// Count tokens with the fast heuristic (fallback) or byte-pair encoding (bpe, needs the
+    /// tiktoken feature)
+    #[arg(long, default_value = "fallback")]
+    token_counter: TokenCounting,
+
+    /// Tokens each chunk is filled up to (default 500)
+    #[arg(long)]
+    chunk_tokens: Option<usize>,
+
+    /// Tokens repeated between consecutive chunks, smaller than --chunk-tokens (default 50)
+    #[arg(long)]
+    chunk_overlap: Option<usize>,
+
+    /// Rows of a CSV or TSV file put in one chunk (default 1)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    csv_rows_per_chunk: Option<u64>,
+
+    /// Index only the first N chunks of a document, as a quick sample of the settings
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_chunks: Option<u64>,
+
+    /// Order retrieved chunks by relevance with the rerank model (RERANK_MODEL) before answering
+    #[arg(long)]
+    rerank: bool,
+
+    /// Search with the question (direct), a model-written hypothetical answer (hyde), or both
+    /// merged by rank (hyde-hybrid)
+    #[arg(long, default_value = "direct")]
+    retrieval_mode: RetrievalMode,
+
+    /// Weight (0 to 1) of the hypothetical answer's ranking with --retrieval-mode hyde-hybrid
+    #[arg(long, value_parser = parse_weight)]
+    hyde_weight: Option<f32>,
+
+    /// Find chunks by embedding similarity (vector), by the words of the question (keyword), or
+    /// both merged by rank (hybrid), for exact terms like error codes that embed poorly
+    #[arg(long, default_value = "vector")]
+    search_mode: SearchMode,
+
+    /// Only retrieve chunks ingested within this many days (slower on unindexed old collections)
+    #[arg(long)]
+    ingested_within_days: Option<u64>,
+
+    /// Always generate answers, instead of reusing one given to the same question over the
+    /// same retrieved chunks within the last hour
+    #[arg(long)]
+    no_answer_cache: bool,
+
+    /// Embed every chunk and question anew instead of reusing embeddings cached on disk
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Earlier questions and answers of the session that follow-up questions can refer to;
+    /// 0 answers every question on its own (type "/reset" in the session to forget them)
+    #[arg(long, default_value_t = 5)]
+    history_turns: usize,
+}
+
+impl EngineArgs {
+    /// Build the engine configuration from the command line settings
+    fn rag_config(self) -> RagConfig {
+        // Enable chunk quality filtering when any threshold is given
+        let quality_filter = if self.min_alpha_ratio.is_some() || self.min_distinct_words.is_some()
+        {
+            let defaults = ChunkQualityFilter::default();
+            Some(ChunkQualityFilter {
+                min_alphabetic_ratio: self
+                    .min_alpha_ratio
+                    .unwrap_or(defaults.min_alphabetic_ratio),
+                min_distinct_words: self
+                    .min_distinct_words
+                    .unwrap_or(defaults.min_distinct_words),
+            })
+        } else {
+            None
+        };
+
+        let default_chunking = ChunkingConfig::default();
+        let chunking = ChunkingConfig {
+            target_tokens: self.chunk_tokens.unwrap_or(default_chunking.target_tokens),
+            overlap_tokens: self
+                .chunk_overlap
+                .unwrap_or(default_chunking.overlap_tokens),
+            ..default_chunking
+        };
+
+        RagConfig {
+            answer_model: self.model,
+            quality_filter,
+            include_prompt: self.show_prompt,
+            max_concurrent_requests: self.max_concurrent_requests,
+            context_concurrency: self
+                .context_concurrency
+                .map(|concurrency| concurrency as usize),
+            context_mode: if self.no_context {
+                ContextMode::None
+            } else {
+                self.context_mode
+            },
+            answer_format: self.answer_format,
+            high_confidence_single_chunk: self.single_chunk_score,
+            generation_min_score: self.generation_min_score,
+            collection_mode: if self.collection.is_some() {
+                CollectionMode::Shared
+            } else {
+                self.collection_mode
+            },
+            collection: self.collection,
+            embedding_metadata_prefix: self.embedding_prefix,
+            trace_dir: self.trace_dir,
+            system_instruction: self.system_prompt,
+            answer_style: self.answer_style,
+            top_k: self.top_k,
+            min_score: self.min_score,
+            document_defaults: RetrievalDefaults {
+                top_k: self.default_top_k,
+                min_score: self.default_min_score,
+            },
+            raw_vector_weight: self.raw_vector_weight,
+            multi_query: self.multi_query,
+            token_counting: self.token_counter,
+            chunking,
+            csv_rows_per_chunk: self.csv_rows_per_chunk.map(|rows| rows as usize),
+            rerank: self.rerank,
+            retrieval_mode: match self.retrieval_mode {
+                RetrievalMode::HyDEHybrid { hyde_weight } => RetrievalMode::HyDEHybrid {
+                    hyde_weight: self.hyde_weight.unwrap_or(hyde_weight),
+                },
+                retrieval_mode => retrieval_mode,
+            },
+            search_mode: self.search_mode,
+            ingested_within: self
+                .ingested_within_days
+                .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+            answer_cache_ttl: (!self.no_answer_cache).then_some(DEFAULT_ANSWER_CACHE_TTL),
+            max_chunks: self.max_chunks.map(|max_chunks| max_chunks as usize),
+            no_embedding_cache: self.no_cache,
+            history_turns: self.history_turns,
+            ..RagConfig::default()
+        }
+    }
+}
+
+/// Parse a weight between 0 and 1
+fn parse_weight(s: &str) -> Result<f32> {
+    let weight: f32 = s.parse()?;
+    if !(0.0..=1.0).contains(&weight) {
+        return Err(anyhow::anyhow!(
+            "weight must be between 0 and 1, got {}",
+            weight
+        ));
+    }
+    Ok(weight)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize environment
     dotenv().ok();
+    init_logging();
+
+    // Parse command line arguments
+    let cli = Cli::parse();
+
+    // Load configuration from environment
+    let qdrant_config = QdrantConfig::from_env().context("Invalid Qdrant configuration")?;
+    let gemini_config = GeminiConfig::from_env().context("Invalid Gemini configuration")?;
+
+    let qdrant = QdrantClient::new(qdrant_config)
+        .await
+        .context("Failed to initialize Qdrant client")?;
+    let gemini = GeminiClient::new(gemini_config);
+
+    match cli.command {
+        Some(Command::DebugRetrieve {
+            collection,
+            question,
+            full,
+            engine,
+        }) => {
+            let top_k = engine.top_k.unwrap_or(DEBUG_RETRIEVE_TOP_K);
+            let rag_engine = RagEngine::with_config(qdrant, gemini, engine.rag_config());
+            debug_retrieve(&rag_engine, &collection, &question, top_k, full).await
+        }
+        Some(Command::ChunkStats { collection, engine }) => {
+            let rag_engine = RagEngine::with_config(qdrant, gemini, engine.rag_config());
+            chunk_stats(&rag_engine, &collection).await
+        }
+        Some(Command::Query {
+            document,
+            question,
+            output,
+            engine,
+        }) => {
+            let rag_engine = RagEngine::with_config(qdrant, gemini, engine.rag_config());
+            query(
+                &rag_engine,
+                document.as_deref(),
+                question.as_deref(),
+                output.as_deref(),
+            )
+            .await
+        }
+        Some(Command::ValidateCollection {
+            document,
+            repair,
+            collection_mode,
+            collection,
+        }) => {
+            let config = storage_config(collection_mode, collection);
+            let rag_engine = RagEngine::with_config(qdrant, gemini, config);
+            validate_collection(&rag_engine, document.as_deref(), repair).await
+        }
+        Some(Command::MergeCollections { source, dest }) => {
+            let dest_collection = if dest == SHARED_COLLECTION {
+                dest.clone()
+            } else {
+                named_collection(&dest)
+            };
+            let copied = qdrant
+                .merge_collections(&source, &dest_collection)
+                .await
+                .context("Failed to merge collections")?;
+            info!("Copied {} chunks from {} into {}", copied, source, dest);
+            Ok(())
+        }
+        Some(Command::List) => list(&qdrant).await,
+        Some(Command::Clear {
+            prefix,
+            absolute_prefix,
+            yes,
+        }) => clear(&qdrant, prefix.as_deref(), absolute_prefix, yes).await,
+        Some(Command::Delete {
+            file_path,
+            collection_mode,
+            collection,
+            yes,
+        }) => {
+            let config = storage_config(collection_mode, collection);
+            let rag_engine = RagEngine::with_config(qdrant, gemini, config);
+            delete(&rag_engine, &file_path, yes).await
+        }
+        Some(Command::Export {
+            file_path,
+            output,
+            collection_mode,
+            collection,
+        }) => {
+            let config = storage_config(collection_mode, collection);
+            let rag_engine = RagEngine::with_config(qdrant, gemini, config);
+            export(&rag_engine, &file_path, &output).await
+        }
+        Some(Command::Import {
+            input,
+            collection_mode,
+            collection,
+        }) => {
+            let config = storage_config(collection_mode, collection);
+            let rag_engine = RagEngine::with_config(qdrant, gemini, config);
+            import(&rag_engine, &input).await
+        }
+        Some(Command::IngestDir {
+            dir,
+            concurrency,
+            pdf,
+            engine,
+        }) => {
+            let config = RagConfig {
+                collection_mode: CollectionMode::Shared,
+                ..engine.rag_config()
+            };
+            let rag_engine = Arc::new(RagEngine::with_config(qdrant, gemini, config));
+            ingest_dir(&rag_engine, &dir, &pdf.pdf_options(), concurrency as usize).await
+        }
+        Some(Command::ReindexAll { engine }) => {
+            let rag_engine = RagEngine::with_config(qdrant, gemini, engine.rag_config());
+            let reindexed = rag_engine
+                .reindex_all()
+                .await
+                .context("Failed to reindex collections")?;
+            info!("Reindexed {} collections", reindexed);
+            Ok(())
+        }
+        None => run_interactive(cli.args, qdrant, gemini).await,
+    }
+}
+
+/// Engine settings locating documents by collection mode, or in the named collection when given
+fn storage_config(collection_mode: CollectionMode, collection: Option<String>) -> RagConfig {
+    RagConfig {
+        collection_mode: if collection.is_some() {
+            CollectionMode::Shared
+        } else {
+            collection_mode
+        },
+        collection,
+        ..RagConfig::default()
+    }
+}
+
+/// Set up log output, as tracing spans with their timings when the `tracing` feature is on
+fn init_logging() {
+    #[cfg(feature = "tracing")]
+    {
+        use tracing_subscriber::fmt::format::FmtSpan;
+        use tracing_subscriber::EnvFilter;
+
+        // Log records are forwarded into the subscriber, so RUST_LOG works as before;
+        // closing spans report their busy and idle time
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_span_events(FmtSpan::CLOSE)
+            .init();
+    }
+
+    #[cfg(not(feature = "tracing"))]
     env_logger::init();
+}
 
-    // Parse and validate command line arguments
-    let args = Args::parse();
-    let file_path = args.file_path; // Path to the document to process
+/// Index a document if needed and answer questions about it interactively
+async fn run_interactive(args: Args, qdrant: QdrantClient, gemini: GeminiClient) -> Result<()> {
+    // Path to the document to process
+    let file_path = args.file_path.context("Missing document path")?;
+    let output = args.output; // Optional file to record answers in
 
     info!("Processing file: {}", file_path);
 
@@ -37,40 +728,469 @@ async fn main() -> Result<()> {
         return Err(anyhow::anyhow!("File not found"));
     }
 
-    // Load configuration from environment
-    let qdrant_config = QdrantConfig::from_env().context("Missing QDRANT_URL")?;
-    let gemini_config = GeminiConfig::from_env().context("Missing GEMINI_API_KEY")?;
-
-    let qdrant = QdrantClient::new(qdrant_config)
-        .await
-        .context("Failed to initialize Qdrant client")?;
-    let gemini = GeminiClient::new(gemini_config);
-
     // Initialize RAG engine
-    let rag_engine = RagEngine::new(qdrant, gemini);
+    let mut rag_config = args.engine.rag_config();
+    let pricing = &mut rag_config.pricing;
+    if let Some(price) = args.embedding_price {
+        pricing.embedding_per_1k = price;
+    }
+    if let Some(price) = args.context_input_price {
+        pricing.context_input_per_1k = price;
+    }
+    if let Some(price) = args.context_output_price {
+        pricing.context_output_per_1k = price;
+    }
+    let rag_engine = RagEngine::with_config(qdrant, gemini, rag_config);
 
     // Process the document (text or PDF)
-    let document = Document::from_file(&file_path).context("Failed to process document")?;
+    let pdf_options = args.pdf.pdf_options();
+    let document = match &args.mime_type {
+        Some(mime_type) => Document::from_file_with_mime(&file_path, mime_type, &pdf_options),
+        None => Document::from_file_with_options(&file_path, &pdf_options),
+    }
+    .context("Failed to process document")?;
     let document_id = document.document_id.clone();
 
     info!("Document type: {}", document.mime_type);
 
-    // Only process file if collection doesn't exist
-    if rag_engine.collection_exists(&document_id).await? {
-        info!("Using existing collection: {}", document_id);
+    if args.dry_run {
+        let estimate =
+            rag_engine.estimate_cost(&document.content, &document_id, Some(&document.mime_type))?;
+        println!("Chunks:                {}", estimate.chunks);
+        println!("Embedding tokens:      {}", estimate.embedding_tokens);
+        println!("Context input tokens:  {}", estimate.context_input_tokens);
+        println!("Context output tokens: {}", estimate.context_output_tokens);
+        println!("Embedding cost:        ${:.4}", estimate.embedding_cost);
+        println!("Context cost:          ${:.4}", estimate.context_cost);
+        println!("Estimated total:       ${:.4}", estimate.total_cost());
+        return Ok(());
+    }
+
+    // Reuse an existing collection unless asked to append to or replace it
+    let exists = rag_engine.collection_exists(&document_id).await?;
+    if exists && args.on_exists == OnExists::Skip {
+        info!("Using already indexed document: {}", document_id);
+
+        if args.recontextualize {
+            rag_engine
+                .recontextualize(&document_id)
+                .await
+                .context("Failed to recontextualize collection")?;
+        }
     } else {
         // Process and index the document
         rag_engine
-            .process_file(document.content, &document_id)
+            .process_document(document, args.on_exists)
             .await
             .context("Failed to process file")?;
     }
 
+    if let Some(question) = args.question {
+        let answer = rag_engine
+            .answer_question(&document_id, &question)
+            .await
+            .context("Failed to answer question")?;
+        return print_answer(&question, &answer, output.as_deref());
+    }
+
     // Enter interactive Q&A loop
     rag_engine
-        .run_query_loop(&document_id)
+        .run_query_loop(&document_id, output.as_deref())
         .await
         .context("Error in query loop")?;
 
     Ok(())
 }
+
+/// Answer questions about one indexed document, or all documents of the shared collection
+async fn query(
+    rag_engine: &RagEngine,
+    document: Option<&str>,
+    question: Option<&str>,
+    output: Option<&Path>,
+) -> Result<()> {
+    if let Some(document) = document {
+        if !rag_engine.collection_exists(document).await? {
+            return Err(anyhow::anyhow!("{} is not indexed", document));
+        }
+    }
+
+    if let Some(question) = question {
+        let answer = match document {
+            Some(document) => rag_engine.answer_question(document, question).await,
+            None => rag_engine
+                .answer_across_documents(question)
+                .await
+                .map(|answer| answer.text),
+        };
+        let answer = answer.context("Failed to answer question")?;
+        return print_answer(question, &answer, output);
+    }
+
+    let result = match document {
+        Some(document) => rag_engine.run_query_loop(document, output).await,
+        None => rag_engine.run_query_loop_across_documents(output).await,
+    };
+
+    result.context("Error in query loop")
+}
+
+/// Print the chunks retrieved for a question as a table, without generating an answer
+async fn debug_retrieve(
+    rag_engine: &RagEngine,
+    collection: &str,
+    question: &str,
+    top_k: u64,
+    full: bool,
+) -> Result<()> {
+    const PREVIEW_CHARS: usize = 60;
+
+    let scored_chunks = rag_engine
+        .retrieve(collection, question, top_k)
+        .await
+        .context("Failed to retrieve chunks")?;
+
+    if scored_chunks.is_empty() {
+        println!("No chunks retrieved from {}", collection);
+        return Ok(());
+    }
+
+    println!(
+        "{:>4}  {:>7}  {:<24}  {:>9}  {:>4}  text",
+        "rank", "score", "document", "position", "page"
+    );
+    for (rank, scored_chunk) in scored_chunks.iter().enumerate() {
+        let chunk = &scored_chunk.chunk;
+        // Show the text on a single line so the table stays aligned
+        let preview: String = chunk
+            .text
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .take(PREVIEW_CHARS)
+            .collect();
+
+        println!(
+            "{:>4}  {:>7.4}  {:<24}  {:>9}  {:>4}  {}",
+            rank + 1,
+            scored_chunk.score,
+            chunk.document_id,
+            chunk.start_position,
+            chunk.page.map(|page| page.to_string()).unwrap_or_default(),
+            preview
+        );
+    }
+
+    if full {
+        for (rank, scored_chunk) in scored_chunks.iter().enumerate() {
+            println!(
+                "\n--- [{}] score {:.4} ---\n{}",
+                rank + 1,
+                scored_chunk.score,
+                scored_chunk.chunk.text
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Print how many sampled chunks carry each expected payload field, repairing them if asked
+async fn validate_collection(
+    rag_engine: &RagEngine,
+    document: Option<&str>,
+    repair: bool,
+) -> Result<()> {
+    let report = rag_engine
+        .validate_collection(document)
+        .await
+        .context("Failed to validate collection")?;
+
+    if report.sampled_points == 0 {
+        println!("No chunks stored in the collection");
+        return Ok(());
+    }
+
+    println!("Sampled {} chunks", report.sampled_points);
+    for (key, count) in &report.key_counts {
+        println!("{:<16} {:>5}/{}", key, count, report.sampled_points);
+    }
+
+    let missing = report.missing_keys();
+    if missing.is_empty() {
+        println!("All expected fields are present");
+        return Ok(());
+    }
+    println!("Missing: {}", missing.join(", "));
+
+    if repair {
+        let repaired = rag_engine
+            .repair_collection(document)
+            .await
+            .context("Failed to repair collection")?;
+        info!("Backfilled derivable fields of {} chunks", repaired);
+    }
+
+    Ok(())
+}
+
+/// Print every indexed document with its number of stored chunks, those of the shared and
+/// named collections marked with the collection's name
+async fn list(qdrant: &QdrantClient) -> Result<()> {
+    let mut rows = Vec::new();
+
+    let documents = qdrant
+        .list_collections()
+        .await
+        .context("Failed to list collections")?;
+    for document in documents {
+        let chunks = qdrant.count_points(&document, None).await?;
+        rows.push((document, chunks));
+    }
+
+    let mut shared_collections =
+        vec![(SHARED_COLLECTION.to_string(), SHARED_COLLECTION.to_string())];
+    for name in qdrant
+        .list_named_collections()
+        .await
+        .context("Failed to list collections")?
+    {
+        shared_collections.push((named_collection(&name), name));
+    }
+    for (shared, name) in &shared_collections {
+        if !qdrant.collection_exists(shared).await? {
+            continue;
+        }
+        for document in qdrant.list_documents(shared).await? {
+            let chunks = qdrant.count_points(shared, Some(&document)).await?;
+            rows.push((format!("{} ({})", document, name), chunks));
+        }
+    }
+
+    if rows.is_empty() {
+        println!("No documents indexed");
+        return Ok(());
+    }
+
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, chunks) in &rows {
+        println!("{:<width$}  {:>6} chunks", name, chunks);
+    }
+
+    Ok(())
+}
+
+/// Delete the collections starting with a prefix after listing them and asking for confirmation
+async fn clear(
+    qdrant: &QdrantClient,
+    prefix: Option<&str>,
+    absolute_prefix: bool,
+    yes: bool,
+) -> Result<()> {
+    let names = qdrant
+        .collections_with_prefix(prefix, absolute_prefix)
+        .await
+        .context("Failed to list collections")?;
+
+    if names.is_empty() {
+        println!("No collections to delete");
+        return Ok(());
+    }
+
+    for name in &names {
+        println!("{}", name);
+    }
+
+    if !yes {
+        print!("Delete these {} collections? [y/N] ", names.len());
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            println!("Nothing deleted");
+            return Ok(());
+        }
+    }
+
+    // Only the confirmed collections, not ones created since they were listed
+    let deleted = qdrant
+        .delete_collections(&names)
+        .await
+        .context("Failed to delete collections")?;
+    info!("Deleted {} collections", deleted);
+
+    Ok(())
+}
+
+/// Print the answer of a one-shot question to stdout, recording it in `output` when given
+fn print_answer(question: &str, answer: &str, output: Option<&Path>) -> Result<()> {
+    println!("{}", answer);
+
+    if let Some(output) = output {
+        append_answer(output, question, answer)?;
+    }
+
+    Ok(())
+}
+
+/// Ingest the files of a directory tree and print how many documents and chunks were indexed
+async fn ingest_dir(
+    rag_engine: &Arc<RagEngine>,
+    dir: &Path,
+    pdf_options: &PdfOptions,
+    concurrency: usize,
+) -> Result<()> {
+    // Unsupported and unreadable files are reported and left out rather than failing the run
+    let loaded = Document::from_directory_with_options(dir, pdf_options)?;
+    let documents: Vec<(String, String)> = loaded
+        .documents
+        .into_iter()
+        .map(|document| (document.document_id, document.content))
+        .collect();
+    info!(
+        "Ingesting {} documents from {}",
+        documents.len(),
+        dir.display()
+    );
+
+    let report = rag_engine
+        .ingest_documents(documents, concurrency)
+        .await
+        .context("Failed to ingest documents")?;
+
+    println!("Indexed:     {}", report.indexed);
+    println!("Skipped:     {}", report.skipped);
+    println!("Unsupported: {}", loaded.unsupported);
+    println!("Failed:      {}", report.failed + loaded.failed);
+    println!("Chunks:      {}", report.chunks);
+    println!("Elapsed:     {:.1}s", report.elapsed.as_secs_f64());
+    println!("Tokens:      {}", report.usage);
+
+    Ok(())
+}
+
+/// Delete a document's chunks after asking for confirmation
+async fn delete(rag_engine: &RagEngine, file_path: &Path, yes: bool) -> Result<()> {
+    let Some(document_id) = indexed_document_id(rag_engine, file_path).await? else {
+        println!("{} is not indexed, nothing to delete", file_path.display());
+        return Ok(());
+    };
+    let document_id = document_id.as_str();
+
+    if !yes {
+        print!("Delete the indexed document {}? [y/N] ", document_id);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            println!("Nothing deleted");
+            return Ok(());
+        }
+    }
+
+    rag_engine
+        .delete_document(document_id)
+        .await
+        .context("Failed to delete document")?;
+    println!("Deleted {}", document_id);
+
+    Ok(())
+}
+
+/// Id a document given by id or path is indexed under, None when it is not indexed
+async fn indexed_document_id(rag_engine: &RagEngine, file_path: &Path) -> Result<Option<String>> {
+    for document_id in document_id_candidates(file_path)? {
+        if rag_engine.has_document(&document_id).await? {
+            return Ok(Some(document_id));
+        }
+    }
+    Ok(None)
+}
+
+/// Write a document's chunks and embeddings to a JSONL file
+async fn export(rag_engine: &RagEngine, file_path: &Path, output: &Path) -> Result<()> {
+    let document_id = indexed_document_id(rag_engine, file_path)
+        .await?
+        .with_context(|| format!("{} is not indexed", file_path.display()))?;
+    let document_id = document_id.as_str();
+
+    let file =
+        File::create(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    let mut writer = BufWriter::new(file);
+    let exported = rag_engine
+        .export_document(document_id, &mut writer)
+        .await
+        .context("Failed to export document")?;
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+
+    println!(
+        "Exported {} chunks of {} to {}",
+        exported,
+        document_id,
+        output.display()
+    );
+    Ok(())
+}
+
+/// Store the chunks and embeddings of a JSONL export
+async fn import(rag_engine: &RagEngine, input: &Path) -> Result<()> {
+    let file = File::open(input).with_context(|| format!("Failed to open {}", input.display()))?;
+
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", input.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ExportRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid chunk on line {} of {}", i + 1, input.display()))?;
+        records.push(record);
+    }
+
+    let imported = rag_engine
+        .import_records(records)
+        .await
+        .context("Failed to import chunks")?;
+    println!("Imported {} chunks from {}", imported, input.display());
+
+    Ok(())
+}
+
+/// Print the token distribution of a document's stored chunks
+async fn chunk_stats(rag_engine: &RagEngine, collection: &str) -> Result<()> {
+    const BAR_WIDTH: usize = 40;
+
+    let stats = rag_engine
+        .chunk_stats(collection)
+        .await
+        .context("Failed to compute chunk statistics")?;
+
+    if stats.count == 0 {
+        println!("No chunks stored for {}", collection);
+        return Ok(());
+    }
+
+    println!("Chunks: {}", stats.count);
+    println!(
+        "Tokens: min {}, max {}, mean {:.1}",
+        stats.min_tokens, stats.max_tokens, stats.mean
+    );
+
+    // One bar per bucket, scaled to the fullest bucket
+    let fullest = stats.histogram.iter().copied().max().unwrap_or(1).max(1);
+    for (bucket, count) in stats.histogram.iter().enumerate() {
+        let start = bucket * CHUNK_STATS_BUCKET_TOKENS;
+        println!(
+            "{:>5}-{:<5} {:>6}  {}",
+            start,
+            start + CHUNK_STATS_BUCKET_TOKENS - 1,
+            count,
+            "#".repeat(count * BAR_WIDTH / fullest)
+        );
+    }
+
+    Ok(())
+}
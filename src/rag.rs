@@ -1,164 +1,3058 @@
-use crate::context::ContextGenerator;
-use crate::database::QdrantClient;
-use crate::embeddings::ContextualEmbeddingExt;
-use crate::gemini::GeminiClient;
-use anyhow::Result;
-use log::info;
+use crate::chunking::ChunkQualityFilter;
+use crate::chunking::{ChunkStats, ChunkingConfig, FallbackCounter, TextChunk, TokenCounter};
+use crate::context::{
+    context_prompt, metadata_prefix, title_and_heading_context, ContextGenerator, ContextMode,
+    ContextualizedChunk, DEFAULT_CONTEXT_CONCURRENCY,
+};
+use crate::database::{
+    named_collection, record_dimensions, ExportRecord, QdrantClient, RetrievalDefaults,
+    SchemaReport, ScoredChunk, SearchFilter, CONTEXTUAL_VECTOR, RAW_VECTOR, SHARED_COLLECTION,
+};
+use crate::document::Document;
+use crate::embeddings::{ContextualEmbedding, ContextualEmbeddingExt};
+use crate::gemini::{
+    answer_prompt, ConversationTurn, Embedding, EmbeddingProfile, GeminiClient, GenerationParams,
+    UsageStats, ANSWER_GENERATION,
+};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use log::{debug, info, warn};
+use qdrant_client::qdrant::Distance;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::OnceCell;
+use tokio::task::JoinSet;
+
+/// Tunable settings for the RAG engine
+#[derive(Debug, Clone, Default)]
+pub struct RagConfig {
+    /// Model used for answering instead of `GeminiConfig.generate_model`
+    pub answer_model: Option<String>,
+    /// Drop low-quality chunks (OCR noise, table fragments) before indexing
+    pub quality_filter: Option<ChunkQualityFilter>,
+    /// Return the assembled prompt with each answer for inspection
+    pub include_prompt: bool,
+    /// Maximum number of Gemini requests in flight at once, shared by all phases
+    pub max_concurrent_requests: Option<usize>,
+    /// Chunks whose LLM context is generated at the same time, instead of
+    /// `DEFAULT_CONTEXT_CONCURRENCY`
+    pub context_concurrency: Option<usize>,
+    /// How chunks are given context before embedding
+    pub context_mode: ContextMode,
+    /// Formatting of generated answers
+    pub answer_format: AnswerFormat,
+    /// Answer from the top chunk alone when its score reaches this threshold
+    pub high_confidence_single_chunk: Option<f32>,
+    /// Token prices used by `RagEngine::estimate_cost`
+    pub pricing: TokenPricing,
+    /// Whether each document gets its own collection or all share one
+    pub collection_mode: CollectionMode,
+    /// Name of the collection shared by all documents in shared mode, instead of
+    /// `SHARED_COLLECTION`
+    pub collection: Option<String>,
+    /// Score the best chunk must reach for `RagEngine::assess_context` to call it sufficient
+    pub sufficiency_score: Option<f32>,
+    /// Let `RagEngine::assess_context` ask the model whether the context holds the answer
+    pub sufficiency_check_with_model: bool,
+    /// Prefix the embedded text with the document title and heading, without storing it
+    pub embedding_metadata_prefix: bool,
+    /// Directory receiving a JSON trace of every generated answer and its inputs
+    pub trace_dir: Option<PathBuf>,
+    /// System instruction sent with every question, such as the tone or level of detail
+    pub system_instruction: Option<String>,
+    /// Guidance on answer length or shape, such as "in one sentence", added to the instruction
+    pub answer_style: Option<String>,
+    /// Number of chunks retrieved per question, overriding the document's stored default
+    pub top_k: Option<u64>,
+    /// Lowest score a retrieved chunk may have, overriding the document's stored default
+    pub min_score: Option<f32>,
+    /// Retrieval defaults stored with documents ingested by `process_file`
+    pub document_defaults: RetrievalDefaults,
+    /// Weight (0 to 1) of the raw chunk-text vector when fusing its search with the contextual
+    /// one; when set, new collections store both vectors
+    pub raw_vector_weight: Option<f32>,
+    /// Number of paraphrases of each question also searched for, merging their results
+    /// (0 searches the question alone)
+    pub multi_query: usize,
+    /// Score the best retrieved chunk must reach for an answer to be generated at all
+    pub generation_min_score: Option<f32>,
+    /// How tokens are counted when sizing chunks and rate limiting context generation
+    pub token_counting: TokenCounting,
+    /// Chunk target size, overlap and re-split threshold
+    pub chunking: ChunkingConfig,
+    /// Rows of a CSV or TSV file put in one chunk, instead of `DEFAULT_CSV_ROWS_PER_CHUNK`
+    pub csv_rows_per_chunk: Option<usize>,
+    /// Let the rerank model order retrieved chunks by relevance before answering
+    pub rerank: bool,
+    /// Whether questions are searched as they are, through a hypothetical answer, or both
+    pub retrieval_mode: RetrievalMode,
+    /// Whether chunks are found by embedding similarity, by keyword, or both
+    pub search_mode: SearchMode,
+    /// Only retrieve chunks stored within this long before the question
+    pub ingested_within: Option<Duration>,
+    /// Reuse an answer for this long when the same question retrieves the same chunks again
+    pub answer_cache_ttl: Option<Duration>,
+    /// Index only the first this many chunks of a document, to try settings out quickly
+    pub max_chunks: Option<usize>,
+    /// Embed every text anew instead of reusing embeddings cached on disk
+    pub no_embedding_cache: bool,
+    /// Earlier question and answer pairs of an interactive session sent along with each
+    /// question, so follow-ups can refer back to them (0 answers every question on its own)
+    pub history_turns: usize,
+}
+
+/// What is embedded to search for the chunks answering a question
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RetrievalMode {
+    /// The question itself
+    #[default]
+    Direct,
+    /// A hypothetical answer written by the model (HyDE), which reads more like the chunks
+    HyDE,
+    /// Both, with the two result lists merged by reciprocal rank fusion
+    HyDEHybrid {
+        /// Weight (0 to 1) of the hypothetical answer's ranking against the question's
+        hyde_weight: f32,
+    },
+}
+
+impl FromStr for RetrievalMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "direct" => Ok(RetrievalMode::Direct),
+            "hyde" => Ok(RetrievalMode::HyDE),
+            "hyde-hybrid" => Ok(RetrievalMode::HyDEHybrid {
+                hyde_weight: DEFAULT_HYDE_WEIGHT,
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Unknown retrieval mode: {} (expected direct, hyde or hyde-hybrid)",
+                s
+            )),
+        }
+    }
+}
+
+/// How the chunks answering a question are searched for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchMode {
+    /// Embedding similarity, as set by the retrieval mode
+    #[default]
+    Vector,
+    /// Words of the question found in the chunk text, for error codes or API names
+    Keyword,
+    /// Both, with the two result lists merged by reciprocal rank fusion
+    Hybrid,
+}
+
+impl FromStr for SearchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "vector" => Ok(SearchMode::Vector),
+            "keyword" => Ok(SearchMode::Keyword),
+            "hybrid" => Ok(SearchMode::Hybrid),
+            _ => Err(anyhow::anyhow!(
+                "Unknown search mode: {} (expected vector, keyword or hybrid)",
+                s
+            )),
+        }
+    }
+}
+
+/// Token counter used for chunk sizes and rate limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenCounting {
+    /// Fast word and punctuation heuristic
+    #[default]
+    Fallback,
+    /// Byte-pair encoding, accurate for CJK text and code (needs the `tiktoken` feature)
+    Bpe,
+}
+
+impl TokenCounting {
+    /// Build the counter, falling back to the heuristic if the encoding cannot be loaded
+    fn counter(self) -> Arc<dyn TokenCounter> {
+        match self {
+            TokenCounting::Fallback => Arc::new(FallbackCounter),
+            #[cfg(feature = "tiktoken")]
+            TokenCounting::Bpe => match crate::chunking::BpeCounter::new() {
+                Ok(counter) => Arc::new(counter),
+                Err(e) => {
+                    warn!(
+                        "Failed to load the BPE encoding, counting tokens heuristically: {}",
+                        e
+                    );
+                    Arc::new(FallbackCounter)
+                }
+            },
+            #[cfg(not(feature = "tiktoken"))]
+            TokenCounting::Bpe => {
+                warn!("Built without the tiktoken feature, counting tokens heuristically");
+                Arc::new(FallbackCounter)
+            }
+        }
+    }
+}
+
+impl FromStr for TokenCounting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fallback" => Ok(TokenCounting::Fallback),
+            "bpe" if cfg!(feature = "tiktoken") => Ok(TokenCounting::Bpe),
+            "bpe" => Err(anyhow::anyhow!(
+                "BPE token counting needs a build with the tiktoken feature"
+            )),
+            _ => Err(anyhow::anyhow!(
+                "Unknown token counter: {} (expected fallback or bpe)",
+                s
+            )),
+        }
+    }
+}
+
+/// How documents are laid out in Qdrant collections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollectionMode {
+    /// One collection per document
+    #[default]
+    PerDocument,
+    /// A single collection for all documents, told apart by their `document_id` payload
+    Shared,
+}
+
+impl FromStr for CollectionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "per-document" => Ok(CollectionMode::PerDocument),
+            "shared" => Ok(CollectionMode::Shared),
+            _ => Err(anyhow::anyhow!(
+                "Unknown collection mode: {} (expected per-document or shared)",
+                s
+            )),
+        }
+    }
+}
+
+/// Gemini prices in dollars per 1,000 tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenPricing {
+    /// Price of embedding input tokens
+    pub embedding_per_1k: f64,
+    /// Price of prompt tokens sent to the context model
+    pub context_input_per_1k: f64,
+    /// Price of tokens generated by the context model
+    pub context_output_per_1k: f64,
+}
+
+impl Default for TokenPricing {
+    fn default() -> Self {
+        TokenPricing {
+            embedding_per_1k: 0.000_15,
+            context_input_per_1k: 0.000_075,
+            context_output_per_1k: 0.000_3,
+        }
+    }
+}
+
+/// Expected token usage and price of ingesting a document
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostEstimate {
+    /// Number of chunks that would be indexed
+    pub chunks: usize,
+    /// Tokens sent for embedding
+    pub embedding_tokens: usize,
+    /// Prompt tokens sent to the context model
+    pub context_input_tokens: usize,
+    /// Tokens expected back from the context model
+    pub context_output_tokens: usize,
+    /// Price of the embedding requests in dollars
+    pub embedding_cost: f64,
+    /// Price of the context requests in dollars
+    pub context_cost: f64,
+}
+
+impl CostEstimate {
+    /// Total price in dollars
+    pub fn total_cost(&self) -> f64 {
+        self.embedding_cost + self.context_cost
+    }
+}
+
+/// Formatting applied to generated answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnswerFormat {
+    /// Keep the model's markdown as is
+    #[default]
+    Markdown,
+    /// Strip markdown for display in plain terminals
+    Plain,
+}
+
+impl FromStr for AnswerFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "markdown" => Ok(AnswerFormat::Markdown),
+            "plain" => Ok(AnswerFormat::Plain),
+            _ => Err(anyhow::anyhow!(
+                "Unknown answer format: {} (expected markdown or plain)",
+                s
+            )),
+        }
+    }
+}
+
+/// What `process_file` does when the document already has a collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnExists {
+    /// Keep the existing collection untouched
+    #[default]
+    Skip,
+    /// Add the new chunks after the ones already stored
+    Append,
+    /// Index the document from scratch, deleting its old chunks once the new ones are embedded
+    Replace,
+}
+
+impl FromStr for OnExists {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(OnExists::Skip),
+            "append" => Ok(OnExists::Append),
+            "replace" => Ok(OnExists::Replace),
+            _ => Err(anyhow::anyhow!(
+                "Unknown on-exists mode: {} (expected skip, append or replace)",
+                s
+            )),
+        }
+    }
+}
+
+/// Progress of an ingest, sent by `RagEngine::process_file_with_events`
+#[derive(Debug, Clone, PartialEq)]
+pub enum IngestEvent {
+    /// The document was split into this many chunks
+    Chunked { count: usize },
+    /// Context was added to `done` of `total` chunks
+    Contextualized { done: usize, total: usize },
+    /// `done` of `total` chunks were embedded
+    Embedded { done: usize, total: usize },
+    /// This many chunks were stored in Qdrant
+    Stored { count: usize },
+    /// The ingest finished
+    Done(IngestReport),
+}
+
+/// Outcome of an ingest
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct IngestReport {
+    /// Number of chunks indexed
+    pub chunks: usize,
+    /// Index given to the first new chunk, past the stored ones when appending
+    pub first_index: u64,
+    /// The document was already indexed and left as it was
+    pub skipped: bool,
+}
+
+/// Outcome of ingesting many documents with `RagEngine::ingest_documents`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BulkIngestReport {
+    /// Number of documents indexed
+    pub indexed: usize,
+    /// Number of documents already indexed and left as they were
+    pub skipped: usize,
+    /// Number of documents whose ingest failed
+    pub failed: usize,
+    /// Number of chunks indexed over all documents
+    pub chunks: usize,
+    /// Time the whole ingest took
+    pub elapsed: Duration,
+    /// Tokens billed for the whole ingest
+    pub usage: UsageStats,
+}
+
+impl BulkIngestReport {
+    /// Count the outcome of one document's ingest
+    fn record(&mut self, report: IngestReport) {
+        if report.skipped {
+            self.skipped += 1;
+        } else {
+            self.indexed += 1;
+            self.chunks += report.chunks;
+        }
+    }
+
+    /// Add up the reports of several workers
+    fn merge(&mut self, other: BulkIngestReport) {
+        self.indexed += other.indexed;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.chunks += other.chunks;
+    }
+}
+
+/// Whether retrieved context is likely to answer a question
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContextAssessment {
+    /// The context looks good enough to answer from
+    pub sufficient: bool,
+    /// Similarity score of the best retrieved chunk, 0.0 when nothing was retrieved or chunks
+    /// are found by keyword alone
+    pub best_score: f32,
+}
+
+/// Answer to a question produced by the RAG engine
+#[derive(Debug, Clone)]
+pub struct Answer {
+    /// The generated answer text
+    pub text: String,
+    /// The exact prompt sent to the model, when `RagConfig.include_prompt` is set
+    pub prompt: Option<String>,
+    /// Retrieved chunks the answer cites, in citation order
+    pub sources: Vec<ChunkRef>,
+}
+
+/// Retrieved chunk cited by an answer through its number in the prompt, such as `[2]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkRef {
+    /// Number the chunk was given in the prompt
+    pub citation: usize,
+    pub document_id: String,
+    /// Position of the chunk within its document's chunks
+    pub chunk_index: usize,
+    /// Starting position of the chunk in the original document
+    pub start_position: usize,
+    /// Page the chunk starts on, for documents with pages such as PDFs
+    pub page: Option<usize>,
+    /// Similarity score of the chunk to the question, 0.0 when found by keyword alone
+    pub score: f32,
+    /// Share of the question's keywords the chunk contains, when found by keyword search
+    pub keyword_score: Option<f32>,
+}
+
+/// Chunks embedded between `IngestEvent::Embedded` progress events
+const EMBED_PROGRESS_CHUNKS: usize = 100;
+
+/// Number of chunks retrieved to answer a question when neither config nor document sets it
+const ANSWER_TOP_K: u64 = 4;
+
+/// Weight of the hypothetical answer's ranking in `RetrievalMode::HyDEHybrid` unless set
+pub const DEFAULT_HYDE_WEIGHT: f32 = 0.5;
+
+/// Rank offset of reciprocal rank fusion, damping the lead of the very first results
+const RRF_K: f32 = 60.0;
+
+/// Weight of the keyword ranking against the vector one in `SearchMode::Hybrid`
+const KEYWORD_WEIGHT: f32 = 0.5;
+
+/// How long a cached answer is reused unless configured otherwise
+pub const DEFAULT_ANSWER_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Tokens assumed per generated chunk context when estimating cost
+const ESTIMATED_CONTEXT_TOKENS: usize = 100;
+
+/// How far an embedding's length may stray from 1.0 and still count as normalized
+const UNIT_NORM_TOLERANCE: f32 = 0.01;
+
+/// Answer returned when retrieval finds nothing to build a context from
+const NO_RELEVANT_INFORMATION: &str = "No relevant information found in the document.";
+
+/// Answer returned when the retrieved chunks score too low to be worth a generation call
+const INSUFFICIENT_INFORMATION: &str =
+    "The document does not seem to contain enough information to answer this question.";
 
 /// RAG (Retrieval-Augmented Generation) engine
 pub struct RagEngine {
     qdrant: QdrantClient,
     gemini: GeminiClient,
     context_generator: ContextGenerator,
+    token_counter: Arc<dyn TokenCounter>,
+    answer_cache: Option<AnswerCache>,
+    /// Vector length of the embedding model, probed once
+    embedding_dimension: OnceCell<u64>,
+    /// Collection holding every document in shared mode
+    shared_collection: String,
+    config: RagConfig,
 }
 
 impl RagEngine {
     /// Create a new RAG engine
     pub fn new(qdrant: QdrantClient, gemini: GeminiClient) -> Self {
+        Self::with_config(qdrant, gemini, RagConfig::default())
+    }
+
+    /// Create a new RAG engine with custom settings
+    pub fn with_config(qdrant: QdrantClient, gemini: GeminiClient, config: RagConfig) -> Self {
+        // Install the request budget before cloning so every phase shares it
+        let gemini = match config.max_concurrent_requests {
+            Some(limit) => gemini.with_request_limit(limit),
+            None => gemini,
+        };
+        let gemini = if config.no_embedding_cache {
+            gemini.without_embedding_cache()
+        } else {
+            gemini
+        };
+
+        // Chunking and the context rate limit count tokens the same way
+        let token_counter = config.token_counting.counter();
+
         // Create a context generator using the same Gemini client
-        let context_generator = ContextGenerator::new(gemini.clone());
+        let context_generator = ContextGenerator::new(gemini.clone())
+            .with_token_counter(token_counter.clone())
+            .with_concurrency(
+                config
+                    .context_concurrency
+                    .unwrap_or(DEFAULT_CONTEXT_CONCURRENCY),
+            );
+
+        let answer_cache = config.answer_cache_ttl.map(AnswerCache::new);
+        let shared_collection = match &config.collection {
+            Some(name) => named_collection(name),
+            None => SHARED_COLLECTION.to_string(),
+        };
 
         RagEngine {
             qdrant,
             gemini,
             context_generator,
+            token_counter,
+            answer_cache,
+            embedding_dimension: OnceCell::new(),
+            shared_collection,
+            config,
         }
     }
 
-    /// Check if the collection exists
+    /// Check if a file is already indexed
+    /// A file whose ingest was interrupted before it completed does not count as indexed
     pub async fn collection_exists(&self, file_name: &str) -> Result<bool> {
-        self.qdrant.collection_exists(file_name).await
+        let (collection, _) = self.document_location(file_name);
+
+        if !self.qdrant.collection_exists(collection).await? {
+            return Ok(false);
+        }
+
+        self.qdrant.is_ingest_complete(collection, file_name).await
+    }
+
+    /// Check if any chunks of a document are stored, including those of an interrupted ingest
+    pub async fn has_document(&self, document_id: &str) -> Result<bool> {
+        let (collection, document_filter) = self.document_location(document_id);
+
+        if !self.qdrant.collection_exists(collection).await? {
+            return Ok(false);
+        }
+
+        match document_filter {
+            Some(document_id) => Ok(self
+                .qdrant
+                .count_points(collection, Some(document_id))
+                .await?
+                > 0),
+            None => Ok(true),
+        }
+    }
+
+    /// Delete a document's chunks: its whole collection, or its points of the shared collection
+    pub async fn delete_document(&self, document_id: &str) -> Result<()> {
+        let (collection, document_filter) = self.document_location(document_id);
+
+        match document_filter {
+            Some(document_id) => self.qdrant.delete_document(collection, document_id).await,
+            None => self.qdrant.delete_collection(collection).await,
+        }
+    }
+
+    /// Write every stored chunk of a document with its embeddings to `writer` as JSON lines,
+    /// returning the number of chunks written
+    pub async fn export_document(
+        &self,
+        document_id: &str,
+        writer: &mut impl Write,
+    ) -> Result<usize> {
+        let (collection, document_filter) = self.document_location(document_id);
+        self.qdrant
+            .export_jsonl(collection, document_filter, writer)
+            .await
+    }
+
+    /// Store exported chunks with their embeddings as they are, without any Gemini calls,
+    /// each in the collection its document belongs to; returns the number of chunks stored
+    /// Every embedding must have the same length, checked before any collection is created
+    pub async fn import_records(&self, records: Vec<ExportRecord>) -> Result<usize> {
+        record_dimensions(&records)?;
+
+        let mut by_collection: HashMap<String, Vec<ExportRecord>> = HashMap::new();
+        for record in records {
+            let (collection, _) = self.document_location(&record.document_id);
+            by_collection
+                .entry(collection.to_string())
+                .or_default()
+                .push(record);
+        }
+
+        let mut imported = 0;
+        for (collection, records) in by_collection {
+            imported += self.qdrant.import_records(&collection, records).await?;
+        }
+
+        Ok(imported)
+    }
+
+    /// Collection holding a document's chunks, and the document filter to apply within it
+    fn document_location<'a>(&'a self, document_id: &'a str) -> (&'a str, Option<&'a str>) {
+        match self.config.collection_mode {
+            CollectionMode::PerDocument => (document_id, None),
+            CollectionMode::Shared => (self.shared_collection(), Some(document_id)),
+        }
+    }
+
+    /// Report which payload fields the stored chunks of a document's collection are missing, or
+    /// those of the shared (or named) collection in shared mode
+    pub async fn validate_collection(&self, document_id: Option<&str>) -> Result<SchemaReport> {
+        let collection = self.checked_collection(document_id)?;
+        self.qdrant.validate_collection(collection).await
+    }
+
+    /// Backfill the derivable payload fields of a document's own collection
+    pub async fn repair_collection(&self, document_id: Option<&str>) -> Result<usize> {
+        let collection = self.checked_collection(document_id)?;
+        self.qdrant.repair_collection(collection).await
+    }
+
+    /// Collection checked by validate and repair: the document's own, or the shared one
+    fn checked_collection<'a>(&'a self, document_id: Option<&'a str>) -> Result<&'a str> {
+        match (self.config.collection_mode, document_id) {
+            (CollectionMode::Shared, _) => Ok(self.shared_collection()),
+            (CollectionMode::PerDocument, Some(document_id)) => Ok(document_id),
+            (CollectionMode::PerDocument, None) => Err(anyhow::anyhow!(
+                "Name the document whose collection is checked, or use --collection-mode shared or --collection"
+            )),
+        }
+    }
+
+    /// Collection holding every document in shared mode
+    fn shared_collection(&self) -> &str {
+        &self.shared_collection
     }
 
     /// Process a file: chunk it, generate embeddings, and store in Qdrant
-    pub async fn process_file(&self, content: String, file_name: &str) -> Result<()> {
+    /// `on_exists` decides what happens when the file already has a collection
+    pub async fn process_file(
+        &self,
+        content: String,
+        file_name: &str,
+        on_exists: OnExists,
+    ) -> Result<()> {
+        let usage_before = self.gemini.usage();
+        let report = self
+            .process_file_with_events(content, file_name, on_exists, None)
+            .await?;
+        if !report.skipped {
+            info!("Indexed with {}", self.gemini.usage().since(usage_before));
+        }
+        Ok(())
+    }
+
+    /// Process a document like `process_file`, splitting it by its MIME type, such as one
+    /// forced with `Document::from_file_with_mime`, rather than by its file extension
+    pub async fn process_document(&self, document: Document, on_exists: OnExists) -> Result<()> {
+        let usage_before = self.gemini.usage();
+        let report = self
+            .ingest(
+                document.content,
+                &document.document_id,
+                Some(&document.mime_type),
+                on_exists,
+                None,
+                None,
+            )
+            .await?;
+        if !report.skipped {
+            info!("Indexed with {}", self.gemini.usage().since(usage_before));
+        }
+        Ok(())
+    }
+
+    /// Process a file like `process_file`, sending its progress to `events` when given
+    /// Events are dropped once the receiver is gone; the ingest carries on regardless
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "process_file",
+            skip_all,
+            fields(file_name = %file_name, chunks = tracing::field::Empty)
+        )
+    )]
+    pub async fn process_file_with_events(
+        &self,
+        content: String,
+        file_name: &str,
+        on_exists: OnExists,
+        events: Option<&UnboundedSender<IngestEvent>>,
+    ) -> Result<IngestReport> {
+        self.ingest(content, file_name, None, on_exists, events, None)
+            .await
+    }
+
+    /// Ingest a document like `process_file_with_events`, indexing `chunks` when given instead
+    /// of splitting `content` again; `content` is still the context of the chunks
+    /// A known `mime_type` decides how the document is split instead of its file extension
+    async fn ingest(
+        &self,
+        content: String,
+        file_name: &str,
+        mime_type: Option<&str>,
+        on_exists: OnExists,
+        events: Option<&UnboundedSender<IngestEvent>>,
+        chunks: Option<Vec<TextChunk>>,
+    ) -> Result<IngestReport> {
+        let emit = |event: IngestEvent| {
+            if let Some(events) = events {
+                let _ = events.send(event);
+            }
+        };
+
         // We need to ensure the content string lives long enough
         let content_ref = &content;
-        // Create a new collection
-        self.qdrant.create_collection(file_name).await?;
+
+        let (collection, document_filter) = self.document_location(file_name);
+
+        // New collections are sized for the vectors of the current embedding model
+        let dimension = self.embedding_dimension().await?;
+
+        // Appended chunks continue the numbering of the stored ones
+        let mut first_index = 0;
+        // Replaced chunks are only cleared once the new ones are embedded, so a failed
+        // ingest leaves the document as it was
+        let mut replace = false;
+        if !self.qdrant.collection_exists(collection).await? {
+            // Create a new collection
+            self.qdrant
+                .create_collection(
+                    collection,
+                    dimension,
+                    self.config.raw_vector_weight.is_some(),
+                )
+                .await?;
+        } else if self.collection_exists(file_name).await? {
+            match on_exists {
+                OnExists::Skip => {
+                    info!("{} is already indexed, skipping", file_name);
+                    let report = IngestReport {
+                        skipped: true,
+                        ..IngestReport::default()
+                    };
+                    emit(IngestEvent::Done(report));
+                    return Ok(report);
+                }
+                OnExists::Append => {
+                    first_index = self
+                        .qdrant
+                        .count_points(collection, document_filter)
+                        .await?;
+                    info!("Appending to existing chunks after {} chunks", first_index);
+                }
+                OnExists::Replace => replace = true,
+            }
+        } else {
+            // A previous ingest was interrupted, so drop whatever it left behind
+            info!(
+                "Previous ingest of {} did not complete, starting over",
+                file_name
+            );
+            self.clear_document(collection, document_filter, dimension)
+                .await?;
+        }
+        // A replaced collection of its own is created again at the current size
+        if !(replace && document_filter.is_none()) {
+            self.check_vector_size(collection, dimension).await?;
+        }
 
         // Split content into chunks
-        let chunks = crate::chunking::split_into_chunks(content_ref, file_name);
-        info!("Split into {} chunks", chunks.len());
+        let mut chunks = match chunks {
+            Some(chunks) => chunks,
+            None => self.chunk_document(content_ref, file_name, mime_type)?,
+        };
+        if let Some(max_chunks) = self.config.max_chunks {
+            if chunks.len() > max_chunks {
+                info!(
+                    "Indexing a sample of the first {} of {} chunks",
+                    max_chunks,
+                    chunks.len()
+                );
+                chunks.truncate(max_chunks);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("chunks", chunks.len());
+        let total = chunks.len();
+        emit(IngestEvent::Chunked { count: total });
 
         // Generate context for each chunk
-        info!("Generating contextual information for chunks...");
         let contextualized_chunks = self
-            .context_generator
-            .contextualize_chunks(chunks, &content)
+            .contextualize(chunks, &content, |done, total| {
+                emit(IngestEvent::Contextualized { done, total })
+            })
             .await?;
         info!(
             "Generated context for {} chunks",
             contextualized_chunks.len()
         );
 
-        // Generate embeddings for contextualized chunks
+        // Generate embeddings for contextualized chunks, a group at a time to report progress
         info!("Generating embeddings for contextualized chunks...");
-        let contextual_embeddings = self
-            .gemini
-            .get_contextual_embeddings(contextualized_chunks)
+        let mut contextual_embeddings = Vec::with_capacity(total);
+        let mut remaining = contextualized_chunks;
+        while !remaining.is_empty() {
+            let rest = remaining.split_off(remaining.len().min(EMBED_PROGRESS_CHUNKS));
+            contextual_embeddings.extend(self.embed_chunks(remaining, &content).await?);
+            emit(IngestEvent::Embedded {
+                done: contextual_embeddings.len(),
+                total,
+            });
+            remaining = rest;
+        }
+
+        if replace {
+            info!("Replacing existing chunks of {}", file_name);
+            self.clear_document(collection, document_filter, dimension)
+                .await?;
+        }
+
+        // Store contextualized chunks in Qdrant
+        self.store_contextual_embeddings(contextual_embeddings, collection, first_index)
             .await?;
+        emit(IngestEvent::Stored { count: total });
 
-        // Create new chunks with contextualized text but preserve metadata
-        let mut contextualized_chunks_for_storage = Vec::new();
-        let mut embeddings = Vec::new();
+        // Only now is the file safe to skip on the next run
+        self.qdrant
+            .mark_ingest_complete(
+                collection,
+                file_name,
+                self.config.document_defaults,
+                &self.gemini.document_embedding_profile(),
+                mime_type,
+            )
+            .await?;
 
-        // Add counter for logging progress
-        let total_chunks = contextual_embeddings.len();
-        info!("Processing {} chunks with embeddings...", total_chunks);
+        let report = IngestReport {
+            chunks: total,
+            first_index,
+            skipped: false,
+        };
+        emit(IngestEvent::Done(report));
+        Ok(report)
+    }
 
-        for (i, contextual_embedding) in contextual_embeddings.into_iter().enumerate() {
-            // Create a new TextChunk with contextualized text but same metadata
-            let original_chunk = contextual_embedding.contextualized_chunk.original_chunk;
-            let contextualized_text_chunk = crate::chunking::TextChunk {
-                text: contextual_embedding
-                    .contextualized_chunk
-                    .contextualized_text,
-                token_count: contextual_embedding.contextualized_chunk.token_count,
-                document_id: original_chunk.document_id,
-                start_position: original_chunk.start_position,
-            };
+    /// Ingest many documents, given as (document id, content) pairs, into the shared collection
+    /// with up to `concurrency` documents in flight
+    /// The documents share this engine's Gemini request budget and context rate limiter;
+    /// already indexed documents are skipped, and a failed document does not stop the others
+    pub async fn ingest_documents(
+        self: &Arc<Self>,
+        documents: Vec<(String, String)>,
+        concurrency: usize,
+    ) -> Result<BulkIngestReport> {
+        if self.config.collection_mode != CollectionMode::Shared {
+            return Err(anyhow::anyhow!(
+                "Ingesting many documents needs the shared collection mode"
+            ));
+        }
+        let started = Instant::now();
+        let usage_before = self.gemini.usage();
 
-            contextualized_chunks_for_storage.push(contextualized_text_chunk);
-            embeddings.push(contextual_embedding.embedding);
+        // Create the collection up front so concurrent ingests do not race to create it
+        let dimension = self.embedding_dimension().await?;
+        if !self
+            .qdrant
+            .collection_exists(self.shared_collection())
+            .await?
+        {
+            self.qdrant
+                .create_collection(
+                    self.shared_collection(),
+                    dimension,
+                    self.config.raw_vector_weight.is_some(),
+                )
+                .await?;
+        }
 
-            // Log progress after every 5th chunk
-            if (i + 1) % 5 == 0 {
-                info!(
-                    "Progress: processed {}/{} chunks ({}%)",
-                    i + 1,
-                    total_chunks,
-                    ((i + 1) * 100) / total_chunks
-                );
-            }
+        // Workers take documents off a shared queue in order until it runs dry
+        let total = documents.len();
+        let queue = Arc::new(Mutex::new(documents.into_iter().enumerate()));
+        let mut workers = JoinSet::new();
+        for _ in 0..concurrency.clamp(1, total.max(1)) {
+            let engine = Arc::clone(self);
+            let queue = Arc::clone(&queue);
+            workers.spawn(async move {
+                let mut report = BulkIngestReport::default();
+                loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let Some((i, (document_id, content))) = next else {
+                        break;
+                    };
+
+                    info!("Ingesting {} ({}/{})", document_id, i + 1, total);
+                    match engine
+                        .process_file_with_events(content, &document_id, OnExists::Skip, None)
+                        .await
+                    {
+                        Ok(ingest) => report.record(ingest),
+                        Err(e) => {
+                            warn!("Failed to ingest {}: {:#}", document_id, e);
+                            report.failed += 1;
+                        }
+                    }
+                }
+                report
+            });
         }
 
-        // Log completion if total chunks is not a multiple of 5
-        if total_chunks % 5 != 0 {
-            info!(
-                "Progress: completed processing all {}/{} chunks (100%)",
-                total_chunks, total_chunks
-            );
+        let mut report = BulkIngestReport::default();
+        while let Some(worker) = workers.join_next().await {
+            report.merge(worker.context("Ingest worker panicked")?);
         }
+        report.elapsed = started.elapsed();
+        report.usage = self.gemini.usage().since(usage_before);
 
-        // Store contextualized chunks in Qdrant
-        self.qdrant
-            .store_chunks(contextualized_chunks_for_storage, embeddings, file_name)
+        Ok(report)
+    }
+
+    /// Remove a document's chunks, recreating its collection for vectors of `dimension`
+    /// dimensions when it has one of its own
+    async fn clear_document(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        dimension: u64,
+    ) -> Result<()> {
+        match document_filter {
+            Some(document_id) => self.qdrant.delete_document(collection, document_id).await,
+            None => {
+                self.qdrant.delete_collection(collection).await?;
+                self.qdrant
+                    .create_collection(
+                        collection,
+                        dimension,
+                        self.config.raw_vector_weight.is_some(),
+                    )
+                    .await
+            }
+        }
+    }
+
+    /// Length of the vectors of the current embedding model, checking that the fallback
+    /// model's vectors have the same length
+    /// A fallback model is only usable if its vectors fit the collection, so this is checked
+    /// before it is needed halfway through a document
+    /// Probed once per engine, so ingesting many documents does not probe for each
+    async fn embedding_dimension(&self) -> Result<u64> {
+        let dimension = self
+            .embedding_dimension
+            .get_or_try_init(|| async {
+                let dimension = self.gemini.embedding_dimension().await?;
+
+                if let Some(fallback_dimension) =
+                    self.gemini.fallback_embedding_dimension().await?
+                {
+                    if fallback_dimension != dimension {
+                        return Err(anyhow::anyhow!(
+                            "Fallback embedding model produces {}-dimensional vectors, but the embedding model produces {}",
+                            fallback_dimension,
+                            dimension
+                        ));
+                    }
+                }
+
+                Ok(dimension as u64)
+            })
             .await?;
 
-        Ok(())
+        Ok(*dimension)
     }
 
-    /// Run the query loop for a file
-    pub async fn run_query_loop(&self, file_name: &str) -> Result<()> {
+    /// Fail when a collection holds vectors of another length than `dimension`
+    async fn check_vector_size(&self, collection: &str, dimension: u64) -> Result<()> {
+        match self.qdrant.vector_size(collection).await? {
+            Some(vector_size) if vector_size != dimension => Err(anyhow::anyhow!(
+                "Collection for {} holds {}-dimensional vectors, but the embedding model produces {}; re-ingest with --on-exists replace or switch EMBEDDING_MODEL back",
+                collection,
+                vector_size,
+                dimension
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Index text appended to an already indexed document, such as new lines of a log,
+    /// without reading the rest of it again
+    /// `last_position` is where the new text starts in the document; its chunks continue
+    /// the document's chunk numbering
+    pub async fn append_text(
+        &self,
+        collection: &str,
+        document_id: &str,
+        new_text: &str,
+        last_position: usize,
+    ) -> Result<()> {
+        if !self.qdrant.collection_exists(collection).await? {
+            return Err(anyhow::anyhow!(
+                "Collection for {} does not exist, ingest the document first",
+                collection
+            ));
+        }
+        // The document is split as it was at ingest, by the MIME type given then if any
+        let mime_type = self
+            .qdrant
+            .document_mime_type(collection, document_id)
+            .await?;
+        let mime_type = mime_type.as_deref();
+        // Appended rows come without the header line naming their columns
+        if matches!(
+            document_format(document_id, mime_type),
+            DocumentFormat::Table(_)
+        ) {
+            return Err(anyhow::anyhow!(
+                "Cannot append to the table {}; ingest it again with --on-exists replace",
+                document_id
+            ));
+        }
+        let dimension = self.embedding_dimension().await?;
+        self.check_vector_size(collection, dimension).await?;
+
+        let first_index = self
+            .qdrant
+            .count_points(collection, Some(document_id))
+            .await?;
+        let defaults = self
+            .qdrant
+            .retrieval_defaults(collection, document_id)
+            .await?;
+
+        let mut chunks = self.chunk_document(new_text, document_id, mime_type)?;
+        for chunk in &mut chunks {
+            chunk.start_position += last_position;
+        }
         info!(
-            "Ready to answer questions about {}. Type 'exit' to quit.",
-            file_name
+            "Appending {} chunks to {} after {} chunks",
+            chunks.len(),
+            document_id,
+            first_index
         );
 
-        let stdin = io::stdin();
-        let mut stdout = io::stdout();
-        let mut buffer = String::new();
+        // Only the new text is at hand, so it is the context for its own chunks
+        let contextualized_chunks = self.contextualize(chunks, new_text, |_, _| {}).await?;
+        let contextual_embeddings = self.embed_chunks(contextualized_chunks, new_text).await?;
 
-        loop {
-            print!("\nYour question: ");
-            stdout.flush()?;
+        self.store_contextual_embeddings(contextual_embeddings, collection, first_index)
+            .await?;
 
-            buffer.clear();
-            stdin.read_line(&mut buffer)?;
+        // Flag the new chunks too, keeping the defaults and MIME type stored at ingest
+        self.qdrant
+            .mark_ingest_complete(
+                collection,
+                document_id,
+                defaults,
+                &self.gemini.document_embedding_profile(),
+                mime_type,
+            )
+            .await
+    }
 
-            let question = buffer.trim();
+    /// Estimate the tokens and price of ingesting a document with the current settings
+    /// A known `mime_type` decides how the document is split instead of its file extension
+    pub fn estimate_cost(
+        &self,
+        content: &str,
+        document_id: &str,
+        mime_type: Option<&str>,
+    ) -> Result<CostEstimate> {
+        let chunks = self.chunk_document(content, document_id, mime_type)?;
+        let pricing = &self.config.pricing;
 
-            if question.to_lowercase() == "exit" {
-                info!("Goodbye!");
-                break;
+        let mut embedding_tokens = 0;
+        let mut context_input_tokens = 0;
+        let mut context_output_tokens = 0;
+        for chunk in &chunks {
+            match self.config.context_mode {
+                ContextMode::LlmGenerated => {
+                    // Every chunk sends the whole document to the context model
+                    let prompt = context_prompt(content, &chunk.text);
+                    context_input_tokens += self.token_counter.count_tokens(&prompt);
+                    context_output_tokens += ESTIMATED_CONTEXT_TOKENS;
+                    embedding_tokens += chunk.token_count + ESTIMATED_CONTEXT_TOKENS;
+                }
+                ContextMode::TitleAndHeading => {
+                    embedding_tokens +=
+                        title_and_heading_context(chunk.clone(), content).token_count;
+                }
+                ContextMode::None => embedding_tokens += chunk.token_count,
             }
 
-            // Get embedding for the question
-            let question_embedding = self.gemini.get_embedding(question).await?;
+            // The raw chunk text is embedded a second time for its own vector
+            if self.config.raw_vector_weight.is_some() {
+                embedding_tokens += chunk.token_count;
+            }
+        }
 
-            // Retrieve relevant chunks
-            let chunks = self.qdrant.search(question_embedding, file_name, 4).await?;
+        Ok(CostEstimate {
+            chunks: chunks.len(),
+            embedding_tokens,
+            context_input_tokens,
+            context_output_tokens,
+            embedding_cost: embedding_tokens as f64 / 1000.0 * pricing.embedding_per_1k,
+            context_cost: context_input_tokens as f64 / 1000.0 * pricing.context_input_per_1k
+                + context_output_tokens as f64 / 1000.0 * pricing.context_output_per_1k,
+        })
+    }
 
-            if chunks.is_empty() {
-                info!("No relevant information found in the document.");
-                continue;
-            }
+    /// Split a document into chunks, dropping the ones rejected by the quality filter
+    /// Markdown documents are split along their sections, and tables by their rows
+    fn chunk_document(
+        &self,
+        content: &str,
+        document_id: &str,
+        mime_type: Option<&str>,
+    ) -> Result<Vec<TextChunk>> {
+        let mut chunks = match document_format(document_id, mime_type) {
+            DocumentFormat::Table(delimiter) => crate::chunking::split_csv_rows(
+                content,
+                document_id,
+                delimiter,
+                self.config
+                    .csv_rows_per_chunk
+                    .unwrap_or(crate::chunking::DEFAULT_CSV_ROWS_PER_CHUNK),
+                &*self.token_counter,
+            )
+            .with_context(|| format!("Failed to parse {} as a table", document_id))?,
+            DocumentFormat::Markdown => crate::chunking::split_markdown(
+                content,
+                document_id,
+                &self.config.chunking,
+                &*self.token_counter,
+            )?,
+            DocumentFormat::Text => crate::chunking::split_into_chunks_with_config(
+                content,
+                document_id,
+                &self.config.chunking,
+                &*self.token_counter,
+            )?,
+        };
+        info!("Split into {} chunks", chunks.len());
 
-            // Create context from chunks
-            let context = chunks
-                .iter()
-                .map(|chunk| chunk.text.clone())
-                .collect::<Vec<String>>()
-                .join("\n\n");
+        // Filter out chunks that are mostly numbers or noise
+        if let Some(filter) = &self.config.quality_filter {
+            let total_chunks = chunks.len();
+            chunks.retain(|chunk| filter.accepts(chunk));
+            info!(
+                "Dropped {} of {} chunks below the quality threshold",
+                total_chunks - chunks.len(),
+                total_chunks
+            );
+        }
 
-            // Generate answer
-            let answer = self.gemini.generate_answer(&context, question).await?;
+        Ok(chunks)
+    }
 
-            info!("\n{}", answer);
+    /// Regenerate context and embeddings for an already indexed file without re-chunking
+    /// Chunks are read back from Qdrant and overwritten in place under the same point ids
+    pub async fn recontextualize(&self, file_name: &str) -> Result<()> {
+        let (collection, document_filter) = self.document_location(file_name);
+        let chunks = self
+            .qdrant
+            .scroll_original_chunks(collection, document_filter)
+            .await?;
+        if chunks.is_empty() {
+            return Err(anyhow::anyhow!("No stored chunks found for {}", file_name));
         }
+        info!("Loaded {} stored chunks", chunks.len());
+        let dimension = self.embedding_dimension().await?;
+        self.check_vector_size(collection, dimension).await?;
 
-        Ok(())
+        // The context prompt needs the whole document, so rebuild it from the chunks
+        let source_document = crate::chunking::merge_chunk_texts(&chunks);
+        let defaults = self
+            .qdrant
+            .retrieval_defaults(collection, file_name)
+            .await?;
+        let mime_type = self
+            .qdrant
+            .document_mime_type(collection, file_name)
+            .await?;
+
+        let contextualized_chunks = self
+            .contextualize(chunks, &source_document, |_, _| {})
+            .await?;
+
+        info!("Generating embeddings for contextualized chunks...");
+        let contextual_embeddings = self
+            .embed_chunks(contextualized_chunks, &source_document)
+            .await?;
+
+        // Chunks are in their original order, so they are stored under the same ids
+        self.store_contextual_embeddings(contextual_embeddings, collection, 0)
+            .await?;
+
+        // Upserting replaced the payloads, including the completion marker and defaults
+        self.qdrant
+            .mark_ingest_complete(
+                collection,
+                file_name,
+                defaults,
+                &self.gemini.document_embedding_profile(),
+                mime_type.as_deref(),
+            )
+            .await
+    }
+
+    /// Compute the token distribution of a document's stored chunks
+    /// Token counts are recomputed from the original chunk text
+    pub async fn chunk_stats(&self, file_name: &str) -> Result<ChunkStats> {
+        let (collection, document_filter) = self.document_location(file_name);
+        let chunks = self
+            .qdrant
+            .scroll_original_chunks(collection, document_filter)
+            .await?;
+
+        Ok(ChunkStats::from_chunks(&chunks))
+    }
+
+    /// Rebuild every indexed document from its stored chunks under the current settings
+    /// Returns the number of rebuilt documents; a document that fails keeps its old chunks and
+    /// does not stop the others, but the run fails once all have been tried
+    pub async fn reindex_all(&self) -> Result<usize> {
+        let documents = match self.config.collection_mode {
+            CollectionMode::PerDocument => self.qdrant.list_collections().await?,
+            CollectionMode::Shared => {
+                if self
+                    .qdrant
+                    .collection_exists(self.shared_collection())
+                    .await?
+                {
+                    self.qdrant.list_documents(self.shared_collection()).await?
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+        info!("Found {} documents to reindex", documents.len());
+
+        // Read every document back first so a collection without original text aborts
+        // the run before anything is deleted
+        let mut contents = Vec::with_capacity(documents.len());
+        for document_id in documents {
+            let (collection, document_filter) = self.document_location(&document_id);
+            let chunks = self
+                .qdrant
+                .scroll_original_chunks(collection, document_filter)
+                .await?;
+            let content = crate::chunking::merge_chunk_texts(&chunks);
+            // Rows rendered as "Header: value" lines cannot be parsed as a table again, so
+            // tables keep the rows of their stored chunks
+            let table_chunks = chunks
+                .iter()
+                .any(|chunk| chunk.row.is_some())
+                .then_some(chunks);
+            // A document given a MIME type at ingest is split by it again
+            let mime_type = self
+                .qdrant
+                .document_mime_type(collection, &document_id)
+                .await?;
+            contents.push((document_id, content, mime_type, table_chunks));
+        }
+
+        let total = contents.len();
+        let mut failed = Vec::new();
+        for (i, (document_id, content, mime_type, table_chunks)) in contents.into_iter().enumerate()
+        {
+            info!("Reindexing {} ({}/{})", document_id, i + 1, total);
+            let usage_before = self.gemini.usage();
+            match self
+                .ingest(
+                    content,
+                    &document_id,
+                    mime_type.as_deref(),
+                    OnExists::Replace,
+                    None,
+                    table_chunks,
+                )
+                .await
+            {
+                Ok(_) => info!("Indexed with {}", self.gemini.usage().since(usage_before)),
+                Err(e) => {
+                    warn!("Failed to reindex {}: {:#}", document_id, e);
+                    failed.push(document_id);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Failed to reindex {} of {} documents, which keep their previous chunks: {}",
+                failed.len(),
+                total,
+                failed.join(", ")
+            ));
+        }
+        Ok(total)
+    }
+
+    /// Add context to chunks according to the configured context mode, calling
+    /// `on_progress(done, total)` as chunks get their context
+    async fn contextualize(
+        &self,
+        chunks: Vec<TextChunk>,
+        source_document: &str,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<ContextualizedChunk>> {
+        let total = chunks.len();
+        let contextualized_chunks: Vec<ContextualizedChunk> = match self.config.context_mode {
+            ContextMode::LlmGenerated => {
+                info!("Generating contextual information for chunks...");
+                return self
+                    .context_generator
+                    .contextualize_chunks_with_progress(chunks, source_document, on_progress)
+                    .await;
+            }
+            ContextMode::TitleAndHeading => {
+                info!("Adding title and heading context to chunks...");
+                chunks
+                    .into_iter()
+                    .map(|chunk| title_and_heading_context(chunk, source_document))
+                    .collect()
+            }
+            ContextMode::None => chunks
+                .into_iter()
+                .map(ContextualizedChunk::without_context)
+                .collect(),
+        };
+
+        // Structural context is instant, so it completes in one step
+        on_progress(total, total);
+        Ok(contextualized_chunks)
+    }
+
+    /// Embed contextualized chunks, prefixing the embedded text with the document title and
+    /// heading when enabled; the stored chunk text stays as it is
+    async fn embed_chunks(
+        &self,
+        chunks: Vec<ContextualizedChunk>,
+        source_document: &str,
+    ) -> Result<Vec<ContextualEmbedding>> {
+        if !self.config.embedding_metadata_prefix {
+            return self.gemini.get_contextual_embeddings(chunks).await;
+        }
+
+        let texts: Vec<String> = chunks
+            .iter()
+            .map(|chunk| {
+                let prefix = metadata_prefix(&chunk.original_chunk, source_document);
+                format!("{}{}", prefix, chunk.contextualized_text)
+            })
+            .collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let embeddings = self.gemini.get_document_embeddings(&texts).await?;
+
+        Ok(embeddings
+            .into_iter()
+            .zip(chunks)
+            .map(|(embedding, contextualized_chunk)| ContextualEmbedding {
+                embedding,
+                contextualized_chunk,
+            })
+            .collect())
+    }
+
+    /// Split contextual embeddings into chunks and vectors and store them in Qdrant
+    /// Chunks are numbered from `first_index`
+    async fn store_contextual_embeddings(
+        &self,
+        contextual_embeddings: Vec<ContextualEmbedding>,
+        file_name: &str,
+        first_index: u64,
+    ) -> Result<()> {
+        if let Some(sample) = contextual_embeddings.first() {
+            if let Some(norm) = dot_distance_norm(
+                self.qdrant.distance(),
+                self.qdrant.normalizes_embeddings(),
+                &sample.embedding,
+            ) {
+                warn!(
+                    "Collection uses dot-product distance but embeddings are not unit length (norm {:.3}); enable embedding normalization or use cosine distance",
+                    norm
+                );
+            }
+        }
+
+        let mut contextualized_chunks = Vec::new();
+        let mut embeddings = Vec::new();
+
+        // Add counter for logging progress
+        let total_chunks = contextual_embeddings.len();
+        info!("Processing {} chunks with embeddings...", total_chunks);
+
+        for (i, contextual_embedding) in contextual_embeddings.into_iter().enumerate() {
+            contextualized_chunks.push(contextual_embedding.contextualized_chunk);
+            embeddings.push(contextual_embedding.embedding);
+
+            // Log progress after every 5th chunk
+            if (i + 1) % 5 == 0 {
+                info!(
+                    "Progress: processed {}/{} chunks ({}%)",
+                    i + 1,
+                    total_chunks,
+                    ((i + 1) * 100) / total_chunks
+                );
+            }
+        }
+
+        // Log completion if total chunks is not a multiple of 5
+        if total_chunks % 5 != 0 {
+            info!(
+                "Progress: completed processing all {}/{} chunks (100%)",
+                total_chunks, total_chunks
+            );
+        }
+
+        // A switched model or misaligned response must not reach the collection
+        check_embedding_dimensions(&embeddings)?;
+
+        // Collections with raw vectors also get an embedding of each chunk's original text
+        let raw_embeddings = if self.qdrant.has_raw_vectors(file_name).await? {
+            info!("Generating embeddings for raw chunk text...");
+            let texts: Vec<&str> = contextualized_chunks
+                .iter()
+                .map(|chunk| chunk.original_chunk.text.as_str())
+                .collect();
+            let raw_embeddings = self.gemini.get_document_embeddings(&texts).await?;
+            check_embedding_dimensions(&raw_embeddings)?;
+            Some(raw_embeddings)
+        } else {
+            None
+        };
+
+        self.qdrant
+            .store_chunks(
+                contextualized_chunks,
+                embeddings,
+                raw_embeddings,
+                file_name,
+                first_index,
+            )
+            .await
+    }
+
+    /// Retrieve the chunks of a file most similar to a question, with their scores
+    pub async fn retrieve(
+        &self,
+        file_name: &str,
+        question: &str,
+        top_k: u64,
+    ) -> Result<Vec<ScoredChunk>> {
+        let (collection, document_filter) = self.document_location(file_name);
+        self.retrieve_from(
+            collection,
+            document_filter,
+            question,
+            top_k,
+            self.config.min_score,
+        )
+        .await
+    }
+
+    /// Retrieve the chunks of a collection best matching a question, optionally of one document
+    /// The search mode decides whether chunks are found by embedding, by keyword, or both;
+    /// `min_score` only applies to embedding similarity
+    async fn retrieve_from(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        question: &str,
+        top_k: u64,
+        min_score: Option<f32>,
+    ) -> Result<Vec<ScoredChunk>> {
+        let filter = self.search_filter(document_filter);
+        match self.config.search_mode {
+            SearchMode::Vector => {
+                self.retrieve_vector(collection, document_filter, question, top_k, min_score)
+                    .await
+            }
+            SearchMode::Keyword => {
+                self.qdrant
+                    .search_keyword(question, collection, top_k, filter)
+                    .await
+            }
+            SearchMode::Hybrid => {
+                let vector = self
+                    .retrieve_vector(collection, document_filter, question, top_k, min_score)
+                    .await?;
+                let keyword = self
+                    .qdrant
+                    .search_keyword(question, collection, top_k, filter)
+                    .await?;
+                Ok(rrf_fuse_scored_chunks(
+                    vector,
+                    keyword,
+                    KEYWORD_WEIGHT,
+                    top_k,
+                ))
+            }
+        }
+    }
+
+    /// Retrieve the chunks of a collection most similar to a question by embedding
+    /// The retrieval mode decides whether the question, a hypothetical answer to it, or both
+    /// are searched
+    async fn retrieve_vector(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        question: &str,
+        top_k: u64,
+        min_score: Option<f32>,
+    ) -> Result<Vec<ScoredChunk>> {
+        let raw_vectors = self.qdrant.has_raw_vectors(collection).await?;
+        // Questions are embedded to match the stored chunks, whatever the current settings
+        let profile = self
+            .qdrant
+            .embedding_profile(collection, document_filter)
+            .await?;
+        let profile = profile.as_ref();
+
+        let hyde_weight = match self.config.retrieval_mode {
+            RetrievalMode::Direct => {
+                return self
+                    .retrieve_direct(
+                        collection,
+                        document_filter,
+                        question,
+                        profile,
+                        raw_vectors,
+                        top_k,
+                        min_score,
+                    )
+                    .await;
+            }
+            RetrievalMode::HyDE => {
+                let answer_embedding = self
+                    .hypothetical_answer_embedding(question, profile)
+                    .await?;
+                return self
+                    .search_embedding(
+                        collection,
+                        document_filter,
+                        answer_embedding,
+                        raw_vectors,
+                        top_k,
+                        min_score,
+                    )
+                    .await;
+            }
+            RetrievalMode::HyDEHybrid { hyde_weight } => hyde_weight,
+        };
+
+        let direct = self
+            .retrieve_direct(
+                collection,
+                document_filter,
+                question,
+                profile,
+                raw_vectors,
+                top_k,
+                min_score,
+            )
+            .await?;
+
+        // Without a hypothetical answer, the question's own results still stand
+        let answer_embedding = match self.hypothetical_answer_embedding(question, profile).await {
+            Ok(answer_embedding) => answer_embedding,
+            Err(e) => {
+                warn!(
+                    "Failed to write a hypothetical answer, searching the question alone: {}",
+                    e
+                );
+                return Ok(direct);
+            }
+        };
+        let hyde = self
+            .search_embedding(
+                collection,
+                document_filter,
+                answer_embedding,
+                raw_vectors,
+                top_k,
+                min_score,
+            )
+            .await?;
+
+        Ok(rrf_fuse_scored_chunks(direct, hyde, hyde_weight, top_k))
+    }
+
+    /// Embed a hypothetical answer to a question, written by the model
+    async fn hypothetical_answer_embedding(
+        &self,
+        question: &str,
+        profile: Option<&EmbeddingProfile>,
+    ) -> Result<Embedding> {
+        let answer = self.gemini.hypothetical_answer(question).await?;
+        debug!("Hypothetical answer: {}", answer);
+        // The answer stands in for a chunk, so it is embedded like one
+        match profile {
+            Some(profile) => {
+                self.gemini
+                    .get_document_embedding_like(&answer, profile)
+                    .await
+            }
+            None => self.gemini.get_document_embedding(&answer).await,
+        }
+    }
+
+    /// Retrieve the chunks most similar to the question itself, embedded to match `profile`
+    /// With `multi_query` set, paraphrases of the question are searched as well and each chunk
+    /// keeps its best score
+    #[allow(clippy::too_many_arguments)]
+    async fn retrieve_direct(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        question: &str,
+        profile: Option<&EmbeddingProfile>,
+        raw_vectors: bool,
+        top_k: u64,
+        min_score: Option<f32>,
+    ) -> Result<Vec<ScoredChunk>> {
+        if self.config.multi_query == 0 {
+            // Get embedding for the question
+            let question_embedding = match profile {
+                Some(profile) => self
+                    .gemini
+                    .get_query_embeddings_like(&[question], profile)
+                    .await?
+                    .pop()
+                    .context("No embedding returned for the question")?,
+                None => self.gemini.get_query_embedding(question).await?,
+            };
+            return self
+                .search_embedding(
+                    collection,
+                    document_filter,
+                    question_embedding,
+                    raw_vectors,
+                    top_k,
+                    min_score,
+                )
+                .await;
+        }
+
+        // A failed rephrasing only narrows retrieval back to the question itself
+        let mut questions = vec![question.to_string()];
+        match self
+            .gemini
+            .paraphrase_question(question, self.config.multi_query)
+            .await
+        {
+            Ok(paraphrases) => questions.extend(paraphrases),
+            Err(e) => warn!(
+                "Failed to paraphrase the question, searching it alone: {}",
+                e
+            ),
+        }
+
+        let questions: Vec<&str> = questions.iter().map(String::as_str).collect();
+        let question_embeddings = match profile {
+            Some(profile) => {
+                self.gemini
+                    .get_query_embeddings_like(&questions, profile)
+                    .await?
+            }
+            None => self.gemini.get_query_embeddings(&questions).await?,
+        };
+
+        let mut results = Vec::with_capacity(question_embeddings.len());
+        for question_embedding in question_embeddings {
+            results.push(
+                self.search_embedding(
+                    collection,
+                    document_filter,
+                    question_embedding,
+                    raw_vectors,
+                    top_k,
+                    min_score,
+                )
+                .await?,
+            );
+        }
+
+        Ok(merge_scored_chunks(results, top_k))
+    }
+
+    /// Search a collection for the chunks closest to an embedded question
+    /// In collections with raw vectors, the contextual and raw searches are fused by
+    /// `raw_vector_weight`
+    async fn search_embedding(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        question_embedding: Embedding,
+        raw_vectors: bool,
+        top_k: u64,
+        min_score: Option<f32>,
+    ) -> Result<Vec<ScoredChunk>> {
+        let filter = self.search_filter(document_filter);
+        if !raw_vectors {
+            return self
+                .qdrant
+                .search_filtered(
+                    question_embedding,
+                    collection,
+                    top_k,
+                    filter,
+                    min_score,
+                    None,
+                )
+                .await;
+        }
+
+        let raw_weight = self.config.raw_vector_weight.unwrap_or(0.0);
+        if raw_weight <= 0.0 {
+            return self
+                .qdrant
+                .search_filtered(
+                    question_embedding,
+                    collection,
+                    top_k,
+                    filter,
+                    min_score,
+                    Some(CONTEXTUAL_VECTOR),
+                )
+                .await;
+        }
+
+        // The score threshold applies to the fused score, so neither search filters by it
+        let contextual = self
+            .qdrant
+            .search_filtered(
+                question_embedding.clone(),
+                collection,
+                top_k,
+                filter,
+                None,
+                Some(CONTEXTUAL_VECTOR),
+            )
+            .await?;
+        let raw = self
+            .qdrant
+            .search_filtered(
+                question_embedding,
+                collection,
+                top_k,
+                filter,
+                None,
+                Some(RAW_VECTOR),
+            )
+            .await?;
+
+        Ok(fuse_scored_chunks(
+            contextual, raw, raw_weight, top_k, min_score,
+        ))
+    }
+
+    /// Payload conditions of a search: the document, and the ingest time window if configured
+    fn search_filter<'a>(&self, document_filter: Option<&'a str>) -> SearchFilter<'a> {
+        let ingested_after = self.config.ingested_within.map(|within| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .saturating_sub(within)
+                .as_secs()
+        });
+
+        SearchFilter {
+            document_id: document_filter,
+            ingested_after,
+            ingested_before: None,
+        }
+    }
+
+    /// Answer a question using the most relevant chunks of a file
+    pub async fn answer(&self, file_name: &str, question: &str) -> Result<Answer> {
+        let (collection, document_filter) = self.document_location(file_name);
+        let retrieval = self.document_retrieval(collection, file_name).await?;
+        let system_instruction = instruction_with_style(
+            self.config.system_instruction.as_deref(),
+            self.config.answer_style.as_deref(),
+        );
+        self.answer_from(
+            collection,
+            document_filter,
+            question,
+            retrieval,
+            system_instruction.as_deref(),
+            None,
+            &[],
+        )
+        .await
+    }
+
+    /// Answer a single question about a file, returning just the answer text
+    pub async fn answer_question(&self, file_name: &str, question: &str) -> Result<String> {
+        Ok(self.answer(file_name, question).await?.text)
+    }
+
+    /// Retrieval settings for a document: configured values win over its stored defaults
+    async fn document_retrieval(
+        &self,
+        collection: &str,
+        document_id: &str,
+    ) -> Result<RetrievalDefaults> {
+        let stored = if self.config.top_k.is_some() && self.config.min_score.is_some() {
+            RetrievalDefaults::default()
+        } else {
+            self.qdrant
+                .retrieval_defaults(collection, document_id)
+                .await?
+        };
+
+        Ok(RetrievalDefaults {
+            top_k: self.config.top_k.or(stored.top_k),
+            min_score: self.config.min_score.or(stored.min_score),
+        })
+    }
+
+    /// Answer a question from the most relevant chunks of all documents in the shared collection
+    pub async fn answer_across_documents(&self, question: &str) -> Result<Answer> {
+        if self.config.collection_mode != CollectionMode::Shared {
+            return Err(anyhow::anyhow!(
+                "Answering across documents needs the shared collection mode"
+            ));
+        }
+
+        // Documents may store different defaults, so only configured values apply here
+        let retrieval = RetrievalDefaults {
+            top_k: self.config.top_k,
+            min_score: self.config.min_score,
+        };
+        let system_instruction = instruction_with_style(
+            self.config.system_instruction.as_deref(),
+            self.config.answer_style.as_deref(),
+        );
+        self.answer_from(
+            self.shared_collection(),
+            None,
+            question,
+            retrieval,
+            system_instruction.as_deref(),
+            None,
+            &[],
+        )
+        .await
+    }
+
+    /// Answer a question from a collection, optionally restricted to one document
+    /// With `on_delta`, a Markdown answer is streamed to it piece by piece as it is generated
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                collection = %collection,
+                document = document_filter,
+                chunks = tracing::field::Empty
+            )
+        )
+    )]
+    #[allow(clippy::too_many_arguments)]
+    async fn answer_from(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        question: &str,
+        retrieval: RetrievalDefaults,
+        system_instruction: Option<&str>,
+        on_delta: Option<&mut dyn FnMut(&str)>,
+        history: &[ConversationTurn],
+    ) -> Result<Answer> {
+        // A follow-up may lean on earlier turns ("what about its pricing?"), so it is searched
+        // for in a form that stands on its own
+        let search_question = if history.is_empty() {
+            question.to_string()
+        } else {
+            match self.gemini.standalone_question(history, question).await {
+                Ok(standalone) => {
+                    debug!("Searching for the follow-up question as: {}", standalone);
+                    standalone
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to rewrite the follow-up question, searching for it as asked: {:#}",
+                        e
+                    );
+                    question.to_string()
+                }
+            }
+        };
+
+        // Retrieve relevant chunks
+        let mut chunks = self
+            .retrieve_from(
+                collection,
+                document_filter,
+                &search_question,
+                retrieval.top_k.unwrap_or(ANSWER_TOP_K),
+                retrieval.min_score,
+            )
+            .await?;
+
+        // A near-exact match answers on its own; more chunks would only add noise and cost
+        // Fused rankings may put a keyword match first, so only a similarity ranking counts
+        let top_score = top_similarity(self.config.search_mode, &chunks);
+        if let (Some(threshold), Some(top_score), SearchMode::Vector) = (
+            self.config.high_confidence_single_chunk,
+            top_score,
+            self.config.search_mode,
+        ) {
+            if top_score >= threshold {
+                info!(
+                    "Top chunk score {:.3} reaches {:.3}, answering from it alone",
+                    top_score, threshold
+                );
+                chunks.truncate(1);
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("chunks", chunks.len());
+
+        if chunks.is_empty() {
+            return Ok(Answer {
+                text: NO_RELEVANT_INFORMATION.to_string(),
+                prompt: None,
+                sources: Vec::new(),
+            });
+        }
+
+        // Hopeless retrieval is not worth a call to the generation model
+        if let (Some(threshold), Some(top_score)) = (self.config.generation_min_score, top_score) {
+            if top_score < threshold {
+                info!(
+                    "Top chunk score {:.3} is below {:.3}, not generating an answer",
+                    top_score, threshold
+                );
+                return Ok(Answer {
+                    text: INSUFFICIENT_INFORMATION.to_string(),
+                    prompt: None,
+                    sources: Vec::new(),
+                });
+            }
+        }
+
+        // The same question over the same retrieved chunks gets the same answer, unless earlier
+        // turns of a conversation shape it
+        let cache_key = self
+            .answer_cache
+            .as_ref()
+            .filter(|_| history.is_empty())
+            .map(|_| {
+                answer_cache_key(
+                    collection,
+                    document_filter,
+                    question,
+                    &chunks,
+                    system_instruction,
+                )
+            });
+        if let (Some(cache), Some(key)) = (&self.answer_cache, cache_key) {
+            if let Some(answer) = cache.get(key) {
+                info!("Reusing the cached answer to this question");
+                return Ok(answer);
+            }
+        }
+
+        if self.config.rerank {
+            chunks = self.rerank_chunks(&search_question, chunks).await;
+        }
+
+        // Create context from chunks
+        let context = join_chunk_texts(&chunks);
+
+        let prompt = self
+            .config
+            .include_prompt
+            .then(|| answer_prompt(&context, question));
+
+        // Generate answer
+        let model = self
+            .config
+            .answer_model
+            .as_deref()
+            .unwrap_or(&self.gemini.config().generate_model);
+        let text = match (on_delta, self.config.answer_format) {
+            // Plain answers are rewritten as a whole, so only Markdown can be streamed
+            (Some(on_delta), AnswerFormat::Markdown) => {
+                if let Some(prompt) = &prompt {
+                    info!("Prompt:\n{}", prompt);
+                }
+                let deltas = self
+                    .gemini
+                    .generate_answer_stream(
+                        &context,
+                        question,
+                        Some(model),
+                        system_instruction,
+                        history,
+                    )
+                    .await?;
+                let mut deltas = pin!(deltas);
+                let mut text = String::new();
+                while let Some(delta) = deltas.next().await {
+                    let delta = delta?;
+                    on_delta(&delta);
+                    text.push_str(&delta);
+                }
+                text
+            }
+            (_, format) => {
+                let text = self
+                    .gemini
+                    .generate_answer(&context, question, Some(model), system_instruction, history)
+                    .await?;
+                match format {
+                    AnswerFormat::Markdown => text,
+                    AnswerFormat::Plain => strip_markdown(&text),
+                }
+            }
+        };
+
+        if let Some(trace_dir) = &self.config.trace_dir {
+            let trace = AnswerTrace {
+                question,
+                collection,
+                document_filter,
+                chunks: chunks
+                    .iter()
+                    .map(|scored_chunk| TracedChunk {
+                        document_id: &scored_chunk.chunk.document_id,
+                        chunk_index: scored_chunk.chunk_index,
+                        score: scored_chunk.score,
+                        text: &scored_chunk.chunk.text,
+                    })
+                    .collect(),
+                prompt: answer_prompt(&context, question),
+                system_instruction,
+                model,
+                generation: ANSWER_GENERATION,
+                answer: &text,
+            };
+            write_trace(trace_dir, &trace)?;
+        }
+
+        let sources = cited_sources(&text, chunks.len())
+            .into_iter()
+            .map(|citation| {
+                let scored_chunk = &chunks[citation - 1];
+                ChunkRef {
+                    citation,
+                    document_id: scored_chunk.chunk.document_id.clone(),
+                    chunk_index: scored_chunk.chunk_index,
+                    start_position: scored_chunk.chunk.start_position,
+                    page: scored_chunk.chunk.page,
+                    score: scored_chunk.score,
+                    keyword_score: scored_chunk.keyword_score,
+                }
+            })
+            .collect();
+
+        let answer = Answer {
+            text,
+            prompt,
+            sources,
+        };
+        if let (Some(cache), Some(key)) = (&self.answer_cache, cache_key) {
+            cache.insert(key, answer.clone());
+        }
+
+        Ok(answer)
+    }
+
+    /// Order chunks by the rerank model's judgement of their relevance to a question
+    /// A failed rerank keeps the retrieval order
+    async fn rerank_chunks(&self, question: &str, chunks: Vec<ScoredChunk>) -> Vec<ScoredChunk> {
+        if chunks.len() < 2 {
+            return chunks;
+        }
+
+        let passages: Vec<&str> = chunks
+            .iter()
+            .map(|scored_chunk| scored_chunk.chunk.text.as_str())
+            .collect();
+        let order = match self.gemini.rerank(question, &passages).await {
+            Ok(order) => order,
+            Err(e) => {
+                warn!("Failed to rerank chunks, keeping retrieval order: {}", e);
+                return chunks;
+            }
+        };
+
+        let mut chunks: Vec<Option<ScoredChunk>> = chunks.into_iter().map(Some).collect();
+        order.into_iter().filter_map(|i| chunks[i].take()).collect()
+    }
+
+    /// Judge whether the retrieved context for a question is likely to contain its answer,
+    /// without generating the answer
+    pub async fn assess_context(
+        &self,
+        collection: &str,
+        question: &str,
+    ) -> Result<ContextAssessment> {
+        let chunks = self.retrieve(collection, question, ANSWER_TOP_K).await?;
+
+        if chunks.is_empty() {
+            return Ok(ContextAssessment {
+                sufficient: false,
+                best_score: 0.0,
+            });
+        }
+        let top_score = top_similarity(self.config.search_mode, &chunks);
+
+        let mut sufficient = self
+            .config
+            .sufficiency_score
+            .zip(top_score)
+            .is_none_or(|(threshold, top_score)| top_score >= threshold);
+
+        // Only pay for a model call when the score alone does not rule the context out
+        if sufficient && self.config.sufficiency_check_with_model {
+            let context = join_chunk_texts(&chunks);
+            sufficient = self
+                .gemini
+                .context_contains_answer(&context, question, self.config.answer_model.as_deref())
+                .await?;
+        }
+
+        Ok(ContextAssessment {
+            sufficient,
+            best_score: top_score.unwrap_or(0.0),
+        })
+    }
+
+    /// Run the query loop for a file
+    /// When `output` is set, each question and answer is also appended to that file
+    /// `/system <text>` sets the system instruction for the rest of the session and `/style <text>`
+    /// the answer style; either command without text clears the setting
+    pub async fn run_query_loop(&self, file_name: &str, output: Option<&Path>) -> Result<()> {
+        info!(
+            "Ready to answer questions about {}. Type 'exit' to quit.",
+            file_name
+        );
+
+        let (collection, document_filter) = self.document_location(file_name);
+        let retrieval = self.document_retrieval(collection, file_name).await?;
+        self.query_loop(collection, document_filter, retrieval, output)
+            .await
+    }
+
+    /// Run the interactive question loop over all documents in the shared collection
+    pub async fn run_query_loop_across_documents(&self, output: Option<&Path>) -> Result<()> {
+        if self.config.collection_mode != CollectionMode::Shared {
+            return Err(anyhow::anyhow!(
+                "Answering across documents needs the shared collection mode"
+            ));
+        }
+
+        info!("Ready to answer questions about all documents. Type 'exit' to quit.");
+
+        // Documents may store different defaults, so only configured values apply here
+        let retrieval = RetrievalDefaults {
+            top_k: self.config.top_k,
+            min_score: self.config.min_score,
+        };
+        self.query_loop(self.shared_collection(), None, retrieval, output)
+            .await
+    }
+
+    /// Answer questions read from stdin from a collection, optionally filtered to one document
+    async fn query_loop(
+        &self,
+        collection: &str,
+        document_filter: Option<&str>,
+        retrieval: RetrievalDefaults,
+        output: Option<&Path>,
+    ) -> Result<()> {
+        let mut system_instruction = self.config.system_instruction.clone();
+        let mut answer_style = self.config.answer_style.clone();
+        // The latest turns, oldest first, that follow-up questions can refer back to
+        let mut history: Vec<ConversationTurn> = Vec::new();
+
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        let mut buffer = String::new();
+
+        loop {
+            print!("\nYour question: ");
+            stdout.flush()?;
+
+            buffer.clear();
+            stdin.read_line(&mut buffer)?;
+
+            let question = buffer.trim();
+
+            if question.to_lowercase() == "exit" {
+                info!("Goodbye!");
+                break;
+            }
+
+            if let Some(instruction) = session_command(question, "/system") {
+                if instruction.is_empty() {
+                    system_instruction = None;
+                    info!("System instruction cleared");
+                } else {
+                    system_instruction = Some(instruction.to_string());
+                    info!("System instruction set");
+                }
+                continue;
+            }
+
+            if session_command(question, "/reset").is_some() {
+                history.clear();
+                info!("Conversation history cleared");
+                continue;
+            }
+
+            if let Some(style) = session_command(question, "/style") {
+                if style.is_empty() {
+                    answer_style = None;
+                    info!("Answer style cleared");
+                } else {
+                    answer_style = Some(style.to_string());
+                    info!("Answer style set");
+                }
+                continue;
+            }
+
+            // Retrieve context and generate an answer
+            let instruction =
+                instruction_with_style(system_instruction.as_deref(), answer_style.as_deref());
+            let mut streamed = false;
+            let mut print_delta = |delta: &str| {
+                if !streamed {
+                    println!();
+                    streamed = true;
+                }
+                print!("{}", delta);
+                let _ = io::stdout().flush();
+            };
+            let usage_before = self.gemini.usage();
+            let answer = self
+                .answer_from(
+                    collection,
+                    document_filter,
+                    question,
+                    retrieval,
+                    instruction.as_deref(),
+                    Some(&mut print_delta),
+                    &history,
+                )
+                .await?;
+
+            if streamed {
+                println!();
+            } else {
+                if let Some(prompt) = &answer.prompt {
+                    info!("Prompt:\n{}", prompt);
+                }
+                info!("\n{}", answer.text);
+            }
+            print_sources(&answer.sources);
+            info!("Answered with {}", self.gemini.usage().since(usage_before));
+
+            if let Some(output) = output {
+                append_answer(output, question, &answer.text)?;
+            }
+
+            if self.config.history_turns > 0 {
+                history.push(ConversationTurn {
+                    question: question.to_string(),
+                    answer: answer.text,
+                });
+                let excess = history.len().saturating_sub(self.config.history_turns);
+                history.drain(..excess);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How a document is split into chunks
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DocumentFormat {
+    /// A CSV or TSV table with this delimiter, split by rows
+    Table(u8),
+    /// Markdown, split along its sections
+    Markdown,
+    /// Plain text
+    Text,
+}
+
+/// Format of a document by its MIME type when known, otherwise by its file extension
+fn document_format(document_id: &str, mime_type: Option<&str>) -> DocumentFormat {
+    let mime_type = match mime_type {
+        Some(mime_type) => mime_type.to_string(),
+        None => mime_guess::from_path(document_id)
+            .first_or_text_plain()
+            .to_string(),
+    };
+
+    match mime_type.split(';').next().unwrap_or_default().trim() {
+        "text/csv" => DocumentFormat::Table(b','),
+        "text/tab-separated-values" => DocumentFormat::Table(b'\t'),
+        "text/markdown" | "text/x-markdown" => DocumentFormat::Markdown,
+        _ => DocumentFormat::Text,
+    }
+}
+
+/// Print the chunks an answer cites after it
+fn print_sources(sources: &[ChunkRef]) {
+    if sources.is_empty() {
+        return;
+    }
+
+    println!("\nSources:");
+    for source in sources {
+        let location = match source.page {
+            Some(page) => format!("page {}", page),
+            None => format!("position {}", source.start_position),
+        };
+        let score = match source.keyword_score {
+            Some(keyword_score) if source.score == 0.0 => {
+                format!("keyword match {:.0}%", keyword_score * 100.0)
+            }
+            _ => format!("score {:.3}", source.score),
+        };
+        println!(
+            "  [{}] {} (chunk {}, {}, {})",
+            source.citation, source.document_id, source.chunk_index, location, score
+        );
+    }
+}
+
+/// Combine the system instruction with answer style guidance into one system instruction
+fn instruction_with_style(
+    system_instruction: Option<&str>,
+    answer_style: Option<&str>,
+) -> Option<String> {
+    match (system_instruction, answer_style) {
+        (Some(instruction), Some(style)) => {
+            Some(format!("{}\n\nAnswer style: {}", instruction, style))
+        }
+        (Some(instruction), None) => Some(instruction.to_string()),
+        (None, Some(style)) => Some(format!("Answer style: {}", style)),
+        (None, None) => None,
+    }
+}
+
+/// Argument of a query loop command such as "/system concise", or None for a question
+fn session_command<'a>(input: &'a str, command: &str) -> Option<&'a str> {
+    let rest = input.strip_prefix(command)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/// Everything that went into producing an answer, as written to a trace file
+#[derive(Serialize)]
+struct AnswerTrace<'a> {
+    question: &'a str,
+    collection: &'a str,
+    document_filter: Option<&'a str>,
+    chunks: Vec<TracedChunk<'a>>,
+    prompt: String,
+    system_instruction: Option<&'a str>,
+    model: &'a str,
+    generation: GenerationParams,
+    answer: &'a str,
+}
+
+/// A retrieved chunk as recorded in an answer trace
+#[derive(Serialize)]
+struct TracedChunk<'a> {
+    document_id: &'a str,
+    chunk_index: usize,
+    score: f32,
+    text: &'a str,
+}
+
+/// Write an answer trace to a new timestamped JSON file in the trace directory
+fn write_trace(trace_dir: &Path, trace: &AnswerTrace) -> Result<()> {
+    fs::create_dir_all(trace_dir)
+        .with_context(|| format!("Failed to create trace directory {}", trace_dir.display()))?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let path = trace_dir.join(format!("trace-{}.json", timestamp));
+
+    let json = serde_json::to_string_pretty(trace)?;
+    fs::write(&path, json)
+        .with_context(|| format!("Failed to write trace file {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Answers kept in memory for `ttl`, keyed by `answer_cache_key`
+struct AnswerCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<u64, (Instant, Answer)>>,
+}
+
+impl AnswerCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cached answer for a key, unless it has expired
+    fn get(&self, key: u64) -> Option<Answer> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (cached_at, _)| cached_at.elapsed() < self.ttl);
+        entries.get(&key).map(|(_, answer)| answer.clone())
+    }
+
+    fn insert(&self, key: u64, answer: Answer) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.insert(key, (Instant::now(), answer));
+    }
+}
+
+/// Cache key of an answer: where it was retrieved from, the normalized question, the content
+/// of the retrieved chunks and the instruction; any change to the chunks misses the cache
+fn answer_cache_key(
+    collection: &str,
+    document_filter: Option<&str>,
+    question: &str,
+    chunks: &[ScoredChunk],
+    system_instruction: Option<&str>,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    collection.hash(&mut hasher);
+    document_filter.hash(&mut hasher);
+    normalize_question(question).hash(&mut hasher);
+    for scored_chunk in chunks {
+        scored_chunk.chunk.document_id.hash(&mut hasher);
+        scored_chunk.chunk_index.hash(&mut hasher);
+        scored_chunk.chunk.text.hash(&mut hasher);
+    }
+    system_instruction.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Lowercase a question and collapse its whitespace, so trivially different spellings match
+fn normalize_question(question: &str) -> String {
+    question
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Join retrieved chunk texts into the context given to the model
+fn join_chunk_texts(chunks: &[ScoredChunk]) -> String {
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, scored_chunk)| format!("[{}] {}", i + 1, scored_chunk.chunk.text))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+/// Numbers of the context passages an answer cites as `[n]` or `[n, m]`, sorted and without
+/// duplicates; numbers outside `1..=count` are ignored
+fn cited_sources(text: &str, count: usize) -> Vec<usize> {
+    let mut citations = Vec::new();
+
+    for (start, _) in text.match_indices('[') {
+        let rest = &text[start + 1..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+        let numbers: Option<Vec<usize>> = rest[..end]
+            .split(',')
+            .map(|number| number.trim().parse().ok())
+            .collect();
+        citations.extend(
+            numbers
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|number| (1..=count).contains(number)),
+        );
+    }
+
+    citations.sort_unstable();
+    citations.dedup();
+    citations
+}
+
+/// Fuse the chunks found by the contextual and raw vector searches into the `top_k` best by
+/// weighted score; a chunk missing from one search scores 0 there
+fn fuse_scored_chunks(
+    contextual: Vec<ScoredChunk>,
+    raw: Vec<ScoredChunk>,
+    raw_weight: f32,
+    top_k: u64,
+    min_score: Option<f32>,
+) -> Vec<ScoredChunk> {
+    let mut fused: Vec<ScoredChunk> = contextual
+        .into_iter()
+        .map(|scored| ScoredChunk {
+            score: scored.score * (1.0 - raw_weight),
+            ..scored
+        })
+        .collect();
+
+    for scored in raw {
+        let score = scored.score * raw_weight;
+        let same_chunk = fused.iter_mut().find(|fused| {
+            fused.chunk_index == scored.chunk_index
+                && fused.chunk.document_id == scored.chunk.document_id
+        });
+        match same_chunk {
+            Some(fused) => fused.score += score,
+            None => fused.push(ScoredChunk { score, ..scored }),
+        }
+    }
+
+    fused.retain(|scored| min_score.is_none_or(|min_score| scored.score >= min_score));
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+    fused.truncate(top_k as usize);
+    fused
+}
+
+/// Best similarity score among retrieved chunks, which similarity thresholds are checked
+/// against; None in keyword mode, where chunks have no similarity to the question
+fn top_similarity(search_mode: SearchMode, chunks: &[ScoredChunk]) -> Option<f32> {
+    if search_mode == SearchMode::Keyword {
+        return None;
+    }
+    chunks
+        .iter()
+        .map(|scored_chunk| scored_chunk.score)
+        .reduce(f32::max)
+}
+
+/// Merge two searches' results, such as the question's and the hypothetical answer's, by
+/// weighted reciprocal rank fusion into the `top_k` best chunks
+/// `second_weight` (0 to 1) is the weight of the second ranking against the first
+/// Chunks found by both searches appear once, keeping their higher score and keyword score
+fn rrf_fuse_scored_chunks(
+    first: Vec<ScoredChunk>,
+    second: Vec<ScoredChunk>,
+    second_weight: f32,
+    top_k: u64,
+) -> Vec<ScoredChunk> {
+    let mut fused: Vec<(f32, ScoredChunk)> = Vec::new();
+
+    let ranked = first
+        .into_iter()
+        .enumerate()
+        .map(|(rank, scored)| (rank, scored, 1.0 - second_weight))
+        .chain(
+            second
+                .into_iter()
+                .enumerate()
+                .map(|(rank, scored)| (rank, scored, second_weight)),
+        );
+    for (rank, scored, weight) in ranked {
+        let rrf_score = weight / (RRF_K + rank as f32 + 1.0);
+        let same_chunk = fused.iter_mut().find(|(_, fused)| {
+            fused.chunk_index == scored.chunk_index
+                && fused.chunk.document_id == scored.chunk.document_id
+        });
+        match same_chunk {
+            Some((fused_rrf, fused)) => {
+                *fused_rrf += rrf_score;
+                fused.score = fused.score.max(scored.score);
+                fused.keyword_score = fused.keyword_score.max(scored.keyword_score);
+            }
+            None => fused.push((rrf_score, scored)),
+        }
+    }
+
+    fused.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    fused.truncate(top_k as usize);
+    fused.into_iter().map(|(_, scored)| scored).collect()
+}
+
+/// Merge the results of several searches into the `top_k` best chunks, each chunk found more
+/// than once keeping its highest score and keyword score
+fn merge_scored_chunks(results: Vec<Vec<ScoredChunk>>, top_k: u64) -> Vec<ScoredChunk> {
+    let mut merged: Vec<ScoredChunk> = Vec::new();
+
+    for scored in results.into_iter().flatten() {
+        let same_chunk = merged.iter_mut().find(|merged| {
+            merged.chunk_index == scored.chunk_index
+                && merged.chunk.document_id == scored.chunk.document_id
+        });
+        match same_chunk {
+            Some(merged) => {
+                merged.score = merged.score.max(scored.score);
+                merged.keyword_score = merged.keyword_score.max(scored.keyword_score);
+            }
+            None => merged.push(scored),
+        }
+    }
+
+    // Keyword-only chunks all have a similarity of 0.0 and rank by their keyword score
+    merged.sort_by(|a, b| {
+        b.score.total_cmp(&a.score).then(
+            b.keyword_score
+                .unwrap_or(0.0)
+                .total_cmp(&a.keyword_score.unwrap_or(0.0)),
+        )
+    });
+    merged.truncate(top_k as usize);
+    merged
+}
+
+/// Length of an embedding stored for dot-product search without being unit length, which then
+/// ranks differently from cosine similarity; None when the embedding is fine as it is
+fn dot_distance_norm(
+    distance: Distance,
+    normalizes_embeddings: bool,
+    embedding: &Embedding,
+) -> Option<f32> {
+    if distance != Distance::Dot || normalizes_embeddings {
+        return None;
+    }
+    let norm = embedding.norm();
+    ((norm - 1.0).abs() > UNIT_NORM_TOLERANCE).then_some(norm)
+}
+
+/// Check that every embedding has the same length as the first one
+fn check_embedding_dimensions(embeddings: &[Embedding]) -> Result<()> {
+    let Some(first) = embeddings.first() else {
+        return Ok(());
+    };
+    let expected = first.values.len();
+
+    for (i, embedding) in embeddings.iter().enumerate() {
+        if embedding.values.len() != expected {
+            return Err(anyhow::anyhow!(
+                "inconsistent embedding dimensions: chunk {} has len {}, expected {}",
+                i,
+                embedding.values.len(),
+                expected
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Append a question and its answer to the output file
+pub fn append_answer(output: &Path, question: &str, answer: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output)
+        .with_context(|| format!("Failed to open output file {}", output.display()))?;
+
+    writeln!(file, "Question: {}\n\n{}\n", question, answer.trim())
+        .with_context(|| format!("Failed to write answer to {}", output.display()))?;
+
+    Ok(())
+}
+
+/// Strip common markdown formatting (headings, emphasis, bullets, code fences) from text
+fn strip_markdown(text: &str) -> String {
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        let indent_len = line.len() - line.trim_start().len();
+        let (indent, content) = line.split_at(indent_len);
+        let content = content.trim_end();
+
+        // Drop code fences and horizontal rules entirely
+        if content.starts_with("```")
+            || (content.len() >= 3 && content.chars().all(|c| c == '-' || c == '*' || c == '_'))
+        {
+            continue;
+        }
+
+        // Headings and block quotes keep only their text
+        let hashes = content.len() - content.trim_start_matches('#').len();
+        let content = if (1..=6).contains(&hashes) && content[hashes..].starts_with(' ') {
+            content[hashes..].trim_start()
+        } else {
+            content.trim_start_matches("> ")
+        };
+
+        // Bullet markers are dropped, leaving the indented item text
+        let content = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|marker| content.strip_prefix(marker))
+            .unwrap_or(content);
+
+        lines.push(format!("{}{}", indent, strip_inline_markdown(content)));
+    }
+
+    lines.join("\n")
+}
+
+/// Remove bold, italic and inline code markers, keeping lone asterisks such as "2 * 3"
+fn strip_inline_markdown(text: &str) -> String {
+    let text = text.replace("**", "").replace("__", "").replace('`', "");
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::with_capacity(text.len());
+
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '*' {
+            let before = i.checked_sub(1).map(|j| chars[j]);
+            let after = chars.get(i + 1).copied();
+            let opens = after.is_some_and(|a| !a.is_whitespace())
+                && before.is_none_or(|b| b.is_whitespace() || b.is_ascii_punctuation());
+            let closes = before.is_some_and(|b| !b.is_whitespace())
+                && after.is_none_or(|a| a.is_whitespace() || a.is_ascii_punctuation());
+            if opens || closes {
+                continue;
+            }
+        }
+        result.push(c);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_format() {
+        assert_eq!(
+            document_format("catalog.csv", None),
+            DocumentFormat::Table(b',')
+        );
+        assert_eq!(
+            document_format("data/FAQ.TSV", None),
+            DocumentFormat::Table(b'\t')
+        );
+        assert_eq!(document_format("notes.md", None), DocumentFormat::Markdown);
+        assert_eq!(
+            document_format("notes.MARKDOWN", None),
+            DocumentFormat::Markdown
+        );
+        assert_eq!(document_format("notes.txt", None), DocumentFormat::Text);
+        assert_eq!(document_format("md", None), DocumentFormat::Text);
+        assert_eq!(document_format("csv", None), DocumentFormat::Text);
+        // A forced MIME type wins over the extension
+        assert_eq!(
+            document_format("notes.txt", Some("text/markdown")),
+            DocumentFormat::Markdown
+        );
+        assert_eq!(
+            document_format("notes.md", Some("text/plain")),
+            DocumentFormat::Text
+        );
+    }
+
+    #[test]
+    fn test_forced_markdown_breadcrumbs() {
+        let path = std::env::temp_dir().join(format!("forced-{}.txt", std::process::id()));
+        fs::write(&path, "# Notes\n\nSome text.").unwrap();
+        let document = Document::from_file_with_mime(
+            &path,
+            "text/markdown",
+            &crate::document::PdfOptions::default(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // A .txt file read as Markdown is split along its sections
+        let format = document_format(&document.document_id, Some(&document.mime_type));
+        assert_eq!(format, DocumentFormat::Markdown);
+        let chunks = crate::chunking::split_markdown(
+            &document.content,
+            &document.document_id,
+            &ChunkingConfig::default(),
+            &FallbackCounter,
+        )
+        .unwrap();
+        assert_eq!(chunks[0].breadcrumb.as_deref(), Some("Notes"));
+    }
+
+    #[test]
+    fn test_bulk_ingest_report() {
+        let mut worker = BulkIngestReport::default();
+        worker.record(IngestReport {
+            chunks: 3,
+            ..IngestReport::default()
+        });
+        worker.record(IngestReport {
+            skipped: true,
+            ..IngestReport::default()
+        });
+
+        let mut report = BulkIngestReport {
+            failed: 1,
+            ..BulkIngestReport::default()
+        };
+        report.merge(worker);
+        report.merge(worker);
+        assert_eq!(report.indexed, 2);
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.chunks, 6);
+    }
+
+    #[test]
+    fn test_answer_cache() {
+        let chunk = |text: &str| ScoredChunk {
+            chunk: TextChunk {
+                text: text.to_string(),
+                token_count: 4,
+                document_id: "doc.txt".to_string(),
+                start_position: 0,
+                breadcrumb: None,
+                row: None,
+                page: None,
+            },
+            score: 0.9,
+            keyword_score: None,
+            chunk_index: 0,
+        };
+        let key = answer_cache_key(
+            "doc.txt",
+            None,
+            "What is  the refund policy?",
+            &[chunk("Refunds within 30 days")],
+            None,
+        );
+        assert_eq!(
+            key,
+            answer_cache_key(
+                "doc.txt",
+                None,
+                " what is the REFUND policy? ",
+                &[chunk("Refunds within 30 days")],
+                None,
+            )
+        );
+        assert_ne!(
+            key,
+            answer_cache_key(
+                "doc.txt",
+                None,
+                "What is the refund policy?",
+                &[chunk("Refunds within 60 days")],
+                None,
+            )
+        );
+
+        let answer = Answer {
+            text: "30 days".to_string(),
+            prompt: None,
+            sources: Vec::new(),
+        };
+        let cache = AnswerCache::new(Duration::from_secs(60));
+        assert!(cache.get(key).is_none());
+        cache.insert(key, answer.clone());
+        assert_eq!(
+            cache.get(key).map(|answer| answer.text),
+            Some(answer.text.clone())
+        );
+
+        let expired = AnswerCache::new(Duration::ZERO);
+        expired.insert(key, answer);
+        assert!(expired.get(key).is_none());
+    }
+
+    #[test]
+    fn test_cited_sources() {
+        assert_eq!(
+            cited_sources("Refunds take 30 days [2]. Exchanges are free [1, 3][2].", 3),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            cited_sources("See [4] and [note] or [0]", 3),
+            Vec::<usize>::new()
+        );
+        assert_eq!(cited_sources("Unclosed [1", 3), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_strip_markdown() {
+        let markdown = "## Summary\n\nThe **refund** policy is *simple*:\n\n- Returns within `30` days\n  * Nested item\n\n---\n```\ncode\n```\n> Quoted\n2 * 3 = 6";
+        let expected = "Summary\n\nThe refund policy is simple:\n\nReturns within 30 days\n  Nested item\n\ncode\nQuoted\n2 * 3 = 6";
+        assert_eq!(strip_markdown(markdown), expected);
+    }
+
+    #[test]
+    fn test_session_command() {
+        assert_eq!(
+            session_command("/system Answer concisely", "/system"),
+            Some("Answer concisely")
+        );
+        assert_eq!(session_command("/system", "/system"), Some(""));
+        assert_eq!(session_command("/systematic review?", "/system"), None);
+        assert_eq!(session_command("What is /system?", "/system"), None);
+    }
+
+    #[test]
+    fn test_instruction_with_style() {
+        assert_eq!(instruction_with_style(None, None), None);
+        assert_eq!(
+            instruction_with_style(Some("Be formal."), None).as_deref(),
+            Some("Be formal.")
+        );
+        assert_eq!(
+            instruction_with_style(None, Some("in one sentence")).as_deref(),
+            Some("Answer style: in one sentence")
+        );
+        assert_eq!(
+            instruction_with_style(Some("Be formal."), Some("in one sentence")).as_deref(),
+            Some("Be formal.\n\nAnswer style: in one sentence")
+        );
+    }
+
+    #[test]
+    fn test_check_embedding_dimensions() {
+        let embedding = |len| Embedding {
+            values: vec![0.5; len],
+        };
+
+        assert!(check_embedding_dimensions(&[]).is_ok());
+        assert!(check_embedding_dimensions(&[embedding(3), embedding(3)]).is_ok());
+
+        let error = check_embedding_dimensions(&[embedding(3), embedding(3), embedding(2)])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "inconsistent embedding dimensions: chunk 2 has len 2, expected 3"
+        );
+    }
+
+    #[test]
+    fn test_dot_distance_norm() {
+        let long = Embedding {
+            values: vec![3.0, 4.0],
+        };
+        let unit = long.normalized();
+
+        assert_eq!(dot_distance_norm(Distance::Dot, false, &long), Some(5.0));
+        assert_eq!(dot_distance_norm(Distance::Dot, false, &unit), None);
+        // Normalized before storing, so the raw length does not matter
+        assert_eq!(dot_distance_norm(Distance::Dot, true, &long), None);
+        assert_eq!(dot_distance_norm(Distance::Cosine, false, &long), None);
+    }
+
+    fn scored(chunk_index: usize, score: f32) -> ScoredChunk {
+        ScoredChunk {
+            chunk: TextChunk {
+                text: format!("chunk {}", chunk_index),
+                token_count: 2,
+                document_id: "doc.txt".to_string(),
+                start_position: 0,
+                breadcrumb: None,
+                row: None,
+                page: None,
+            },
+            score,
+            keyword_score: None,
+            chunk_index,
+        }
+    }
+
+    #[test]
+    fn test_fuse_scored_chunks() {
+        let contextual = vec![scored(0, 0.9), scored(1, 0.6)];
+        let raw = vec![scored(1, 0.9), scored(2, 0.8)];
+
+        // 0: 0.9 * 0.5, 1: (0.6 + 0.9) * 0.5, 2: 0.8 * 0.5
+        let fused = fuse_scored_chunks(contextual.clone(), raw.clone(), 0.5, 2, None);
+        let ranked: Vec<usize> = fused.iter().map(|scored| scored.chunk_index).collect();
+        assert_eq!(ranked, vec![1, 0]);
+        assert!((fused[0].score - 0.75).abs() < 1e-6);
+
+        // The threshold applies to the fused score
+        let fused = fuse_scored_chunks(contextual, raw, 0.5, 4, Some(0.42));
+        let ranked: Vec<usize> = fused.iter().map(|scored| scored.chunk_index).collect();
+        assert_eq!(ranked, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_rrf_fuse_scored_chunks() {
+        let direct = vec![scored(0, 0.8), scored(1, 0.7), scored(2, 0.6)];
+        let hyde = vec![scored(3, 0.9), scored(1, 0.85)];
+
+        // Chunk 1 is found by both searches, so it ranks first and appears once
+        let fused = rrf_fuse_scored_chunks(direct.clone(), hyde.clone(), 0.5, 3);
+        let ranked: Vec<usize> = fused.iter().map(|scored| scored.chunk_index).collect();
+        assert_eq!(ranked, vec![1, 0, 3]);
+        assert!((fused[0].score - 0.85).abs() < 1e-6);
+
+        // Without weight on the hypothetical answer, the question's ranking stands
+        let fused = rrf_fuse_scored_chunks(direct, hyde, 0.0, 3);
+        let ranked: Vec<usize> = fused.iter().map(|scored| scored.chunk_index).collect();
+        assert_eq!(ranked, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_hybrid_search_keeps_keyword_match() {
+        // Chunk 7 holds the exact error code asked about, but embeds too far from the question
+        let vector = vec![scored(0, 0.8), scored(1, 0.75), scored(2, 0.7)];
+        let keyword = vec![ScoredChunk {
+            keyword_score: Some(1.0),
+            ..scored(7, 0.0)
+        }];
+
+        let fused = rrf_fuse_scored_chunks(vector, keyword, KEYWORD_WEIGHT, 2);
+        let ranked: Vec<usize> = fused.iter().map(|scored| scored.chunk_index).collect();
+        assert_eq!(ranked, vec![0, 7]);
+        // Matching every keyword does not make the chunk look similar to the question
+        assert_eq!(fused[1].score, 0.0);
+        assert_eq!(fused[1].keyword_score, Some(1.0));
+        assert_eq!(top_similarity(SearchMode::Hybrid, &fused), Some(0.8));
+        assert_eq!(top_similarity(SearchMode::Keyword, &fused), None);
+    }
+
+    #[test]
+    fn test_merge_scored_chunks() {
+        let results = vec![
+            vec![scored(0, 0.7), scored(1, 0.6)],
+            vec![scored(1, 0.9), scored(2, 0.5)],
+            vec![scored(0, 0.4), scored(3, 0.65)],
+        ];
+
+        let merged = merge_scored_chunks(results, 3);
+        let ranked: Vec<(usize, f32)> = merged
+            .iter()
+            .map(|scored| (scored.chunk_index, scored.score))
+            .collect();
+        assert_eq!(ranked, vec![(1, 0.9), (0, 0.7), (3, 0.65)]);
     }
 }